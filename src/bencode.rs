@@ -1,7 +1,16 @@
-use std::{collections::HashMap, fmt::Display, iter::once};
+use std::{collections::HashMap, fmt::Display, iter::once, ops::Range};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{bterror, bytes::Bytes, error::BitTorrentError};
 
+use self::borrowed::{BencodedRef, DecodeOptions};
+
+pub mod borrowed;
+pub mod de;
+pub mod json;
+pub mod ser;
+
 pub type Number = i64;
 
 #[derive(Clone, Debug)]
@@ -62,80 +71,58 @@ impl BencodedValue {
         }
     }
 
-    pub fn ingest(bytes: &mut &[u8]) -> Result<Self, BitTorrentError>
-    where
-        Self: Sized,
-    {
-        match bytes.split_first() {
-            None => Err(bterror!("No data")),
-            Some((b'i', rest)) => {
-                let end_token = rest
-                    .iter()
-                    .position(|&b| b == b'e')
-                    .ok_or(bterror!("Missing integer end token"))?;
-                *bytes = &rest[end_token + 1..];
-                Ok(Self::Int(
-                    String::from_utf8(rest[..end_token].to_vec())?.parse()?,
-                ))
-            }
-            Some((b'l', mut rest)) => {
-                let mut items = Vec::new();
-                loop {
-                    match rest.split_first() {
-                        None => return Err(bterror!("Missing list end token")),
-                        Some((b'e', rest)) => {
-                            *bytes = rest;
-                            break;
-                        }
-                        Some(_) => {
-                            *bytes = rest;
-                            let value = Self::ingest(bytes)?;
-                            items.push(value);
-                            rest = bytes;
-                        }
-                    }
-                }
-                Ok(Self::List(items))
-            }
-            Some((b'd', mut rest)) => {
-                let mut map = HashMap::new();
-                loop {
-                    match rest.split_first() {
-                        None => return Err(bterror!("Missing dict end token")),
-                        Some((b'e', rest)) => {
-                            *bytes = rest;
-                            break;
-                        }
-                        Some(_) => {
-                            *bytes = rest;
-                            if let Self::Bytes(key) = Self::ingest(bytes)? {
-                                let value = Self::ingest(bytes)?;
-                                map.insert(key, value);
-                                rest = bytes;
-                            } else {
-                                return Err(bterror!("Invalid dict key"));
-                            }
-                        }
-                    }
-                }
-                Ok(Self::Dict(map))
-            }
-            Some((b'0'..=b'9', _)) => {
-                let length_end_token = bytes
-                    .iter()
-                    .position(|&b| b == b':')
-                    .ok_or(bterror!("Missing string length end token"))?;
-                let length: usize =
-                    String::from_utf8(bytes[..length_end_token].to_vec())?.parse()?;
-                if let Some(string) = bytes.get(length_end_token + 1..length_end_token + 1 + length)
-                {
-                    *bytes = &bytes[length_end_token + 1 + length..];
-                    Ok(Self::Bytes(Bytes(string.to_vec())))
-                } else {
-                    Err(bterror!("Insufficient characters remaining"))
+    /// Decode the value at the start of `bytes`, advancing it past the value. Any well formed
+    /// value is accepted, nested no deeper than `DecodeOptions::LENIENT` allows.
+    pub fn ingest(bytes: &mut &[u8]) -> Result<Self, BitTorrentError> {
+        Self::ingest_with(bytes, DecodeOptions::LENIENT)
+    }
+
+    /// Decode like `ingest`, rejecting values that break `options`.
+    pub fn ingest_with(bytes: &mut &[u8], options: DecodeOptions) -> Result<Self, BitTorrentError> {
+        Ok(BencodedRef::decode_with(bytes, options)?.into())
+    }
+
+    /// Decode a dict like `ingest`, additionally returning the byte range within `bytes` that
+    /// each of its values was read from, e.g. to hash a sub-value exactly as it was encoded.
+    pub fn ingest_with_spans(
+        bytes: &mut &[u8],
+    ) -> Result<(Self, HashMap<Bytes, Range<usize>>), BitTorrentError> {
+        let start = *bytes;
+        if !matches!(
+            BencodedRef::decode_with(bytes, DecodeOptions::LENIENT)?,
+            BencodedRef::Dict(_)
+        ) {
+            return Err(bterror!("Expected a dict"));
+        }
+
+        // the entries were checked while decoding the dict
+        let mut rest = &start[1..];
+        let mut map = HashMap::new();
+        let mut spans = HashMap::new();
+        while rest.first() != Some(&b'e') {
+            let key = BencodedRef::decode(&mut rest)?
+                .as_bytes()
+                .map(Bytes::from)
+                .ok_or(bterror!("Invalid dict key"))?;
+            let offset = start.len() - rest.len();
+            let value = BencodedRef::decode(&mut rest)?;
+            spans.insert(key.clone(), offset..start.len() - rest.len());
+            map.insert(key, value.into());
+        }
+        Ok((Self::Dict(map), spans))
+    }
+
+    /// Add the entries of `extra` to a dict, keeping any key it already has. Used to round-trip
+    /// keys that aren't modelled when re-encoding.
+    pub fn with_extra(self, extra: HashMap<Bytes, BencodedValue>) -> Self {
+        match self {
+            Self::Dict(mut dict) => {
+                for (key, value) in extra {
+                    dict.entry(key).or_insert(value);
                 }
+                Self::Dict(dict)
             }
-            _ => Err(bterror!("Invalid bencoded value")),
+            value => value,
         }
     }
 
@@ -235,6 +222,26 @@ impl Display for BencodedValue {
     }
 }
 
+/// Serialize `value` into a `BencodedValue`.
+pub fn to_value<T: ?Sized + Serialize>(value: &T) -> Result<BencodedValue, BitTorrentError> {
+    value.serialize(ser::Serializer)
+}
+
+/// Serialize `value` straight to its bencoded bytes.
+pub fn to_bytes<T: ?Sized + Serialize>(value: &T) -> Result<Vec<u8>, BitTorrentError> {
+    to_value(value)?.encode()
+}
+
+/// Deserialize a `T` from a decoded value.
+pub fn from_value<T: DeserializeOwned>(value: BencodedValue) -> Result<T, BitTorrentError> {
+    T::deserialize(value)
+}
+
+/// Deserialize a `T` from a borrowed value, which `T` may borrow byte strings from.
+pub fn from_ref<'a, T: Deserialize<'a>>(value: BencodedRef<'a>) -> Result<T, BitTorrentError> {
+    T::deserialize(value)
+}
+
 // when's specialization :,(
 
 // impl<I: Into<Number>> From<I> for BencodedValue {
@@ -307,3 +314,47 @@ macro_rules! list {
         BencodedValue::List(Vec::from([$($v.into(),)*]))
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested(depth: usize) -> Vec<u8> {
+        [vec![b'l'; depth], vec![b'e'; depth]].concat()
+    }
+
+    #[test]
+    fn ingest_bounds_nesting() {
+        assert!(BencodedValue::ingest(&mut &nested(borrowed::MAX_DEPTH)[..]).is_ok());
+
+        // deep enough to overflow the stack of an unbounded decoder
+        let error = BencodedValue::ingest(&mut &nested(1 << 20)[..]).unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with(&format!("at byte {}", borrowed::MAX_DEPTH)));
+        let dict = [&b"d1:a"[..], &nested(1 << 20), b"e"].concat();
+        assert!(BencodedValue::ingest_with_spans(&mut &dict[..]).is_err());
+    }
+
+    #[test]
+    fn ingest_is_lenient_unless_asked_to_be_strict() {
+        let bytes = b"d1:bi03e1:ai2ee";
+        assert!(BencodedValue::ingest(&mut &bytes[..]).is_ok());
+        let error = BencodedValue::ingest_with(&mut &bytes[..], DecodeOptions::UNTRUSTED);
+        assert!(error
+            .unwrap_err()
+            .to_string()
+            .ends_with("Leading zero in number at byte 5"));
+    }
+
+    #[test]
+    fn ingest_with_spans_locates_each_value() {
+        let bytes = b"d1:bi1e1:ali2eee";
+        let mut rest = &bytes[..];
+        let (value, spans) = BencodedValue::ingest_with_spans(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(value.as_dict().unwrap().len(), 2);
+        assert_eq!(&bytes[spans[&Bytes::from(&b"b"[..])].clone()], b"i1e");
+        assert_eq!(&bytes[spans[&Bytes::from(&b"a"[..])].clone()], b"li2ee");
+    }
+}
@@ -0,0 +1,418 @@
+use std::fmt::{self, Debug, Display, Formatter};
+
+use crate::{bterror, bytes::Bytes, error::BitTorrentError};
+
+use super::{BencodedValue, Number};
+
+/// Bencoded value borrowing its byte strings from the buffer it was decoded from. Lists and
+/// dicts keep their encoded contents and decode their items as they are visited, so decoding
+/// allocates nothing.
+#[derive(Clone, Copy, Debug)]
+pub enum BencodedRef<'a> {
+    Bytes(&'a [u8]),
+    Int(Number),
+    List(ListRef<'a>),
+    Dict(DictRef<'a>),
+}
+
+/// Encoded items of a list, between its start and end tokens
+#[derive(Clone, Copy)]
+pub struct ListRef<'a>(&'a [u8]);
+
+/// Encoded key/value pairs of a dict, between its start and end tokens
+#[derive(Clone, Copy)]
+pub struct DictRef<'a>(&'a [u8]);
+
+/// deepest nesting of lists and dicts accepted from any source, to bound the recursion of
+/// decoding well within the stack
+pub const MAX_DEPTH: usize = 64;
+
+/// Limits and canonical form checks applied while decoding
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeOptions {
+    /// only accept the canonical encoding: no leading zeros or plus signs in integers and
+    /// string lengths, no `-0`, and dict keys in strictly ascending order
+    pub strict: bool,
+    /// deepest nesting of lists and dicts allowed
+    pub max_depth: usize,
+    /// longest encoded value allowed (bytes)
+    pub max_size: usize,
+}
+
+impl DecodeOptions {
+    /// accept any well formed value that isn't nested too deeply
+    pub const LENIENT: Self = Self {
+        strict: false,
+        max_depth: MAX_DEPTH,
+        max_size: usize::MAX,
+    };
+
+    /// canonical values within limits that fit any message from a peer or DHT node
+    pub const UNTRUSTED: Self = Self {
+        strict: true,
+        max_depth: 32,
+        max_size: 1 << 20,
+    };
+}
+
+impl<'a> BencodedRef<'a> {
+    /// Decode the value at the start of `bytes`, advancing it past the value. Nested values
+    /// are checked to be well formed, but only decoded again once visited.
+    pub fn decode(bytes: &mut &'a [u8]) -> Result<Self, BitTorrentError> {
+        Self::decode_with(bytes, DecodeOptions::LENIENT)
+    }
+
+    /// Decode like `decode`, rejecting values that break `options`. Errors give the offset
+    /// into `bytes` of the offending value.
+    pub fn decode_with(
+        bytes: &mut &'a [u8],
+        options: DecodeOptions,
+    ) -> Result<Self, BitTorrentError> {
+        let mut decoder = Decoder {
+            input: bytes,
+            position: 0,
+            options,
+        };
+        let value = decoder.value(0)?;
+        *bytes = &bytes[decoder.position..];
+        Ok(value)
+    }
+
+    pub fn as_bytes(self) -> Option<&'a [u8]> {
+        if let Self::Bytes(bytes) = self {
+            Some(bytes)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_int(self) -> Option<Number> {
+        if let Self::Int(int) = self {
+            Some(int)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_list(self) -> Option<ListRef<'a>> {
+        if let Self::List(list) = self {
+            Some(list)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_dict(self) -> Option<DictRef<'a>> {
+        if let Self::Dict(dict) = self {
+            Some(dict)
+        } else {
+            None
+        }
+    }
+}
+
+/// Cursor over the input of `BencodedRef::decode_with`
+struct Decoder<'a> {
+    input: &'a [u8],
+    position: usize,
+    options: DecodeOptions,
+}
+
+impl<'a> Decoder<'a> {
+    fn error(position: usize, message: impl Display) -> BitTorrentError {
+        bterror!("{} at byte {}", message, position)
+    }
+
+    /// Bytes up to the next `token`, moving past the token.
+    fn take_until(&mut self, token: u8, what: &str) -> Result<&'a [u8], BitTorrentError> {
+        let start = self.position;
+        let length = self.input[start..]
+            .iter()
+            .position(|&b| b == token)
+            .ok_or(Self::error(start, format!("Missing {what} end token")))?;
+        self.position += length + 1;
+        Ok(&self.input[start..start + length])
+    }
+
+    /// Check the digits of an integer or string length, starting at byte `start`, are in
+    /// canonical form.
+    fn check_digits(&self, digits: &[u8], start: usize) -> Result<(), BitTorrentError> {
+        if !self.options.strict {
+            return Ok(());
+        }
+        let (negative, magnitude) = match digits.split_first() {
+            Some((b'-', magnitude)) => (true, magnitude),
+            _ => (false, digits),
+        };
+        if magnitude.is_empty() || !magnitude.iter().all(u8::is_ascii_digit) {
+            Err(Self::error(start, "Non-canonical number"))
+        } else if magnitude.len() > 1 && magnitude[0] == b'0' {
+            Err(Self::error(start, "Leading zero in number"))
+        } else if negative && magnitude == b"0" {
+            Err(Self::error(start, "Negative zero"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<BencodedRef<'a>, BitTorrentError> {
+        let start = self.position;
+        let value = match self.input.get(start) {
+            None => return Err(Self::error(start, "Missing value")),
+            Some(b'i') => {
+                self.position += 1;
+                let digits = self.take_until(b'e', "integer")?;
+                self.check_digits(digits, start + 1)?;
+                BencodedRef::Int(
+                    std::str::from_utf8(digits)
+                        .ok()
+                        .and_then(|digits| digits.parse().ok())
+                        .ok_or(Self::error(start + 1, "Invalid integer"))?,
+                )
+            }
+            Some(b'l' | b'd') if depth >= self.options.max_depth => {
+                return Err(Self::error(
+                    start,
+                    format!("Nesting deeper than {} levels", self.options.max_depth),
+                ));
+            }
+            Some(b'l') => {
+                self.position += 1;
+                while self.input.get(self.position) != Some(&b'e') {
+                    if self.position >= self.input.len() {
+                        return Err(Self::error(start, "Missing list end token"));
+                    }
+                    self.value(depth + 1)?;
+                }
+                self.position += 1;
+                BencodedRef::List(ListRef(&self.input[start + 1..self.position - 1]))
+            }
+            Some(b'd') => {
+                self.position += 1;
+                let mut last_key: Option<&[u8]> = None;
+                while self.input.get(self.position) != Some(&b'e') {
+                    if self.position >= self.input.len() {
+                        return Err(Self::error(start, "Missing dict end token"));
+                    }
+                    let key_start = self.position;
+                    let BencodedRef::Bytes(key) = self.value(depth + 1)? else {
+                        return Err(Self::error(key_start, "Invalid dict key"));
+                    };
+                    if self.options.strict && last_key.is_some_and(|last_key| key <= last_key) {
+                        return Err(Self::error(key_start, "Unsorted or repeated dict key"));
+                    }
+                    last_key = Some(key);
+                    self.value(depth + 1)?;
+                }
+                self.position += 1;
+                BencodedRef::Dict(DictRef(&self.input[start + 1..self.position - 1]))
+            }
+            Some(b'0'..=b'9') => {
+                let digits = self.take_until(b':', "string length")?;
+                self.check_digits(digits, start)?;
+                let length: usize = std::str::from_utf8(digits)
+                    .ok()
+                    .and_then(|digits| digits.parse().ok())
+                    .ok_or(Self::error(start, "Invalid string length"))?;
+                let string = self
+                    .input
+                    .get(self.position..self.position.saturating_add(length))
+                    .ok_or(Self::error(start, "Insufficient characters remaining"))?;
+                self.position += length;
+                BencodedRef::Bytes(string)
+            }
+            Some(_) => return Err(Self::error(start, "Invalid bencoded value")),
+        };
+        if self.position > self.options.max_size {
+            return Err(Self::error(
+                start,
+                format!("Value larger than {} bytes", self.options.max_size),
+            ));
+        }
+        Ok(value)
+    }
+}
+
+impl<'a> ListRef<'a> {
+    /// Decode the items of the list in order.
+    pub fn iter(self) -> impl Iterator<Item = BencodedRef<'a>> {
+        let mut rest = self.0;
+        // the items were checked when the list was decoded
+        std::iter::from_fn(move || BencodedRef::decode(&mut rest).ok())
+    }
+}
+
+impl<'a> DictRef<'a> {
+    /// Decode the key/value pairs of the dict in their encoded order.
+    pub fn iter(self) -> impl Iterator<Item = (&'a [u8], BencodedRef<'a>)> {
+        let mut rest = self.0;
+        // the pairs were checked when the dict was decoded
+        std::iter::from_fn(move || {
+            let key = BencodedRef::decode(&mut rest).ok()?.as_bytes()?;
+            Some((key, BencodedRef::decode(&mut rest).ok()?))
+        })
+    }
+
+    /// Find the value under `key`, decoding only as much of the dict as it takes.
+    pub fn get(self, key: &[u8]) -> Option<BencodedRef<'a>> {
+        self.iter()
+            .find(|(entry_key, _)| *entry_key == key)
+            .map(|(_, value)| value)
+    }
+}
+
+impl Debug for ListRef<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Debug for DictRef<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.iter().map(|(key, value)| (Bytes::from(key), value)))
+            .finish()
+    }
+}
+
+impl From<BencodedRef<'_>> for BencodedValue {
+    fn from(value: BencodedRef<'_>) -> Self {
+        match value {
+            BencodedRef::Bytes(bytes) => Self::Bytes(Bytes::from(bytes)),
+            BencodedRef::Int(int) => Self::Int(int),
+            BencodedRef::List(list) => Self::List(list.iter().map(Self::from).collect()),
+            BencodedRef::Dict(dict) => Self::Dict(
+                dict.iter()
+                    .map(|(key, value)| (Bytes::from(key), Self::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict(bytes: &[u8]) -> Result<BencodedRef<'_>, BitTorrentError> {
+        BencodedRef::decode_with(&mut &bytes[..], DecodeOptions::UNTRUSTED)
+    }
+
+    #[test]
+    fn decodes_canonical_values() {
+        let value = strict(b"d3:bar4:spam3:fooi42e4:listli-7ei0eee").unwrap();
+        let dict = value.as_dict().unwrap();
+        assert_eq!(
+            dict.get(b"bar").and_then(BencodedRef::as_bytes),
+            Some(&b"spam"[..])
+        );
+        assert_eq!(dict.get(b"foo").and_then(BencodedRef::as_int), Some(42));
+        let list = dict.get(b"list").and_then(BencodedRef::as_list).unwrap();
+        assert_eq!(
+            list.iter().map(|item| item.as_int()).collect::<Vec<_>>(),
+            vec![Some(-7), Some(0)]
+        );
+    }
+
+    #[test]
+    fn decode_advances_past_value() {
+        let mut bytes = &b"i1e4:resti2e"[..];
+        assert_eq!(BencodedRef::decode(&mut bytes).unwrap().as_int(), Some(1));
+        assert_eq!(
+            BencodedRef::decode(&mut bytes).unwrap().as_bytes(),
+            Some(&b"rest"[..])
+        );
+        assert_eq!(bytes, b"i2e");
+    }
+
+    #[test]
+    fn strict_rejects_leading_zeros() {
+        assert!(strict(b"i03e").is_err());
+        assert!(strict(b"i-03e").is_err());
+        assert!(strict(b"03:abc").is_err());
+        assert!(strict(b"i0e").is_ok());
+    }
+
+    #[test]
+    fn strict_rejects_negative_zero() {
+        assert!(strict(b"i-0e").is_err());
+        assert!(strict(b"i-1e").is_ok());
+    }
+
+    #[test]
+    fn strict_rejects_signs_and_empty_numbers() {
+        assert!(strict(b"i+3e").is_err());
+        assert!(strict(b"ie").is_err());
+        assert!(strict(b"i-e").is_err());
+    }
+
+    #[test]
+    fn strict_rejects_unsorted_or_repeated_keys() {
+        assert!(strict(b"d1:bi1e1:ai2ee").is_err());
+        assert!(strict(b"d1:ai1e1:ai2ee").is_err());
+        assert!(strict(b"d1:ai1e1:bi2ee").is_ok());
+    }
+
+    #[test]
+    fn lenient_accepts_non_canonical_values() {
+        let decode = |bytes: &[u8]| BencodedRef::decode(&mut &bytes[..]).map(|_| ());
+        assert!(decode(b"i03e").is_ok());
+        assert!(decode(b"i-0e").is_ok());
+        assert!(decode(b"d1:bi1e1:ai2ee").is_ok());
+    }
+
+    #[test]
+    fn rejects_nesting_beyond_max_depth() {
+        let max_depth = DecodeOptions::UNTRUSTED.max_depth;
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(strict(&nested(max_depth)).is_ok());
+        assert!(strict(&nested(max_depth + 1)).is_err());
+        assert!(BencodedRef::decode(&mut &nested(max_depth + 1)[..]).is_ok());
+    }
+
+    #[test]
+    fn rejects_values_beyond_max_size() {
+        let options = DecodeOptions {
+            max_size: 8,
+            ..DecodeOptions::UNTRUSTED
+        };
+        assert!(BencodedRef::decode_with(&mut &b"5:abcde"[..], options).is_ok());
+        assert!(BencodedRef::decode_with(&mut &b"l5:abcdee"[..], options).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_values() {
+        assert!(strict(b"i42").is_err());
+        assert!(strict(b"5:abc").is_err());
+        assert!(strict(b"l1:a").is_err());
+        assert!(strict(b"d1:a").is_err());
+        assert!(strict(b"di1ei2ee").is_err());
+    }
+
+    #[test]
+    fn converts_to_owned_value() {
+        let value = BencodedValue::from(strict(b"d1:ali1e1:bee").unwrap());
+        let list = value.as_dict().unwrap()[&Bytes::from(&b"a"[..])]
+            .as_list()
+            .unwrap();
+        assert!(matches!(
+            list.as_slice(),
+            [BencodedValue::Int(1), BencodedValue::Bytes(bytes)] if bytes.0 == b"b"
+        ));
+    }
+
+    #[test]
+    fn errors_give_the_offset_of_the_offending_value() {
+        let error = |bytes: &[u8]| strict(bytes).unwrap_err().to_string();
+        assert!(error(b"li1ei03ee").ends_with("Leading zero in number at byte 5"));
+        assert!(error(b"d1:bi1e1:ai2ee").ends_with("Unsorted or repeated dict key at byte 7"));
+        assert!(error(b"di1ei2ee").ends_with("Invalid dict key at byte 1"));
+        assert!(error(b"l1:ax").ends_with("Invalid bencoded value at byte 4"));
+        assert!(error(b"li1e5:abc").ends_with("Insufficient characters remaining at byte 4"));
+        assert!(error(b"l1:a").ends_with("Missing list end token at byte 0"));
+
+        let max_depth = DecodeOptions::UNTRUSTED.max_depth;
+        let nested = [vec![b'l'; max_depth + 1], vec![b'e'; max_depth + 1]].concat();
+        assert!(error(&nested).ends_with(&format!("at byte {max_depth}")));
+    }
+}
@@ -0,0 +1,495 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{
+    de::{self, DeserializeSeed, Visitor},
+    forward_to_deserialize_any, Deserialize,
+};
+
+use crate::{bterror, bytes::Bytes, error::BitTorrentError};
+
+use super::{borrowed::BencodedRef, BencodedValue, Number};
+
+/// Hand byte strings to `visitor` as a string when they are valid utf-8.
+fn visit_text<'de, V: Visitor<'de>>(
+    value: BencodedValue,
+    visitor: V,
+) -> Result<V::Value, BitTorrentError> {
+    match value {
+        BencodedValue::Bytes(bytes) => match String::from_utf8(bytes.into_inner()) {
+            Ok(string) => visitor.visit_string(string),
+            Err(err) => visitor.visit_byte_buf(err.into_bytes()),
+        },
+        value => de::Deserializer::deserialize_any(value, visitor),
+    }
+}
+
+/// Deserializing from a decoded value, the inverse of `ser::Serializer`. Ints stand in for
+/// bools, and missing or `BencodedValue::None` entries for `None`.
+impl<'de> de::Deserializer<'de> for BencodedValue {
+    type Error = BitTorrentError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            BencodedValue::Bytes(bytes) => visitor.visit_byte_buf(bytes.into_inner()),
+            BencodedValue::Int(int) => visitor.visit_i64(int),
+            BencodedValue::List(list) => visitor.visit_seq(ListAccess(list.into_iter())),
+            BencodedValue::Dict(dict) => visitor.visit_map(DictAccess {
+                entries: dict
+                    .into_iter()
+                    .map(|(key, value)| (BencodedValue::Bytes(key), value)),
+                value: None,
+            }),
+            BencodedValue::None => visitor.visit_unit(),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            BencodedValue::Int(int) => visitor.visit_bool(int != 0),
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visit_text(self, visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visit_text(self, visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visit_text(self, visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visit_text(self, visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            BencodedValue::None => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            BencodedValue::Bytes(variant) => visitor.visit_enum(EnumAccess {
+                variant: BencodedValue::Bytes(variant),
+                value: None,
+            }),
+            BencodedValue::Dict(dict) if dict.len() == 1 => {
+                let (variant, value) = dict.into_iter().next().unwrap();
+                visitor.visit_enum(EnumAccess {
+                    variant: BencodedValue::Bytes(variant),
+                    value: Some(value),
+                })
+            }
+            value => Err(bterror!("Invalid enum: {}", value)),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 bytes byte_buf unit unit_struct seq
+        tuple tuple_struct map struct ignored_any
+    }
+}
+
+/// Decoding from a borrowed value, handing out byte strings that borrow from the buffer it
+/// was decoded from. Apart from that, it behaves like decoding from a `BencodedValue`.
+impl<'de> de::Deserializer<'de> for BencodedRef<'de> {
+    type Error = BitTorrentError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            BencodedRef::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            BencodedRef::Int(int) => visitor.visit_i64(int),
+            BencodedRef::List(list) => visitor.visit_seq(ListAccess(list.iter())),
+            BencodedRef::Dict(dict) => visitor.visit_map(DictAccess {
+                entries: dict
+                    .iter()
+                    .map(|(key, value)| (BencodedRef::Bytes(key), value)),
+                value: None,
+            }),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            BencodedRef::Int(int) => visitor.visit_bool(int != 0),
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            BencodedRef::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(string) => visitor.visit_borrowed_str(string),
+                Err(_) => visitor.visit_borrowed_bytes(bytes),
+            },
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            BencodedRef::Bytes(variant) => visitor.visit_enum(EnumAccess {
+                variant: BencodedRef::Bytes(variant),
+                value: None,
+            }),
+            BencodedRef::Dict(dict) if dict.iter().count() == 1 => {
+                let (variant, value) = dict.iter().next().unwrap();
+                visitor.visit_enum(EnumAccess {
+                    variant: BencodedRef::Bytes(variant),
+                    value: Some(value),
+                })
+            }
+            value => Err(bterror!("Invalid enum: {:?}", value)),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 bytes byte_buf unit unit_struct seq
+        tuple tuple_struct map struct ignored_any
+    }
+}
+
+/// Items of a list being deserialized
+struct ListAccess<I>(I);
+
+impl<'de, I, D> de::SeqAccess<'de> for ListAccess<I>
+where
+    I: Iterator<Item = D>,
+    D: de::Deserializer<'de, Error = BitTorrentError>,
+{
+    type Error = BitTorrentError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        self.0
+            .next()
+            .map(|value| seed.deserialize(value))
+            .transpose()
+    }
+}
+
+/// Entries of a dict being deserialized
+struct DictAccess<I, D> {
+    entries: I,
+    /// value of the entry whose key was deserialized last
+    value: Option<D>,
+}
+
+impl<'de, I, D> de::MapAccess<'de> for DictAccess<I, D>
+where
+    I: Iterator<Item = (D, D)>,
+    D: de::Deserializer<'de, Error = BitTorrentError>,
+{
+    type Error = BitTorrentError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(key).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let value = self
+            .value
+            .take()
+            .ok_or(bterror!("Dict value requested before its key"))?;
+        seed.deserialize(value)
+    }
+}
+
+struct EnumAccess<D> {
+    variant: D,
+    /// contents of the variant, absent for unit variants
+    value: Option<D>,
+}
+
+impl<'de, D: de::Deserializer<'de, Error = BitTorrentError>> de::EnumAccess<'de> for EnumAccess<D> {
+    type Error = BitTorrentError;
+    type Variant = VariantAccess<D>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), Self::Error> {
+        let variant = seed.deserialize(self.variant)?;
+        Ok((variant, VariantAccess(self.value)))
+    }
+}
+
+struct VariantAccess<D>(Option<D>);
+
+impl<D> VariantAccess<D> {
+    fn contents(self) -> Result<D, BitTorrentError> {
+        self.0.ok_or(bterror!("Missing enum variant contents"))
+    }
+}
+
+impl<'de, D: de::Deserializer<'de, Error = BitTorrentError>> de::VariantAccess<'de>
+    for VariantAccess<D>
+{
+    type Error = BitTorrentError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        match self.0 {
+            None => Ok(()),
+            Some(_) => Err(bterror!("Unexpected contents for a unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, Self::Error> {
+        seed.deserialize(self.contents()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_seq(self.contents()?, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_map(self.contents()?, visitor)
+    }
+}
+
+struct BencodedValueVisitor;
+
+impl<'de> Visitor<'de> for BencodedValueVisitor {
+    type Value = BencodedValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a bencodable value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(BencodedValue::Int(v as Number))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(BencodedValue::Int(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(BencodedValue::Int(v.try_into().map_err(E::custom)?))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        self.visit_bytes(v.as_bytes())
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(BencodedValue::Bytes(Bytes::from(v)))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(BencodedValue::Bytes(Bytes(v)))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(BencodedValue::None)
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        BencodedValue::deserialize(deserializer)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(BencodedValue::None)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut list = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            list.push(value);
+        }
+        Ok(BencodedValue::List(list))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut dict = HashMap::new();
+        while let Some((key, value)) = map.next_entry()? {
+            dict.insert(key, value);
+        }
+        Ok(BencodedValue::Dict(dict))
+    }
+}
+
+impl<'de> Deserialize<'de> for BencodedValue {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BencodedValueVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::bencode::{borrowed::BencodedRef, from_ref, from_value, to_bytes, BencodedValue};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Event {
+        Started,
+        Resume(u32),
+        Range(u32, u32),
+        Piece { index: u32 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Record {
+        name: String,
+        offset: i64,
+        seeding: bool,
+        #[serde(with = "serde_bytes")]
+        hash: Vec<u8>,
+        comment: Option<String>,
+        tags: Vec<String>,
+        sizes: HashMap<String, u64>,
+        events: Vec<Event>,
+    }
+
+    fn record() -> Record {
+        Record {
+            name: "ubuntu.iso".to_string(),
+            offset: -42,
+            seeding: true,
+            hash: vec![0, 0xff, 0x80, b'e'],
+            comment: None,
+            tags: vec!["linux".to_string(), "".to_string()],
+            sizes: HashMap::from([("a".to_string(), 1), ("b".to_string(), 1 << 40)]),
+            events: vec![
+                Event::Started,
+                Event::Resume(7),
+                Event::Range(1, 2),
+                Event::Piece { index: 3 },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trips_through_owned_values() {
+        let bytes = to_bytes(&record()).unwrap();
+        let value = BencodedValue::ingest(&mut &bytes[..]).unwrap();
+        assert_eq!(from_value::<Record>(value).unwrap(), record());
+    }
+
+    #[test]
+    fn round_trips_through_borrowed_values() {
+        let bytes = to_bytes(&record()).unwrap();
+        let value = BencodedRef::decode(&mut &bytes[..]).unwrap();
+        assert_eq!(from_ref::<Record>(value).unwrap(), record());
+    }
+
+    #[test]
+    fn round_trips_present_options() {
+        let mut expected = record();
+        expected.comment = Some("made with care".to_string());
+        let bytes = to_bytes(&expected).unwrap();
+        let value = BencodedValue::ingest(&mut &bytes[..]).unwrap();
+        assert_eq!(from_value::<Record>(value).unwrap(), expected);
+    }
+
+    #[test]
+    fn borrows_from_the_input_buffer() {
+        #[derive(Deserialize)]
+        struct Peer<'a> {
+            ip: &'a str,
+            #[serde(borrow)]
+            id: &'a [u8],
+        }
+
+        let bytes = b"d2:id3:\x01\x02\x032:ip9:127.0.0.1e";
+        let peer: Peer = from_ref(BencodedRef::decode(&mut &bytes[..]).unwrap()).unwrap();
+        assert_eq!(peer.ip, "127.0.0.1");
+        assert_eq!(peer.id, b"\x01\x02\x03");
+        assert!(std::ptr::eq(peer.id.as_ptr(), bytes[7..].as_ptr()));
+    }
+
+    #[test]
+    fn round_trips_bencoded_values() {
+        let bytes = b"d4:listli1e1:xdee3:numi-3ee".to_vec();
+        let value = BencodedValue::ingest(&mut &bytes[..]).unwrap();
+        let value: BencodedValue = from_value(value).unwrap();
+        assert_eq!(to_bytes(&value).unwrap(), bytes);
+    }
+
+    #[test]
+    fn rejects_mismatched_types() {
+        let value = BencodedValue::ingest(&mut &b"li1ee"[..]).unwrap();
+        assert!(from_value::<Record>(value).is_err());
+        let value = BencodedValue::ingest(&mut &b"d5:Rangei1e6:Resumei2ee"[..]).unwrap();
+        assert!(from_value::<Event>(value).is_err());
+    }
+}
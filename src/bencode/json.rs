@@ -0,0 +1,150 @@
+use serde_json::{Map, Value};
+
+use crate::{bterror, bytes::Bytes, error::BitTorrentError};
+
+use super::{borrowed::DecodeOptions, BencodedValue};
+
+/// Marks a JSON string holding a byte string in hex. Converting to JSON, ints map to numbers,
+/// lists to arrays, dicts to objects, and byte strings (dict keys included) to strings: their
+/// text if they are valid utf-8 that doesn't start with this prefix, and this prefix followed
+/// by their bytes in hex otherwise, e.g. `"hex:00ff"`. Converting back is the exact inverse,
+/// with `null` standing for a missing value and bools for `0` or `1`, like serializing with
+/// serde. Dict keys always come back sorted, so only bencode decoded with `DECODE_OPTIONS`
+/// round trips byte for byte.
+pub const HEX_PREFIX: &str = "hex:";
+
+/// bencode converted to JSON must be canonical, rather than having its key order and number
+/// formatting quietly normalized on the way back
+pub const DECODE_OPTIONS: DecodeOptions = DecodeOptions {
+    strict: true,
+    ..DecodeOptions::LENIENT
+};
+
+fn bytes_to_json(bytes: Bytes) -> String {
+    match String::from_utf8(bytes.into_inner()) {
+        Ok(text) if !text.starts_with(HEX_PREFIX) => text,
+        Ok(text) => format!("{HEX_PREFIX}{}", hex::encode(text)),
+        Err(err) => format!("{HEX_PREFIX}{}", hex::encode(err.into_bytes())),
+    }
+}
+
+fn json_to_bytes(text: String) -> Result<Bytes, BitTorrentError> {
+    match text.strip_prefix(HEX_PREFIX) {
+        Some(encoded) => Ok(Bytes(hex::decode(encoded)?)),
+        None => Ok(Bytes::from(text)),
+    }
+}
+
+impl From<BencodedValue> for Value {
+    fn from(value: BencodedValue) -> Self {
+        match value {
+            BencodedValue::Bytes(bytes) => Value::String(bytes_to_json(bytes)),
+            BencodedValue::Int(int) => Value::Number(int.into()),
+            BencodedValue::List(list) => Value::Array(list.into_iter().map(Value::from).collect()),
+            BencodedValue::Dict(dict) => Value::Object(
+                dict.into_iter()
+                    .map(|(key, value)| (bytes_to_json(key), Value::from(value)))
+                    .collect::<Map<_, _>>(),
+            ),
+            BencodedValue::None => Value::Null,
+        }
+    }
+}
+
+impl TryFrom<Value> for BencodedValue {
+    type Error = BitTorrentError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        Ok(match value {
+            Value::Null => BencodedValue::None,
+            Value::Bool(bool) => BencodedValue::Int(bool.into()),
+            Value::Number(number) => BencodedValue::Int(
+                number
+                    .as_i64()
+                    .ok_or_else(|| bterror!("Bencode has no integer like {}", number))?,
+            ),
+            Value::String(text) => BencodedValue::Bytes(json_to_bytes(text)?),
+            Value::Array(array) => BencodedValue::List(
+                array
+                    .into_iter()
+                    .map(BencodedValue::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(object) => BencodedValue::Dict(
+                object
+                    .into_iter()
+                    .map(|(key, value)| Ok((json_to_bytes(key)?, BencodedValue::try_from(value)?)))
+                    .collect::<Result<_, BitTorrentError>>()?,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn to_json(bytes: &[u8]) -> Value {
+        Value::from(BencodedValue::ingest_with(&mut &bytes[..], DECODE_OPTIONS).unwrap())
+    }
+
+    fn from_json(value: Value) -> Vec<u8> {
+        BencodedValue::try_from(value).unwrap().encode().unwrap()
+    }
+
+    #[test]
+    fn maps_values_to_json() {
+        assert_eq!(
+            to_json(b"d4:infod6:lengthi-12e4:name3:abce5:peersl2:\x00\xffee"),
+            json!({
+                "info": { "length": -12, "name": "abc" },
+                "peers": ["hex:00ff"],
+            })
+        );
+    }
+
+    #[test]
+    fn round_trips_arbitrary_bytes() {
+        let bytes: &[u8] = b"d5:textsl0:1:\x80i0ee2:\xc3\x288:hex:abcde";
+        let value = to_json(bytes);
+        assert_eq!(
+            value,
+            json!({
+                "hex:c328": "hex:6865783a61626364",
+                "texts": ["", "hex:80", 0],
+            })
+        );
+        assert_eq!(from_json(value), bytes);
+    }
+
+    #[test]
+    fn round_trips_through_json_text() {
+        let bytes: &[u8] = b"d8:announce3:\x01\x02\x034:infod6:pieces4:\xde\xad\xbe\xefee";
+        let text = serde_json::to_string(&to_json(bytes)).unwrap();
+        assert_eq!(from_json(serde_json::from_str(&text).unwrap()), bytes);
+    }
+
+    #[test]
+    fn converts_json_only_values() {
+        assert_eq!(
+            from_json(json!({ "private": true, "seed": false, "comment": null })),
+            b"d7:privatei1e4:seedi0ee"
+        );
+    }
+
+    #[test]
+    fn rejects_values_bencode_cannot_hold() {
+        assert!(BencodedValue::try_from(json!(1.5)).is_err());
+        assert!(BencodedValue::try_from(json!(u64::MAX)).is_err());
+        assert!(BencodedValue::try_from(json!("hex:zz")).is_err());
+    }
+
+    #[test]
+    fn rejects_non_canonical_bencode() {
+        for bytes in [&b"d1:bi0e1:ai0ee"[..], b"i007e", b"i-0e", b"02:ab"] {
+            assert!(BencodedValue::ingest_with(&mut &bytes[..], DECODE_OPTIONS).is_err());
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+
+use serde::ser::{self, Serialize};
+
+use crate::{bterror, bytes::Bytes, error::BitTorrentError};
+
+use super::{BencodedValue, Number};
+
+/// Serializer into a `BencodedValue`. Byte strings, strings and chars become bytes, integers
+/// and bools become ints, sequences and tuples become lists, and maps and structs become
+/// dicts, whose keys are sorted when encoded. `None` and unit values become
+/// `BencodedValue::None`, so they are left out of dicts. Enums are externally tagged: unit
+/// variants become their name, and other variants a dict of their name to their contents.
+pub struct Serializer;
+
+/// Contents of a tuple or struct variant, wrapped in a dict keyed by the variant name.
+fn tag(variant: Option<&'static str>, value: BencodedValue) -> BencodedValue {
+    match variant {
+        Some(variant) => {
+            BencodedValue::Dict(HashMap::from([(Bytes::from(variant.as_bytes()), value)]))
+        }
+        None => value,
+    }
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = BencodedValue;
+    type Error = BitTorrentError;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeDict;
+    type SerializeStruct = SerializeDict;
+    type SerializeStructVariant = SerializeDict;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        Ok(BencodedValue::Int(v as Number))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        Ok(BencodedValue::Int(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v.try_into()?)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Self::Error> {
+        Err(bterror!("Bencode can't represent floating point numbers"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Self::Error> {
+        Err(bterror!("Bencode can't represent floating point numbers"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Ok(BencodedValue::Bytes(Bytes::from(v)))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Ok(BencodedValue::None)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Ok(BencodedValue::None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        Ok(tag(Some(variant), value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(SerializeList {
+            items: Vec::with_capacity(len.unwrap_or(0)),
+            variant: None,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Ok(SerializeList {
+            items: Vec::with_capacity(len),
+            variant: Some(variant),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(SerializeDict {
+            dict: HashMap::new(),
+            key: None,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Ok(SerializeDict {
+            dict: HashMap::new(),
+            key: None,
+            variant: Some(variant),
+        })
+    }
+}
+
+/// Sequences, tuples and tuple variants being serialized into a list
+pub struct SerializeList {
+    items: Vec<BencodedValue>,
+    variant: Option<&'static str>,
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = BencodedValue;
+    type Error = BitTorrentError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.items.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(tag(self.variant, BencodedValue::List(self.items)))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = BencodedValue;
+    type Error = BitTorrentError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = BencodedValue;
+    type Error = BitTorrentError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeList {
+    type Ok = BencodedValue;
+    type Error = BitTorrentError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+/// Maps, structs and struct variants being serialized into a dict
+pub struct SerializeDict {
+    dict: HashMap<Bytes, BencodedValue>,
+    /// key of the entry whose value is serialized next
+    key: Option<Bytes>,
+    variant: Option<&'static str>,
+}
+
+impl ser::SerializeMap for SerializeDict {
+    type Ok = BencodedValue;
+    type Error = BitTorrentError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Self::Error> {
+        self.key = Some(
+            key.serialize(Serializer)?
+                .into_bytes()
+                .ok_or(bterror!("Dict keys must be byte strings"))?,
+        );
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or(bterror!("Dict value serialized before its key"))?;
+        self.dict.insert(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(tag(self.variant, BencodedValue::Dict(self.dict)))
+    }
+}
+
+impl ser::SerializeStruct for SerializeDict {
+    type Ok = BencodedValue;
+    type Error = BitTorrentError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.dict
+            .insert(Bytes::from(key.as_bytes()), value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeDict {
+    type Ok = BencodedValue;
+    type Error = BitTorrentError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+impl Serialize for BencodedValue {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            BencodedValue::Bytes(bytes) => bytes.serialize(serializer),
+            BencodedValue::Int(int) => serializer.serialize_i64(*int),
+            BencodedValue::List(list) => serializer.collect_seq(list),
+            BencodedValue::Dict(dict) => serializer.collect_map(dict),
+            BencodedValue::None => serializer.serialize_none(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use crate::bencode::to_bytes;
+
+    #[derive(Serialize)]
+    struct Announce<'a> {
+        port: u16,
+        info_hash: &'a str,
+        compact: bool,
+        trackerid: Option<&'a str>,
+    }
+
+    #[derive(Serialize)]
+    enum Event {
+        Started,
+        Resume(u32),
+        Range(u32, u32),
+        Piece { index: u32 },
+    }
+
+    #[test]
+    fn sorts_struct_keys_and_omits_none() {
+        let announce = Announce {
+            port: 6881,
+            info_hash: "abc",
+            compact: true,
+            trackerid: None,
+        };
+        assert_eq!(
+            to_bytes(&announce).unwrap(),
+            b"d7:compacti1e9:info_hash3:abc4:porti6881ee"
+        );
+    }
+
+    #[test]
+    fn tags_enums_externally() {
+        assert_eq!(to_bytes(&Event::Started).unwrap(), b"7:Started");
+        assert_eq!(to_bytes(&Event::Resume(3)).unwrap(), b"d6:Resumei3ee");
+        assert_eq!(to_bytes(&Event::Range(1, 2)).unwrap(), b"d5:Rangeli1ei2eee");
+        assert_eq!(
+            to_bytes(&Event::Piece { index: 9 }).unwrap(),
+            b"d5:Pieced5:indexi9eee"
+        );
+    }
+
+    #[test]
+    fn encodes_bytes_as_strings_and_vectors_as_lists() {
+        assert_eq!(
+            to_bytes(serde_bytes::Bytes::new(b"\xff\x00")).unwrap(),
+            b"2:\xff\x00"
+        );
+        assert_eq!(to_bytes(&vec![1u8, 2]).unwrap(), b"li1ei2ee");
+    }
+
+    #[test]
+    fn rejects_unrepresentable_values() {
+        assert!(to_bytes(&1.5f64).is_err());
+        assert!(to_bytes(&u64::MAX).is_err());
+    }
+}
@@ -1,21 +1,62 @@
-use std::io::{self, Read};
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use crate::download::corkboard::PieceLocation;
+use crate::{multithread::SyncFlags, storage::Storage};
 
 pub struct DataProxy {
-    pub pieces: Vec<PieceLocation>,
+    pub storage: Arc<dyn Storage>,
     pub piece_length: usize,
     pub length: usize,
     pub cursor: usize,
+    /// pieces fetched so far, when reading while the download is still running
+    pub fetched: Option<Arc<SyncFlags>>,
+    /// how long to wait for a piece that has not been fetched yet before giving up
+    pub timeout: Option<Duration>,
+    /// position of the reader within the torrent, shared with the piece picker
+    pub read_cursor: Arc<AtomicUsize>,
 }
 
 impl DataProxy {
-    pub fn new(pieces: Vec<PieceLocation>, piece_length: usize, length: usize) -> Self {
+    pub fn new(storage: Arc<dyn Storage>, piece_length: usize, length: usize) -> Self {
         Self {
-            pieces,
+            storage,
             piece_length,
             length,
             cursor: 0,
+            fetched: None,
+            timeout: None,
+            read_cursor: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Create a proxy over a download that is still running: reads block until the pieces
+    /// they need are fetched, and the reader's position is published to `read_cursor`.
+    pub fn streaming(
+        storage: Arc<dyn Storage>,
+        piece_length: usize,
+        length: usize,
+        fetched: Arc<SyncFlags>,
+        read_cursor: Arc<AtomicUsize>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            fetched: Some(fetched),
+            timeout,
+            read_cursor,
+            ..Self::new(storage, piece_length, length)
+        }
+    }
+
+    fn set_cursor(&mut self, cursor: usize) {
+        self.cursor = cursor;
+        if self.fetched.is_some() {
+            self.read_cursor.store(cursor, Ordering::Relaxed);
         }
     }
 }
@@ -29,23 +70,48 @@ impl Read for DataProxy {
             let offset_in_piece = self.cursor % self.piece_length;
             let piece_length = (self.length - piece_offset).min(self.piece_length);
             let to_read = (piece_length - offset_in_piece).min(buf.len() - read);
-            let piece = self.pieces.get(current_piece).ok_or(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                format!("Piece index out of range: {current_piece}"),
-            ))?;
-            let piece_location = piece.clone();
-            let piece_data = piece_location.load().map_err(|err| {
-                io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!("Piece not found: {current_piece}: {err}"),
-                )
-            })?;
-            buf[read..read + to_read]
-                .copy_from_slice(&piece_data[offset_in_piece..offset_in_piece + to_read]);
+            if let Some(fetched) = &self.fetched {
+                if !fetched.wait(current_piece, self.timeout) {
+                    // hand back what has been read so far before reporting the timeout
+                    if read > 0 {
+                        break;
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("Timed out waiting for piece {current_piece}"),
+                    ));
+                }
+            }
+            let block = self
+                .storage
+                .read_block(current_piece, offset_in_piece, to_read)
+                .map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Piece not found: {current_piece}: {err}"),
+                    )
+                })?;
+            buf[read..read + to_read].copy_from_slice(&block);
             read += to_read;
-            self.cursor += to_read;
+            self.set_cursor(self.cursor + to_read);
         }
 
         Ok(read)
     }
 }
+
+impl Seek for DataProxy {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let cursor = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.length as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => (self.cursor as u64).checked_add_signed(offset),
+        }
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Seek to a negative or overflowing position",
+        ))?;
+        self.set_cursor(cursor as usize);
+        Ok(cursor)
+    }
+}
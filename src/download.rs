@@ -7,7 +7,7 @@ use anyhow::Context;
 
 use crate::peer::PeerConnection;
 use crate::torrent_source::TorrentSource;
-use crate::tracker::multimodal::Tracker;
+use crate::tracker::multimodal::{AnnounceEvent, Tracker};
 use crate::{bterror, error::BitTorrentError, info::MetaInfo};
 
 pub mod corkboard;
@@ -25,7 +25,7 @@ pub fn download_piece_from_peer<T: PeerConnection<Error = BitTorrentError>>(
         peer_id.to_string(),
         port,
     )?;
-    let (peers, _) = tracker.query().unwrap();
+    let (peers, _) = tracker.query(AnnounceEvent::None).unwrap();
     let peer = peers.get(0).ok_or(bterror!("Tracker has no peers"))?;
     let mut connection = T::new(
         *peer,
@@ -50,7 +50,7 @@ pub fn download_file<T: PeerConnection<Error = BitTorrentError>>(
         peer_id.to_string(),
         port,
     )?;
-    let (peers, _) = tracker.query().unwrap();
+    let (peers, _) = tracker.query(AnnounceEvent::None).unwrap();
 
     let (worker_send, worker_recieve) = mpsc::channel();
     let worker_send = Arc::new(Mutex::new(worker_send));
@@ -58,7 +58,7 @@ pub fn download_file<T: PeerConnection<Error = BitTorrentError>>(
     let (master_send, master_recieve) = mpsc::channel();
     let master_send = Arc::new(Mutex::new(master_send));
 
-    let num_pieces = meta_info.info.pieces.len();
+    let num_pieces = meta_info.piece_count();
 
     for piece_id in 0..num_pieces {
         worker_send
@@ -1,33 +1,37 @@
 use std::{
-    borrow::Cow,
     collections::HashMap,
-    fs::create_dir_all,
     net::SocketAddr,
-    ops::ControlFlow,
+    ops::Range,
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc::{channel, RecvTimeoutError},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
         Arc, Mutex, RwLock,
     },
-    thread::Scope,
-    time::Duration,
+    thread::{self, Scope, ScopedJoinHandle},
+    time::{Duration, Instant},
 };
 
 use crate::{
     bterror,
     data_proxy::DataProxy,
     error::BitTorrentError,
-    info::MetaInfo,
-    multithread::SyncDoor,
-    peer::PeerConnection,
+    info::{FilePriority, MetaInfo},
+    multithread::{SyncDoor, SyncFlags},
+    peer::{blocks::PieceBlocks, PeerConnection},
+    storage::{Geometry, PieceFileStorage, Storage, StorageBackend},
     torrent_source::TorrentSource,
-    tracker::{dht::Dht, multimodal::Tracker},
+    tracker::{
+        dht::Dht,
+        multimodal::{AnnounceEvent, AnnounceMode, Tracker},
+    },
     util::timestr,
 };
 
-use crossbeam::channel::unbounded;
+use crossbeam::channel::{self as crossbeam_channel, unbounded};
+use rayon::prelude::*;
 
+mod choker;
 mod locator;
 mod monitor;
 mod seeder;
@@ -39,70 +43,216 @@ const MAX_INTERVAL: Duration = Duration::from_secs(2 * 60);
 
 const DHT_WORKERS: usize = 64;
 
+/// time in between upload statistics reports while seeding
+const SEED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// amount of data ahead of the reader that sequential mode prioritises (bytes)
+const SEQUENTIAL_WINDOW: usize = 16777216; // 16 MiB
+
 pub struct Corkboard {
     pub meta_info: MetaInfo,
     pub pieces: Vec<Piece>,
     pub peers: HashMap<SocketAddr, Peer>,
     pub finishing: Arc<AtomicBool>,
+    pub storage: Arc<dyn Storage>,
+    /// signals raised as each piece is fetched, for readers waiting on the data
+    pub fetched: Arc<SyncFlags>,
+    /// byte position of the most recently active streaming reader, that sequential mode downloads ahead of
+    pub read_cursor: Arc<AtomicUsize>,
+    /// prioritise the pieces just ahead of `read_cursor`
+    pub sequential: bool,
+    /// peers connected to the seeder, by address
+    pub uploaders: HashMap<SocketAddr, Uploader>,
+    /// total bytes of piece data served to other peers, shared with the tracker for reporting
+    pub uploaded: Arc<AtomicUsize>,
+    /// total bytes of piece data received from other peers, shared with the tracker for reporting
+    pub downloaded: Arc<AtomicUsize>,
+    /// bytes of wanted piece data not fetched yet, shared with the tracker for reporting
+    pub left: Arc<AtomicUsize>,
 }
 
 impl Corkboard {
-    pub fn new(meta_info: MetaInfo) -> Result<Self, BitTorrentError> {
-        Ok(Self {
+    pub fn new(
+        meta_info: MetaInfo,
+        storage: Arc<dyn Storage>,
+        file_priorities: &[FilePriority],
+    ) -> Result<Self, BitTorrentError> {
+        let board = Self {
+            storage,
             pieces: meta_info
-                .info
-                .pieces
-                .iter()
-                .cloned()
+                .piece_priorities(file_priorities)
+                .into_iter()
                 .map(Piece::new)
                 .collect(),
             peers: HashMap::new(),
             finishing: Arc::new(AtomicBool::new(false)),
+            fetched: Arc::new(SyncFlags::new(meta_info.piece_count())),
+            read_cursor: Arc::new(AtomicUsize::new(0)),
+            sequential: false,
+            uploaders: HashMap::new(),
+            uploaded: Arc::new(AtomicUsize::new(0)),
+            downloaded: Arc::new(AtomicUsize::new(0)),
+            left: Arc::new(AtomicUsize::new(0)),
             meta_info,
-        })
+        };
+        board.update_left();
+        Ok(board)
+    }
+
+    /// Range of pieces that sequential mode fetches ahead of the reader.
+    pub fn sequential_window(&self) -> Range<usize> {
+        let piece_length = self.meta_info.info.piece_length;
+        let start = self.read_cursor.load(Ordering::Relaxed) / piece_length;
+        start..start + SEQUENTIAL_WINDOW.div_ceil(piece_length)
+    }
+
+    /// Check the storage, and any piece files in `temp_path` if given, for piece data left
+    /// behind by an interrupted download, and mark every piece whose data still matches its
+    /// hash as fetched. Returns the number of pieces recovered.
+    pub fn resume(&mut self, temp_path: Option<&Path>) -> usize {
+        let storage = self.storage.clone();
+        let meta_info = &self.meta_info;
+        let leftovers = temp_path
+            .map(|temp_path| PieceFileStorage::new(Geometry::new(meta_info), temp_path.into()));
+        let resumed = self
+            .pieces
+            .par_iter_mut()
+            .enumerate()
+            .map(|(piece_id, piece)| {
+                if storage.verify(piece_id, meta_info).unwrap_or(false) {
+                    piece.state = PieceState::Fetched;
+                    return 1;
+                }
+                match leftovers
+                    .as_ref()
+                    .map(|leftovers| leftovers.read_piece(piece_id))
+                {
+                    Some(Ok(data)) if meta_info.check_piece(piece_id, &data) => {
+                        if storage.write_piece(piece_id, data).is_err() {
+                            return 0;
+                        }
+                        piece.state = PieceState::Fetched;
+                        1
+                    }
+                    _ => 0,
+                }
+            })
+            .sum();
+        for (piece_id, piece) in self.pieces.iter().enumerate() {
+            if piece.state == PieceState::Fetched {
+                self.fetched.raise(piece_id);
+            }
+        }
+        self.update_left();
+        resumed
+    }
+
+    /// Check if every wanted piece has been fetched.
+    pub fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|piece| !piece.is_wanted() || piece.state == PieceState::Fetched)
+    }
+
+    /// Count the bytes of wanted piece data that haven't been fetched yet into `left`.
+    pub fn update_left(&self) {
+        let geometry = Geometry::new(&self.meta_info);
+        let left = self
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| piece.is_wanted() && piece.state != PieceState::Fetched)
+            .map(|(piece_id, _)| geometry.piece_range(piece_id).1)
+            .sum();
+        self.left.store(left, Ordering::Relaxed);
+    }
+
+    /// Ratio of the data uploaded to the data downloaded, or to the size of the wanted data if
+    /// none of it had to be downloaded.
+    pub fn share_ratio(&self) -> f64 {
+        let downloaded = match self.downloaded.load(Ordering::Relaxed) {
+            0 => {
+                let geometry = Geometry::new(&self.meta_info);
+                self.pieces
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, piece)| piece.is_wanted())
+                    .map(|(piece_id, _)| geometry.piece_range(piece_id).1)
+                    .sum()
+            }
+            downloaded => downloaded,
+        };
+        self.uploaded.load(Ordering::Relaxed) as f64 / downloaded.max(1) as f64
+    }
+
+    /// Record `bitfield` as the set of pieces the connected peer at `address` has, updating the
+    /// availability of every piece that was gained or lost since the peer's last update.
+    pub fn update_availability(&mut self, address: &SocketAddr, bitfield: &[bool]) {
+        let Some(peer) = self.peers.get_mut(address) else {
+            return;
+        };
+        for (piece_id, piece) in self.pieces.iter_mut().enumerate() {
+            let had = peer.bitfield.get(piece_id).copied().unwrap_or(false);
+            let has = bitfield.get(piece_id).copied().unwrap_or(false);
+            match (had, has) {
+                (false, true) => piece.availability += 1,
+                (true, false) => piece.availability -= 1,
+                _ => {}
+            }
+        }
+        peer.bitfield = bitfield.to_vec();
+    }
+
+    /// Remove the pieces of the peer at `address` from the availability counts, once it is no
+    /// longer connected.
+    pub fn clear_availability(&mut self, address: &SocketAddr) {
+        self.update_availability(address, &[]);
     }
 }
 
 pub struct Piece {
-    pub hash: [u8; 20],
     pub state: PieceState,
+    /// number of connected peers that have this piece
+    pub availability: usize,
+    /// highest priority of the files this piece holds data of
+    pub priority: FilePriority,
+    /// block level progress, once the piece has been picked up
+    pub blocks: Option<Arc<PieceBlocks>>,
+    /// number of workers currently downloading this piece
+    pub downloaders: usize,
 }
 
 impl Piece {
-    fn new(hash: [u8; 20]) -> Self {
+    fn new(priority: FilePriority) -> Self {
         Self {
-            hash,
             state: PieceState::Unfetched,
+            availability: 0,
+            priority,
+            blocks: None,
+            downloaders: 0,
         }
     }
+
+    /// Check if the piece holds data of any file that is not skipped.
+    pub fn is_wanted(&self) -> bool {
+        self.priority != FilePriority::Skip
+    }
+
+    /// Check if some, but not all, of the piece's blocks have been received.
+    pub fn is_partial(&self) -> bool {
+        self.state != PieceState::Fetched
+            && self
+                .blocks
+                .as_ref()
+                .is_some_and(|blocks| blocks.received_count() > 0)
+    }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum PieceState {
     Unfetched,
     InProgress,
-    Fetched(PieceLocation),
-}
-
-#[derive(PartialEq, Eq, Debug, Clone)]
-pub enum PieceLocation {
-    Memory(Vec<u8>),
-    Disk(PathBuf),
-}
-
-impl PieceLocation {
-    pub fn load(&self) -> Result<Cow<Vec<u8>>, BitTorrentError> {
-        match self {
-            Self::Memory(data) => Ok(Cow::Borrowed(data)),
-            Self::Disk(path) => Ok(Cow::Owned(std::fs::read(path)?)),
-        }
-    }
-
-    fn save_to_disk(path: PathBuf, data: Vec<u8>) -> Result<Self, BitTorrentError> {
-        create_dir_all(path.parent().unwrap())?;
-        std::fs::write(path.clone(), data)?;
-        Ok(Self::Disk(path))
-    }
+    Fetched,
 }
 
 #[derive(Clone)]
@@ -111,6 +261,10 @@ pub struct Peer {
     pub benchmarks: Vec<Benchmark>,
     pub performance: Option<f64>,
     pub connection_attempts: usize,
+    /// pieces the peer has, as last counted towards piece availability
+    pub bitfield: Vec<bool>,
+    /// bytes of piece data received from the peer
+    pub downloaded: usize,
 }
 
 impl Peer {
@@ -120,6 +274,8 @@ impl Peer {
             benchmarks: Vec::new(),
             performance: None,
             connection_attempts: 0,
+            bitfield: Vec::new(),
+            downloaded: 0,
         }
     }
 
@@ -141,6 +297,23 @@ impl Peer {
     }
 }
 
+/// Peer that connected to the seeder to download from us
+#[derive(Clone, Default)]
+pub struct Uploader {
+    /// whether the peer wants data from us
+    pub interested: bool,
+    /// whether the choker allows the peer to download from us
+    pub unchoked: bool,
+    /// whether the peer holds the optimistic unchoke, rather than a regular upload slot
+    pub optimistic: bool,
+    /// bytes of piece data sent to the peer
+    pub uploaded: usize,
+    /// rate the peer was ranked by in the last choking round (bytes/s)
+    pub rate: f64,
+    /// bytes sent to and received from the peer at the start of the current choking round
+    pub round_start: (usize, usize),
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum PeerState {
     Fresh,
@@ -165,6 +338,18 @@ pub struct Config {
     pub temp_path: PathBuf,
     pub peer_id: String,
     pub port: u16,
+    /// where fetched pieces are kept
+    pub storage: StorageBackend,
+    /// download priority of each of the torrent's files, in order; unlisted files are normal priority
+    pub file_priorities: Vec<FilePriority>,
+    /// download the pieces just ahead of the streaming reader first, for playback while downloading
+    pub sequential: bool,
+    /// once the download completes, keep seeding until this share ratio is reached
+    pub seed_ratio: Option<f64>,
+    /// once the download completes, keep seeding for this long
+    pub seed_time: Option<Duration>,
+    /// which of the torrent's trackers to announce to at once
+    pub announce_mode: AnnounceMode,
 }
 
 impl Default for Config {
@@ -175,6 +360,12 @@ impl Default for Config {
             temp_path: Path::new("tmp/in-progress/").to_path_buf(),
             peer_id: "00112233445566778899".to_string(),
             port: 6881,
+            storage: StorageBackend::Auto,
+            file_priorities: Vec::new(),
+            sequential: false,
+            seed_ratio: None,
+            seed_time: None,
+            announce_mode: AnnounceMode::default(),
         }
     }
 }
@@ -193,6 +384,173 @@ pub fn corkboard_download<'a, T: PeerConnection>(
     scope: &'a Scope<'a, '_>,
     config: Config,
 ) -> Result<(DataProxy, MetaInfo), BitTorrentError> {
+    start_corkboard_download::<T>(torrent_source, scope, config)?.finish()
+}
+
+/// Entry point of a subtask, run until a message is sent on its alarm
+type SubtaskFn = fn(Arc<RwLock<Corkboard>>, Receiver<()>, Config) -> Result<(), BitTorrentError>;
+
+/// Subtask running alongside the workers, until a message is sent on its alarm
+struct Subtask<'a> {
+    alarm: Sender<()>,
+    handle: ScopedJoinHandle<'a, Result<(), BitTorrentError>>,
+}
+
+impl<'a> Subtask<'a> {
+    /// Spawn `task` in `scope`, handing it a clone of `corkboard` and `config`.
+    fn spawn(
+        scope: &'a Scope<'a, '_>,
+        task: SubtaskFn,
+        corkboard: &Arc<RwLock<Corkboard>>,
+        config: &Config,
+    ) -> Self {
+        let corkboard = corkboard.clone();
+        let (alarm, receiver) = channel();
+        let config = config.clone();
+        let handle = scope.spawn(move || task(corkboard, receiver, config));
+        Self { alarm, handle }
+    }
+}
+
+/// Stop every subtask and wait for them to exit, returning the first error any of them ran
+/// into, e.g. the seeder failing to listen on its port.
+fn stop_subtasks(tasks: Vec<Subtask>) -> Result<(), BitTorrentError> {
+    for task in &tasks {
+        // a subtask that already exited has dropped its alarm
+        let _ = task.alarm.send(());
+    }
+    tasks
+        .into_iter()
+        .map(|task| {
+            task.handle
+                .join()
+                .unwrap_or_else(|_| Err(bterror!("Subtask panicked")))
+        })
+        .fold(Ok(()), Result::and)
+}
+
+/// Download that is running in the background, started by `start_corkboard_download`
+pub struct ActiveDownload<'a> {
+    pub meta_info: MetaInfo,
+    corkboard: Arc<RwLock<Corkboard>>,
+    storage: Arc<dyn Storage>,
+    download_finished: Arc<SyncDoor>,
+    tasks: Vec<Subtask<'a>>,
+    tracker_notify: Vec<Sender<AnnounceEvent>>,
+    dht_killswitch: Arc<AtomicBool>,
+    config: Config,
+}
+
+impl ActiveDownload<'_> {
+    /// Create a reader over the torrent's data that can be used while the download runs,
+    /// blocking for up to `timeout` on each piece that has not been fetched yet.
+    pub fn reader(&self, timeout: Option<Duration>) -> DataProxy {
+        let (fetched, read_cursor) = self
+            .corkboard
+            .read()
+            .map(|board| (board.fetched.clone(), board.read_cursor.clone()))
+            .unwrap();
+        DataProxy::streaming(
+            self.storage.clone(),
+            self.meta_info.info.piece_length,
+            self.meta_info.length(),
+            fetched,
+            read_cursor,
+            timeout,
+        )
+    }
+
+    /// Stop the download before it completes, e.g. when its data is no longer wanted, waiting
+    /// for its workers and subtasks to shut down.
+    pub fn abort(self) -> Result<(), BitTorrentError> {
+        self.corkboard
+            .read()
+            .map(|board| board.finishing.store(true, Ordering::Relaxed))
+            .unwrap();
+        self.download_finished.wait().unwrap();
+        for notify in &self.tracker_notify {
+            notify.send(AnnounceEvent::Stopped).unwrap_or_default();
+        }
+        self.dht_killswitch.store(true, Ordering::Relaxed);
+        stop_subtasks(self.tasks)?;
+        self.storage.flush()
+    }
+
+    /// Wait for the download to complete, keep seeding for as long as the config asks for, then
+    /// shut down its subtasks.
+    pub fn finish(self) -> Result<(DataProxy, MetaInfo), BitTorrentError> {
+        let verbose = self.config.verbose;
+        let log = move |msg: String| {
+            if verbose {
+                println!("[{}] {msg}", timestr())
+            }
+        };
+        let Self {
+            meta_info,
+            corkboard,
+            storage,
+            download_finished,
+            tasks,
+            tracker_notify,
+            dht_killswitch,
+            config,
+        } = self;
+
+        // wait for workers to finish
+        log("Waiting for workers to finish".to_string());
+        download_finished.wait().unwrap();
+
+        println!("Finished downloading");
+
+        // tell the trackers, unless the data was all there to begin with
+        {
+            let board = corkboard.read().unwrap();
+            if board.is_complete() && board.downloaded.load(Ordering::Relaxed) > 0 {
+                for notify in &tracker_notify {
+                    notify.send(AnnounceEvent::Completed).unwrap_or_default();
+                }
+            }
+        }
+
+        // give back to the swarm
+        if config.seed_ratio.is_some() || config.seed_time.is_some() {
+            let complete = corkboard.read().unwrap().is_complete();
+            if complete {
+                storage.flush()?;
+                seed_until_limits(&corkboard, &tasks, &config);
+            }
+        }
+
+        // send kill signals to subtasks
+        log("Killing subtasks".to_string());
+        for notify in tracker_notify {
+            notify.send(AnnounceEvent::Stopped).unwrap_or_default();
+        }
+        dht_killswitch.store(true, Ordering::Relaxed);
+        stop_subtasks(tasks)?;
+
+        // coallate data
+        log("Coallating data".to_string());
+        if !corkboard.read().unwrap().is_complete() {
+            return Err(bterror!("Unfetched piece data remains!"));
+        }
+        storage.flush()?;
+
+        let proxy = DataProxy::new(storage, meta_info.info.piece_length, meta_info.length());
+
+        log("Done".to_string());
+
+        Ok((proxy, meta_info))
+    }
+}
+
+/// Start downloading the torrent in the background, as in `corkboard_download`, returning
+/// as soon as the meta info is known and the workers have been started.
+pub fn start_corkboard_download<'a, T: PeerConnection>(
+    torrent_source: TorrentSource,
+    scope: &'a Scope<'a, '_>,
+    config: Config,
+) -> Result<ActiveDownload<'a>, BitTorrentError> {
     let verbose = config.verbose.clone();
     let log = move |msg: String| {
         if verbose {
@@ -205,35 +563,38 @@ pub fn corkboard_download<'a, T: PeerConnection>(
     let (peer_send, peer_recv) = unbounded();
 
     let dht_killswitch = Arc::new(AtomicBool::new(false));
-    let (tracker_notify, tracker_alarm) = channel::<()>();
     let download_finished = Arc::new(SyncDoor::new());
     download_finished.close().unwrap();
 
-    // spawn tracker
-    {
-        let peer_send = peer_send.clone();
-        let alarm = Arc::new(Mutex::new(tracker_alarm));
-        scope.spawn(move || {
-            log(format!("Initializing tracker"));
-            for (new_peer, should_wait) in tracker {
-                log(format!("New peer from tracker: {new_peer}"));
-                peer_send.send(new_peer).unwrap();
-
-                // wait if requested to do so
-                if let ControlFlow::Break(wait_time) = should_wait {
-                    let wait_time = wait_time.min(MAX_INTERVAL);
-                    log(format!("Waiting {}s", wait_time.as_secs()));
-                    if matches!(
-                        alarm.lock().unwrap().recv_timeout(wait_time),
-                        Err(RecvTimeoutError::Disconnected) | Ok(_)
-                    ) {
-                        break;
-                    }
-                }
-            }
-            log(format!("Trackers exhausted"));
-        });
-    }
+    let mut tracker_notify = Vec::new();
+    let last_sent = Arc::new(Mutex::new(HashMap::new()));
+    let mut spawn_trackers = |tracker: Tracker| {
+        log("Initializing tracker".to_string());
+        for tracker in tracker.split(config.announce_mode) {
+            let peer_send = peer_send.clone();
+            let last_sent = last_sent.clone();
+            let (notify, alarm) = channel();
+            tracker_notify.push(notify);
+            scope.spawn(move || announce(tracker, Some(peer_send), last_sent, alarm, log));
+        }
+    };
+
+    // the peers to retrieve a magnet link's meta info from are needed before it is known how
+    // much of the torrent is left, so its trackers are announced to straight away, and are
+    // told how much is left once the corkboard has worked it out
+    let magnet = matches!(torrent_source, TorrentSource::Magnet(_));
+    let counters = (
+        tracker.uploaded.clone(),
+        tracker.downloaded.clone(),
+        tracker.left.clone(),
+    );
+    let mut tracker = match magnet {
+        true => {
+            spawn_trackers(tracker);
+            None
+        }
+        false => Some(tracker),
+    };
 
     // spawn dht
     {
@@ -244,8 +605,10 @@ pub fn corkboard_download<'a, T: PeerConnection>(
             config.peer_id.clone().into(),
             config.verbose,
         );
+        dht.announce_port = Some(config.port);
+        dht.killswitch = killswitch.clone();
         scope.spawn(move || {
-            log(format!("Initializing DHT"));
+            log("Initializing DHT".to_string());
             let dht_peers = dht.initialize(DHT_WORKERS, scope);
             for peer in dht_peers {
                 // log(format!("New peer from dht: {peer}"));
@@ -263,7 +626,7 @@ pub fn corkboard_download<'a, T: PeerConnection>(
         TorrentSource::File(meta_info) => meta_info,
         TorrentSource::Magnet(_) => {
             println!("Retrieving metadata");
-            log(format!("Retrieving metadata"));
+            log("Retrieving metadata".to_string());
             let meta_info = Arc::new(RwLock::new(None::<MetaInfo>));
             let peer_search_killswitch = Arc::new(AtomicBool::new(false));
             let door = Arc::new(SyncDoor::new());
@@ -314,7 +677,7 @@ pub fn corkboard_download<'a, T: PeerConnection>(
                         }
                     }
                     peer_search_killswitch.store(true, Ordering::Relaxed);
-                    log(format!("Exiting"));
+                    log("Exiting".to_string());
                 });
             }
             door.wait().unwrap();
@@ -328,10 +691,32 @@ pub fn corkboard_download<'a, T: PeerConnection>(
     };
 
     // create corkboard
-    log(format!("Initializing Corkboard"));
-    let corkboard: Arc<RwLock<Corkboard>> =
-        Arc::new(RwLock::new(Corkboard::new(meta_info.clone())?));
+    log("Initializing Corkboard".to_string());
+    let storage = config
+        .storage
+        .open(&meta_info, &config.temp_path, &config.file_priorities)?;
+    let corkboard: Arc<RwLock<Corkboard>> = Arc::new(RwLock::new(Corkboard::new(
+        meta_info.clone(),
+        storage.clone(),
+        &config.file_priorities,
+    )?));
     if let Ok(mut board) = corkboard.write() {
+        board.sequential = config.sequential;
+        match &mut tracker {
+            Some(tracker) => {
+                tracker.uploaded = board.uploaded.clone();
+                tracker.downloaded = board.downloaded.clone();
+                tracker.left = board.left.clone();
+            }
+            None => (board.uploaded, board.downloaded, board.left) = counters,
+        }
+        let resumed = board.resume(Some(&config.temp_path));
+        if resumed > 0 {
+            println!(
+                "Resuming download, {resumed}/{} pieces already present",
+                board.pieces.len()
+            );
+        }
         board.peers.extend(
             peer_list
                 .lock()
@@ -341,22 +726,28 @@ pub fn corkboard_download<'a, T: PeerConnection>(
         );
     }
 
+    // announce ourselves, now that it is known how much of the torrent is left
+    if let Some(tracker) = tracker {
+        spawn_trackers(tracker);
+    }
+
     println!("Downloading {}", meta_info.info.name);
     log(format!(
         "Preparing to download {} pieces",
-        corkboard.clone().read().unwrap().pieces.len(),
+        corkboard
+            .clone()
+            .read()
+            .unwrap()
+            .pieces
+            .iter()
+            .filter(|piece| piece.is_wanted())
+            .count(),
     ));
 
     // spawn subtasks
-    log(format!("Starting subtasks"));
-    let tasks = [monitor::monitor, seeder::seeder].map(|task| {
-        let corkboard = corkboard.clone();
-        let (notify, alarm) = channel();
-        let meta_info = meta_info.clone();
-        let config = config.clone();
-        scope.spawn(move || task(corkboard, alarm, meta_info, config));
-        notify
-    });
+    log("Starting subtasks".to_string());
+    let tasks = [monitor::monitor, seeder::seeder, choker::choker]
+        .map(|task| Subtask::spawn(scope, task, &corkboard, &config));
     {
         let corkboard = corkboard.clone();
         let config = config.clone();
@@ -364,7 +755,7 @@ pub fn corkboard_download<'a, T: PeerConnection>(
     }
 
     // start workers
-    log(format!("Starting workers"));
+    log("Starting workers".to_string());
     for worker_id in 0..config.workers {
         let corkboard = corkboard.clone();
         let meta_info = meta_info.clone();
@@ -375,36 +766,206 @@ pub fn corkboard_download<'a, T: PeerConnection>(
         });
     }
 
-    // wait for workers to finish
-    log(format!("Waiting for workers to finish"));
-    download_finished.wait().unwrap();
+    Ok(ActiveDownload {
+        meta_info,
+        corkboard,
+        storage,
+        download_finished,
+        tasks: tasks.into(),
+        tracker_notify,
+        dht_killswitch,
+        config,
+    })
+}
 
-    println!("Finished downloading");
+/// ## Corkboard Seed
+///
+/// Serve a torrent whose data is already in place in `config.storage`. The data is checked
+/// against the piece hashes first, and only the pieces that match are served. We announce
+/// ourselves on `config.port` to the torrent's trackers and the DHT, then serve peers until
+/// the share ratio or seeding time limit in `config` is reached, or until the process is
+/// stopped if there is none, reporting upload statistics along the way.
+pub fn corkboard_seed<'a>(
+    meta_info: MetaInfo,
+    scope: &'a Scope<'a, '_>,
+    config: Config,
+) -> Result<(), BitTorrentError> {
+    let verbose = config.verbose;
+    let log = move |msg: String| {
+        if verbose {
+            println!("[{}] {msg}", timestr())
+        }
+    };
+
+    // verify the data
+    println!("Verifying {}", meta_info.info.name);
+    let storage = config
+        .storage
+        .open(&meta_info, &config.temp_path, &config.file_priorities)?;
+    let mut board = Corkboard::new(meta_info.clone(), storage, &config.file_priorities)?;
+    // the data is only read, leftovers of a download are not imported into it
+    let verified = board.resume(None);
+    let total = board.pieces.len();
+    if verified == 0 {
+        let name = &meta_info.info.name;
+        return Err(bterror!("None of the data of {name} is present"));
+    }
+    println!("Verified {verified}/{total} pieces");
+
+    let mut tracker = Tracker::new(
+        TorrentSource::File(meta_info.clone()),
+        config.peer_id.clone(),
+        config.port,
+    )?;
+    tracker.uploaded = board.uploaded.clone();
+    tracker.downloaded = board.downloaded.clone();
+    tracker.left = board.left.clone();
+    let corkboard = Arc::new(RwLock::new(board));
+
+    // spawn tracker announcements
+    log("Announcing to trackers".to_string());
+    let mut tracker_notify = Vec::new();
+    let last_sent = Arc::new(Mutex::new(HashMap::new()));
+    for tracker in tracker.split(config.announce_mode) {
+        let last_sent = last_sent.clone();
+        let (notify, alarm) = channel();
+        tracker_notify.push(notify);
+        scope.spawn(move || announce(tracker, None, last_sent, alarm, log));
+    }
+
+    // spawn dht announcements
+    let dht_killswitch = Arc::new(AtomicBool::new(false));
+    {
+        let killswitch = dht_killswitch.clone();
+        let mut dht = Dht::new(
+            TorrentSource::File(meta_info.clone()),
+            config.peer_id.clone().into(),
+            config.verbose,
+        );
+        dht.announce_port = Some(config.port);
+        dht.killswitch = killswitch;
+        scope.spawn(move || {
+            log("Announcing to DHT".to_string());
+            for _ in dht.initialize(DHT_WORKERS, scope) {}
+        });
+    }
+
+    // spawn subtasks
+    log("Starting subtasks".to_string());
+    let tasks = [seeder::seeder, choker::choker]
+        .map(|task| Subtask::spawn(scope, task, &corkboard, &config));
+
+    println!("Seeding {} on port {}", meta_info.info.name, config.port);
+    seed_until_limits(&corkboard, &tasks, &config);
 
     // send kill signals to subtasks
-    log(format!("Killing subtasks"));
-    for alarm in tasks {
-        alarm.send(()).unwrap();
+    log("Killing subtasks".to_string());
+    for notify in tracker_notify {
+        notify.send(AnnounceEvent::Stopped).unwrap_or_default();
     }
-    tracker_notify.send(()).unwrap_or_default();
     dht_killswitch.store(true, Ordering::Relaxed);
+    stop_subtasks(tasks.into())
+}
 
-    // coallate data
-    log(format!("Coallating data"));
-    let data = corkboard
-        .read()
-        .unwrap()
-        .pieces
-        .iter()
-        .map(|piece| match &piece.state {
-            PieceState::Fetched(data_location) => Ok(data_location.clone()),
-            _ => Err(bterror!("Unfetched piece data remains!")),
-        })
-        .collect::<Result<Vec<PieceLocation>, _>>()?;
+/// Announce to `tracker`, starting with the `started` event, until `alarm` is disconnected, no
+/// tracker answers or `stopped` has been announced. In between, wait for the interval the
+/// tracker asks for, up to `MAX_INTERVAL`, or for the next event to announce from `alarm`. The
+/// peers handed out are sent to `peer_send`, except those another tracker sharing `last_sent`
+/// handed out within the interval.
+fn announce(
+    mut tracker: Tracker,
+    peer_send: Option<crossbeam_channel::Sender<SocketAddr>>,
+    last_sent: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+    alarm: Receiver<AnnounceEvent>,
+    log: impl Fn(String),
+) {
+    let mut event = AnnounceEvent::Started;
+    while let Some((peers, interval)) = tracker.query(event) {
+        if event == AnnounceEvent::Stopped {
+            break;
+        }
+        let interval = interval.min(MAX_INTERVAL);
+        if let Some(peer_send) = &peer_send {
+            let now = Instant::now();
+            let mut last_sent = last_sent.lock().unwrap();
+            for peer in peers {
+                if last_sent
+                    .get(&peer)
+                    .is_some_and(|sent| now.duration_since(*sent) < interval)
+                {
+                    continue;
+                }
+                last_sent.insert(peer, now);
+                log(format!("New peer from tracker: {peer}"));
+                if peer_send.send(peer).is_err() {
+                    return;
+                }
+            }
+        }
+        log(format!("Announced, waiting {}s", interval.as_secs()));
+        event = match alarm.recv_timeout(interval) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => AnnounceEvent::None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+    }
+    log("Stopped announcing to trackers".to_string());
+}
 
-    let proxy = DataProxy::new(data, meta_info.info.piece_length, meta_info.length());
+/// Keep serving peers, reporting upload statistics, until the share ratio reaches
+/// `config.seed_ratio` or we have seeded for `config.seed_time`, whichever comes first, or one
+/// of the `tasks` serving peers stops early. Without either limit, this only returns then.
+fn seed_until_limits(corkboard: &Arc<RwLock<Corkboard>>, tasks: &[Subtask], config: &Config) {
+    let uploaded = corkboard
+        .read()
+        .map(|board| board.uploaded.clone())
+        .unwrap();
+    let start_time = Instant::now();
+    let mut last_report = (start_time, uploaded.load(Ordering::Relaxed));
+    loop {
+        // wait until the next report is due, or the time limit is up
+        let remaining = config.seed_time.map_or(SEED_REPORT_INTERVAL, |seed_time| {
+            seed_time.saturating_sub(start_time.elapsed())
+        });
+        thread::sleep(remaining.min(SEED_REPORT_INTERVAL));
+        if tasks.iter().any(|task| task.handle.is_finished()) {
+            return;
+        }
 
-    log(format!("Done"));
+        let (connected, unchoked, ratio) = corkboard
+            .read()
+            .map(|board| {
+                let unchoked = board
+                    .uploaders
+                    .values()
+                    .filter(|uploader| uploader.unchoked)
+                    .count();
+                (board.uploaders.len(), unchoked, board.share_ratio())
+            })
+            .unwrap();
+        let total = uploaded.load(Ordering::Relaxed);
+        let (last_time, last_uploaded) = last_report;
+        let rate = (total - last_uploaded) as f64 / last_time.elapsed().as_secs_f64();
+        last_report = (Instant::now(), total);
+        println!(
+            "{}: Serving {unchoked}/{connected} peers, uploaded {total} bytes ({rate:.0} B/s), share ratio {ratio:.2}",
+            timestr(),
+        );
 
-    Ok((proxy, meta_info))
+        if config
+            .seed_ratio
+            .is_some_and(|seed_ratio| ratio >= seed_ratio)
+        {
+            println!("Reached share ratio {ratio:.2}, done seeding");
+            return;
+        }
+        if config
+            .seed_time
+            .is_some_and(|seed_time| start_time.elapsed() >= seed_time)
+        {
+            let elapsed = start_time.elapsed().as_secs();
+            println!("Seeded for {elapsed}s, done seeding");
+            return;
+        }
+    }
 }
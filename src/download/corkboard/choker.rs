@@ -0,0 +1,365 @@
+use std::{
+    cmp::Ordering,
+    net::SocketAddr,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::seq::IteratorRandom;
+
+use crate::{error::BitTorrentError, util::timestr};
+
+use super::{Config, Corkboard};
+
+/// time in between checks for free upload slots
+const INTERVAL: Duration = Duration::from_secs(1);
+/// time in between re-evaluations of which peers are unchoked
+const ROUND_INTERVAL: Duration = Duration::from_secs(10);
+/// number of choking rounds that the optimistic unchoke is kept for
+const OPTIMISTIC_ROUNDS: usize = 3;
+/// number of peers unchoked on merit, in addition to the optimistic unchoke
+const UPLOAD_SLOTS: usize = 4;
+
+/// Choker thread: decides which of the seeder's peers may download from us. Every round, the
+/// peers that sent us the most data are unchoked, or while seeding, the peers we upload to the
+/// fastest. One more peer is unchoked at random, rotating every few rounds, so that new peers
+/// get a chance to prove themselves.
+pub fn choker(
+    corkboard: Arc<RwLock<Corkboard>>,
+    alarm: Receiver<()>,
+    config: Config,
+) -> Result<(), BitTorrentError> {
+    let log = |msg: String| {
+        if config.verbose {
+            println!("[{}][C] {msg}", timestr())
+        }
+    };
+
+    log("Choker init".to_string());
+    let mut round = 0;
+    let mut round_start = Instant::now();
+    loop {
+        corkboard
+            .write()
+            .map(|mut board| {
+                if round_start.elapsed() >= ROUND_INTERVAL {
+                    rechoke(&mut board, round_start.elapsed(), round);
+                    round += 1;
+                    round_start = Instant::now();
+                    let unchoked = board
+                        .uploaders
+                        .iter()
+                        .filter(|(_, uploader)| uploader.unchoked)
+                        .map(|(address, uploader)| format!("{address} ({:.0}B/s)", uploader.rate))
+                        .collect::<Vec<_>>();
+                    log(format!("Round {round}, unchoked: {unchoked:?}"));
+                } else {
+                    fill_slots(&mut board);
+                }
+            })
+            .unwrap();
+
+        // wait on alarm
+        if matches!(
+            alarm.recv_timeout(INTERVAL),
+            Err(RecvTimeoutError::Disconnected) | Ok(_)
+        ) {
+            break;
+        }
+    }
+    log("Exiting".to_string());
+
+    Ok(())
+}
+
+/// Rank the interested peers by the rate measured over the last `elapsed`, unchoking the best
+/// `UPLOAD_SLOTS` and choking the rest, apart from the optimistic unchoke. A new optimistic
+/// unchoke is picked every `OPTIMISTIC_ROUNDS` rounds, counting from `round` 0, or if the
+/// current one no longer applies.
+fn rechoke(board: &mut Corkboard, elapsed: Duration, round: usize) {
+    let rotate = round.is_multiple_of(OPTIMISTIC_ROUNDS);
+    let seeding = board.is_complete();
+    let received = |board: &Corkboard, address: &SocketAddr| {
+        // the peer's inbound connection is matched to our connections to it by ip address
+        board
+            .peers
+            .iter()
+            .filter(|(peer_address, _)| peer_address.ip() == address.ip())
+            .map(|(_, peer)| peer.downloaded)
+            .sum::<usize>()
+    };
+
+    // measure the rates of the past round
+    let totals = board
+        .uploaders
+        .iter()
+        .map(|(address, uploader)| (*address, (uploader.uploaded, received(board, address))))
+        .collect::<Vec<_>>();
+    for (address, (uploaded, downloaded)) in totals {
+        let uploader = board.uploaders.get_mut(&address).unwrap();
+        let (round_uploaded, round_downloaded) = uploader.round_start;
+        let bytes = match seeding {
+            true => uploaded.saturating_sub(round_uploaded),
+            false => downloaded.saturating_sub(round_downloaded),
+        };
+        uploader.rate = bytes as f64 / elapsed.as_secs_f64();
+        uploader.round_start = (uploaded, downloaded);
+    }
+
+    // unchoke the fastest interested peers
+    let mut ranked = board
+        .uploaders
+        .iter()
+        .filter(|(_, uploader)| uploader.interested)
+        .map(|(address, uploader)| (*address, uploader.rate))
+        .collect::<Vec<_>>();
+    ranked.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    let regular = ranked
+        .iter()
+        .take(UPLOAD_SLOTS)
+        .map(|(address, _)| *address)
+        .collect::<Vec<_>>();
+
+    // keep or replace the optimistic unchoke
+    let optimistic = board
+        .uploaders
+        .iter()
+        .find(|(address, uploader)| {
+            uploader.optimistic && uploader.interested && !regular.contains(address)
+        })
+        .map(|(address, _)| *address)
+        .filter(|_| !rotate)
+        .or_else(|| {
+            ranked
+                .iter()
+                .map(|(address, _)| *address)
+                .filter(|address| !regular.contains(address))
+                .choose(&mut rand::thread_rng())
+        });
+
+    for (address, uploader) in board.uploaders.iter_mut() {
+        uploader.optimistic = Some(*address) == optimistic;
+        uploader.unchoked = uploader.optimistic || regular.contains(address);
+    }
+}
+
+/// Unchoke interested peers while there are free upload slots, so that new peers don't have to
+/// wait for the next round to be served. Every regular unchoke takes up a slot until the next
+/// round, whether or not the peer is still interested.
+fn fill_slots(board: &mut Corkboard) {
+    let unchoked = board
+        .uploaders
+        .values()
+        .filter(|uploader| uploader.unchoked && !uploader.optimistic)
+        .count();
+    for uploader in board
+        .uploaders
+        .values_mut()
+        .filter(|uploader| uploader.interested && !uploader.unchoked)
+        .take(UPLOAD_SLOTS.saturating_sub(unchoked))
+    {
+        uploader.unchoked = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    use super::*;
+    use crate::{
+        bencode::{BencodedValue, Number},
+        bytes::Bytes,
+        dict,
+        download::corkboard::{Peer, PieceState, Uploader},
+        info::MetaInfo,
+        storage::{Geometry, MemoryStorage},
+    };
+
+    const ROUND: Duration = Duration::from_secs(10);
+
+    /// Board of a one piece torrent, with an uploader for each of `uploaders`, given by whether
+    /// it is interested, the bytes we sent it and the bytes it sent us, all in the last round.
+    fn board(seeding: bool, uploaders: &[(bool, usize, usize)]) -> Corkboard {
+        let info = dict! {
+            b"length" => 5 as Number,
+            b"name" => Bytes::from("t".to_string()),
+            b"piece length" => 5 as Number,
+            b"pieces" => Bytes(vec![0; 20]),
+        };
+        let meta_info: MetaInfo = Result::from(dict! { b"info" => info }).unwrap();
+        let storage = Arc::new(MemoryStorage::new(Geometry::new(&meta_info)));
+        let mut board = Corkboard::new(meta_info, storage, &[]).unwrap();
+        if seeding {
+            board.pieces[0].state = PieceState::Fetched;
+        }
+        for (id, &(interested, uploaded, downloaded)) in uploaders.iter().enumerate() {
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, id as u8));
+            board.uploaders.insert(
+                SocketAddr::new(ip, 6881),
+                Uploader {
+                    interested,
+                    uploaded,
+                    ..Default::default()
+                },
+            );
+            // our own connection to the peer, which the data it sent us is counted on
+            let mut peer = Peer::new();
+            peer.downloaded = downloaded;
+            board.peers.insert(SocketAddr::new(ip, 51413), peer);
+        }
+        board
+    }
+
+    /// Last octet of the address of each uploader matching `filter`.
+    fn ids(board: &Corkboard, filter: impl Fn(&Uploader) -> bool) -> HashSet<u8> {
+        board
+            .uploaders
+            .iter()
+            .filter(|(_, uploader)| filter(uploader))
+            .map(|(address, _)| match address.ip() {
+                IpAddr::V4(ip) => ip.octets()[3],
+                IpAddr::V6(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    fn regular(uploader: &Uploader) -> bool {
+        uploader.unchoked && !uploader.optimistic
+    }
+
+    #[test]
+    fn unchokes_the_peers_that_sent_us_the_most() {
+        let mut board = board(
+            false,
+            &[
+                (true, 0, 100),
+                (true, 0, 600),
+                (false, 0, 9000),
+                (true, 9000, 200),
+                (true, 0, 500),
+                (true, 0, 400),
+                (true, 0, 300),
+            ],
+        );
+        rechoke(&mut board, ROUND, 0);
+        assert_eq!(ids(&board, regular), HashSet::from([1, 4, 5, 6]));
+        let optimistic = ids(&board, |uploader| uploader.optimistic);
+        assert_eq!(optimistic.len(), 1);
+        assert!(optimistic.is_subset(&HashSet::from([0, 3])));
+        assert_eq!(
+            ids(&board, |uploader| uploader.unchoked).len(),
+            UPLOAD_SLOTS + 1
+        );
+        let rates = ids(&board, |uploader| uploader.rate == 60.0);
+        assert_eq!(rates, HashSet::from([1]));
+    }
+
+    #[test]
+    fn seeding_unchokes_the_peers_we_upload_to_the_fastest() {
+        let mut board = board(
+            true,
+            &[
+                (true, 100, 9000),
+                (true, 600, 0),
+                (true, 200, 0),
+                (true, 500, 0),
+                (true, 400, 0),
+                (true, 300, 0),
+            ],
+        );
+        rechoke(&mut board, ROUND, 0);
+        assert_eq!(ids(&board, regular), HashSet::from([1, 3, 4, 5]));
+    }
+
+    #[test]
+    fn rates_only_count_the_last_round() {
+        let mut board = board(false, &[(true, 0, 1000), (true, 0, 500)]);
+        rechoke(&mut board, ROUND, 0);
+        for peer in board.peers.values_mut() {
+            peer.downloaded += if peer.downloaded == 500 { 1000 } else { 100 };
+        }
+        rechoke(&mut board, ROUND, 1);
+        assert_eq!(
+            ids(&board, |uploader| uploader.rate == 100.0),
+            HashSet::from([1])
+        );
+        assert_eq!(
+            ids(&board, |uploader| uploader.rate == 10.0),
+            HashSet::from([0])
+        );
+    }
+
+    #[test]
+    fn rotates_the_optimistic_unchoke_every_few_rounds() {
+        let mut board = board(false, &[(true, 0, 0); UPLOAD_SLOTS + 8]);
+        let optimistic = |board: &Corkboard| ids(board, |uploader| uploader.optimistic);
+        let mut picked = HashSet::new();
+        for rotation in 0..10 {
+            rechoke(&mut board, ROUND, rotation * OPTIMISTIC_ROUNDS);
+            let current = optimistic(&board);
+            assert_eq!(current.len(), 1);
+            for round in 1..OPTIMISTIC_ROUNDS {
+                rechoke(&mut board, ROUND, rotation * OPTIMISTIC_ROUNDS + round);
+                assert_eq!(optimistic(&board), current);
+            }
+            picked.extend(current);
+        }
+        // ten picks out of eight candidates landing on the same peer each time is as good as
+        // impossible
+        assert!(picked.len() > 1);
+    }
+
+    #[test]
+    fn replaces_an_optimistic_unchoke_that_lost_interest() {
+        let mut board = board(false, &[(true, 0, 0); UPLOAD_SLOTS + 2]);
+        rechoke(&mut board, ROUND, 0);
+        let optimistic = ids(&board, |uploader| uploader.optimistic);
+        for uploader in board.uploaders.values_mut() {
+            uploader.interested = !uploader.optimistic;
+        }
+        rechoke(&mut board, ROUND, 1);
+        let replacement = ids(&board, |uploader| uploader.optimistic);
+        assert_eq!(replacement.len(), 1);
+        assert_ne!(replacement, optimistic);
+    }
+
+    #[test]
+    fn fills_free_slots_up_to_the_cap() {
+        let mut board = board(false, &[(true, 0, 0); UPLOAD_SLOTS + 2]);
+        fill_slots(&mut board);
+        assert_eq!(ids(&board, regular).len(), UPLOAD_SLOTS);
+        fill_slots(&mut board);
+        assert_eq!(ids(&board, regular).len(), UPLOAD_SLOTS);
+
+        // a peer that lost interest keeps its slot until the next round
+        let lost = *ids(&board, regular).iter().next().unwrap();
+        for (address, uploader) in board.uploaders.iter_mut() {
+            if address.ip() == IpAddr::V4(Ipv4Addr::new(10, 0, 0, lost)) {
+                uploader.interested = false;
+            }
+        }
+        fill_slots(&mut board);
+        assert_eq!(ids(&board, regular).len(), UPLOAD_SLOTS);
+    }
+
+    #[test]
+    fn optimistic_unchokes_take_no_slot() {
+        let mut board = board(false, &[(true, 0, 0); UPLOAD_SLOTS + 2]);
+        let (_, uploader) = board.uploaders.iter_mut().next().unwrap();
+        uploader.unchoked = true;
+        uploader.optimistic = true;
+        fill_slots(&mut board);
+        assert_eq!(ids(&board, regular).len(), UPLOAD_SLOTS);
+        assert_eq!(
+            ids(&board, |uploader| uploader.unchoked).len(),
+            UPLOAD_SLOTS + 1
+        );
+    }
+}
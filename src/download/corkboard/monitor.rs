@@ -6,7 +6,7 @@ use std::{
     time::Duration,
 };
 
-use crate::{error::BitTorrentError, info::MetaInfo, util::timestr};
+use crate::{error::BitTorrentError, util::timestr};
 
 use super::{Config, Corkboard, PeerState, PieceState};
 
@@ -17,7 +17,6 @@ const INTERVAL: Duration = Duration::from_secs(5);
 pub fn monitor(
     corkboard: Arc<RwLock<Corkboard>>,
     alarm: Receiver<()>,
-    _meta_info: MetaInfo,
     config: Config,
 ) -> Result<(), BitTorrentError> {
     let log = |msg: String| {
@@ -33,8 +32,8 @@ pub fn monitor(
             let total_peers = board.peers.len();
             let active_acquired_count = board.peers.iter().filter(|(_, peer)| matches!(peer.state, PeerState::Active(true))).count();
 
-            let total_pieces = board.pieces.len();
-            let fetched_count = board.pieces.iter().filter(|piece| matches!(piece.state, PieceState::Fetched(_))).count();
+            let total_pieces = board.pieces.iter().filter(|piece| piece.is_wanted()).count();
+            let fetched_count = board.pieces.iter().filter(|piece| piece.is_wanted() && matches!(piece.state, PieceState::Fetched)).count();
 
             if config.verbose {
                 // collect peer statistics
@@ -45,8 +44,10 @@ pub fn monitor(
                 let error_count = board.peers.iter().filter(|(_, peer)| matches!(peer.state, PeerState::Error)).count();
 
                 // collect piece statistics
-                let unfetched_count = board.pieces.iter().filter(|piece| matches!(piece.state, PieceState::Unfetched)).count();
+                let unfetched_count = board.pieces.iter().filter(|piece| piece.is_wanted() && matches!(piece.state, PieceState::Unfetched)).count();
+                let skipped_count = board.pieces.iter().filter(|piece| !piece.is_wanted()).count();
                 let in_progress_count = board.pieces.iter().filter(|piece| matches!(piece.state, PieceState::InProgress)).count();
+                let partial_count = board.pieces.iter().filter(|piece| piece.is_wanted() && matches!(piece.state, PieceState::Unfetched) && piece.is_partial()).count();
 
                 // print results
                 log(format!(""));
@@ -64,11 +65,13 @@ pub fn monitor(
                 log(format!("Piece Stats:"));
                 log(format!("Total Pieces:  {total_pieces}"));
                 log(format!("Unfetched:     {unfetched_count}"));
+                log(format!("Partial:       {partial_count}"));
                 log(format!("In Progress:   {in_progress_count}"));
                 log(format!("Fetched:       {fetched_count}"));
+                log(format!("Skipped:       {skipped_count}"));
                 if unfetched_count + in_progress_count < 50 {
                     log(format!(""));
-                    log(format!("Unfetched ids: {:?}", board.pieces.iter().enumerate().filter_map(|(id, piece)| if matches!(piece.state, PieceState::Unfetched) { Some(id) } else { None }).collect::<Vec<_>>()));
+                    log(format!("Unfetched ids: {:?}", board.pieces.iter().enumerate().filter_map(|(id, piece)| if piece.is_wanted() && matches!(piece.state, PieceState::Unfetched) { Some(id) } else { None }).collect::<Vec<_>>()));
                     log(format!("In Progress ids: {:?}", board.pieces.iter().enumerate().filter_map(|(id, piece)| if matches!(piece.state, PieceState::InProgress) { Some(id) } else { None }).collect::<Vec<_>>()));
                 }
                 log(format!(""));
@@ -1,7 +1,8 @@
 use std::{
-    io,
+    io::{self, ErrorKind},
     net::TcpListener,
     sync::{
+        atomic::{self, AtomicBool},
         mpsc::{Receiver, RecvTimeoutError},
         Arc, RwLock,
     },
@@ -14,25 +15,35 @@ use anyhow::Context;
 use crate::{
     bterror,
     error::BitTorrentError,
-    info::MetaInfo,
+    info::{
+        merkle::{self, MERKLE_BLOCK_SIZE},
+        MetaInfo,
+    },
     peer::{
-        message::{HandshakeMessage, PeerMessage, PeerMessageCodec, PieceMessage},
-        tcp::{TcpPeer, CODEC_EXTENSION_CONFIG},
+        blocks::BLOCK_SIZE,
+        message::{
+            HandshakeMessage, HashRequestMessage, HashesMessage, PeerMessage, PeerMessageCodec,
+            PieceMessage,
+        },
+        pipeline::RequestPipeline,
+        tcp::TcpPeer,
+        wire::CODEC_EXTENSION_CONFIG,
     },
     torrent_source::TorrentSource,
     util::timestr,
 };
 
-use super::{Config, Corkboard, Piece, PieceState};
+use super::{Config, Corkboard, Piece, PieceState, Uploader};
 
 /// time in between non-blocking tcp listener requests
 const INTERVAL: Duration = Duration::from_secs(1);
+/// largest block a peer may request, larger requests get the peer disconnected
+const MAX_REQUEST_LENGTH: u32 = BLOCK_SIZE;
 
 /// Seeder thread: allows incoming peer connections and feeds torrent data back to them
 pub fn seeder(
     corkboard: Arc<RwLock<Corkboard>>,
     alarm: Receiver<()>,
-    meta_info: MetaInfo,
     config: Config,
 ) -> Result<(), BitTorrentError> {
     let log = |msg: String| {
@@ -42,13 +53,16 @@ pub fn seeder(
     };
 
     log(format!("Seeder init"));
+    let meta_info = corkboard.read().unwrap().meta_info.clone();
 
-    // let listener = corkboard
-    //     .read()
-    //     .map(|board| TcpListener::bind(format!("0.0.0.0:{}", board.port)).unwrap())
-    //     .unwrap();
-    let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+    let listener = TcpListener::bind(("0.0.0.0", config.port))
+        .with_context(|| format!("Unable to listen on port {}", config.port))?;
     listener.set_nonblocking(true)?;
+    log(format!("Listening on port {}", config.port));
+
+    // connections are served until the seeder itself is stopped, which may be well after the
+    // download finishes
+    let killswitch = Arc::new(AtomicBool::new(false));
 
     for stream in listener.incoming() {
         match stream {
@@ -57,14 +71,11 @@ pub fn seeder(
                 let connection_board = corkboard.clone();
                 let peer_id = config.peer_id.clone();
                 let meta_info = meta_info.clone();
+                let killswitch = killswitch.clone();
 
                 log(format!("New connection from {address}"));
                 thread::spawn(move || {
                     let log = |msg: String| println!("[{}][{}] {msg}", timestr(), address);
-                    let killswitch = connection_board
-                        .read()
-                        .map(|board| board.finishing.clone())
-                        .unwrap();
 
                     // set up connection
                     let mut connection = TcpPeer {
@@ -80,6 +91,9 @@ pub fn seeder(
                         encoder: PeerMessageCodec::new(CODEC_EXTENSION_CONFIG.clone()),
                         decoder: PeerMessageCodec::default(),
                         choked: true,
+                        pipeline: RequestPipeline::default(),
+                        pieces: Vec::new(),
+                        requested: Vec::new(),
                     };
 
                     // recieve handshake
@@ -89,8 +103,19 @@ pub fn seeder(
                         std::str::from_utf8(&handshake.peer_id).context("Peer id not bytes")?
                     ));
 
-                    // send response handshake
-                    connection.handshake()?;
+                    // send response handshake, in whichever swarm of a hybrid torrent the peer
+                    // connected for
+                    if !connection
+                        .torrent_source
+                        .swarm_hashes()?
+                        .contains(&handshake.info_hash)
+                    {
+                        return Err(bterror!("{address} handshake is for a different torrent"));
+                    }
+                    connection.send_peer_message(PeerMessage::Handshake(HandshakeMessage {
+                        info_hash: handshake.info_hash,
+                        peer_id: connection.peer_id.as_bytes().to_vec(),
+                    }))?;
 
                     // send bitfield
                     let bitfield = connection_board
@@ -99,68 +124,23 @@ pub fn seeder(
                             board
                                 .pieces
                                 .iter()
-                                .map(|piece| matches!(piece.state, PieceState::Fetched(_)))
+                                .map(|piece| matches!(piece.state, PieceState::Fetched))
                                 .collect::<Vec<_>>()
                         })
                         .unwrap();
                     connection.send_peer_message(PeerMessage::Bitfield(bitfield))?;
 
-                    // wait for interested
-                    loop {
-                        match connection.await_peer_message()? {
-                            PeerMessage::Interested => break,
-                            PeerMessage::NotInterested => {
-                                return Err::<(), BitTorrentError>(bterror!(
-                                    "{} was not interested",
-                                    address
-                                ))
-                            }
-                            _ => {}
-                        }
-                    }
-
-                    // send unchoke
-                    connection.send_peer_message(PeerMessage::Unchoke)?;
-
-                    // respond to data requests
-                    loop {
-                        match connection.await_peer_message()? {
-                            PeerMessage::Request(request) => {
-                                let piece_id = request.index;
-                                let chunk_data: Vec<u8> = connection_board
-                                    .read()
-                                    .map(|board| {
-                                        let piece_data = match board.pieces.get(piece_id as usize) {
-                                            Some(Piece {
-                                                state: PieceState::Fetched(location),
-                                                ..
-                                            }) => location.load()?,
-                                            _ => {
-                                                return Err(bterror!(
-                                                    "Piece {piece_id} is not fetched"
-                                                ))
-                                            }
-                                        };
-                                        Ok(piece_data
-                                            .get(
-                                                (request.begin as usize)
-                                                    ..((request.begin + request.length) as usize),
-                                            )
-                                            .context("Invalid chunk data")?
-                                            .into_iter()
-                                            .copied()
-                                            .collect())
-                                    })
-                                    .unwrap()?;
-                                connection.send_peer_message(PeerMessage::Piece(PieceMessage {
-                                    index: request.index,
-                                    begin: request.begin,
-                                    block: chunk_data,
-                                }))?;
-                            }
-                            _ => {}
-                        }
-                    }
+                    // register with the choker
+                    connection_board
+                        .write()
+                        .map(|mut board| board.uploaders.insert(address, Uploader::default()))
+                        .unwrap();
+                    let result = serve(&mut connection, &connection_board);
+                    connection_board
+                        .write()
+                        .map(|mut board| board.uploaders.remove(&address))
+                        .unwrap();
+                    result
                 });
             }
             Err(err) => match err.kind() {
@@ -169,6 +149,7 @@ pub fn seeder(
                         alarm.recv_timeout(INTERVAL),
                         Err(RecvTimeoutError::Disconnected) | Ok(_)
                     ) {
+                        killswitch.store(true, atomic::Ordering::Relaxed);
                         break;
                     }
                 }
@@ -178,3 +159,196 @@ pub fn seeder(
     }
     Ok(())
 }
+
+/// Serve the data requests of a peer until it disconnects, choking and unchoking it as the
+/// choker decides. Requests received while the peer is choked are dropped.
+fn serve(
+    connection: &mut TcpPeer,
+    corkboard: &Arc<RwLock<Corkboard>>,
+) -> Result<(), BitTorrentError> {
+    let address = connection.address;
+    let mut choked = true;
+    loop {
+        // follow the choker's decision
+        let unchoked = corkboard
+            .read()
+            .map(|board| {
+                board
+                    .uploaders
+                    .get(&address)
+                    .is_some_and(|uploader| uploader.unchoked)
+            })
+            .unwrap();
+        if unchoked == choked {
+            choked = !unchoked;
+            connection.send_peer_message(match choked {
+                true => PeerMessage::Choke,
+                false => PeerMessage::Unchoke,
+            })?;
+        }
+
+        // wait for the next message, checking back with the choker in the meantime
+        connection.stream.set_read_timeout(Some(INTERVAL))?;
+        match connection.stream.peek(&mut [0; 1]) {
+            Ok(0) => return Err(bterror!("{address} disconnected")),
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if connection.killswitch.load(atomic::Ordering::Relaxed) {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        match connection.await_peer_message()? {
+            message @ (PeerMessage::Interested | PeerMessage::NotInterested) => {
+                let interested = matches!(message, PeerMessage::Interested);
+                corkboard
+                    .write()
+                    .map(|mut board| {
+                        if let Some(uploader) = board.uploaders.get_mut(&address) {
+                            uploader.interested = interested;
+                        }
+                    })
+                    .unwrap();
+            }
+            PeerMessage::HashRequest(request) if request.base_layer == 0 => {
+                let hashes = block_hashes(corkboard, &request)?;
+                connection.send_peer_message(match hashes {
+                    Some(hashes) => PeerMessage::Hashes(HashesMessage { request, hashes }),
+                    None => PeerMessage::HashReject(request),
+                })?;
+            }
+            PeerMessage::HashRequest(request) => {
+                let hashes = corkboard
+                    .read()
+                    .map(|board| layer_hashes(&board.meta_info, &request))
+                    .unwrap();
+                connection.send_peer_message(match hashes {
+                    Some(hashes) => PeerMessage::Hashes(HashesMessage { request, hashes }),
+                    None => PeerMessage::HashReject(request),
+                })?;
+            }
+            PeerMessage::Request(request) if request.length > MAX_REQUEST_LENGTH => {
+                return Err(bterror!(
+                    "{address} requested a block of {} bytes",
+                    request.length
+                ));
+            }
+            PeerMessage::Request(request) if !choked => {
+                let piece_id = request.index;
+                let storage = corkboard
+                    .read()
+                    .map(|board| match board.pieces.get(piece_id as usize) {
+                        Some(Piece {
+                            state: PieceState::Fetched,
+                            ..
+                        }) => Ok(board.storage.clone()),
+                        _ => Err(bterror!("Piece {piece_id} is not fetched")),
+                    })
+                    .unwrap()?;
+                let chunk_data = storage.read_block(
+                    piece_id as usize,
+                    request.begin as usize,
+                    request.length as usize,
+                )?;
+                let length = chunk_data.len();
+                connection.send_peer_message(PeerMessage::Piece(PieceMessage {
+                    index: request.index,
+                    begin: request.begin,
+                    block: chunk_data,
+                }))?;
+
+                // record the upload
+                corkboard
+                    .write()
+                    .map(|mut board| {
+                        board.uploaded.fetch_add(length, atomic::Ordering::Relaxed);
+                        if let Some(uploader) = board.uploaders.get_mut(&address) {
+                            uploader.uploaded += length;
+                        }
+                    })
+                    .unwrap();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Hashes answering a v2 hash request, if it asks for part of a piece layer we know: the
+/// requested hashes followed by the uncle hashes proving them.
+fn layer_hashes(meta_info: &MetaInfo, request: &HashRequestMessage) -> Option<Vec<[u8; 32]>> {
+    let piece_length = meta_info.info.piece_length;
+    if request.base_layer != (piece_length / MERKLE_BLOCK_SIZE).trailing_zeros() {
+        return None;
+    }
+    merkle::layer_proof(
+        meta_info.piece_layers.get(&request.pieces_root)?,
+        piece_length,
+        request.index as usize,
+        request.length as usize,
+        request.proof_layers as usize,
+    )
+}
+
+/// Hashes answering a v2 hash request for blocks of a piece we have: the requested block hashes
+/// followed by the uncle hashes proving them, from the blocks of the piece and then from the
+/// piece layer above it.
+fn block_hashes(
+    corkboard: &RwLock<Corkboard>,
+    request: &HashRequestMessage,
+) -> Result<Option<Vec<[u8; 32]>>, BitTorrentError> {
+    let (index, length) = (request.index as usize, request.length as usize);
+    let proof_layers = request.proof_layers as usize;
+    let found = corkboard
+        .read()
+        .map(|board| {
+            let meta_info = &board.meta_info;
+            let piece_id = meta_info.block_piece(&request.pieces_root, index)?;
+            let subtree = meta_info.piece_subtree(piece_id)?;
+            if !matches!(board.pieces.get(piece_id)?.state, PieceState::Fetched)
+                || subtree.pieces_root != request.pieces_root
+                || index + length > subtree.first_block + subtree.width
+            {
+                return None;
+            }
+
+            // uncles above the piece, a file no longer than a piece has none
+            let inner_layers = (subtree.width / length.max(1)).trailing_zeros() as usize;
+            let layer = meta_info.piece_layers.get(&request.pieces_root);
+            let outer = match (proof_layers.saturating_sub(inner_layers), layer) {
+                (0, _) => Vec::new(),
+                (_, None) if subtree.root == Some(subtree.pieces_root) => Vec::new(),
+                (outer_layers, layer) => merkle::layer_proof(
+                    layer?,
+                    meta_info.info.piece_length,
+                    subtree.first_block / subtree.width,
+                    1,
+                    outer_layers,
+                )?
+                .split_off(1),
+            };
+            Some((piece_id, subtree, board.storage.clone(), outer))
+        })
+        .unwrap();
+    let Some((piece_id, subtree, storage, outer)) = found else {
+        return Ok(None);
+    };
+
+    // the piece is read and hashed outside of the lock
+    let data = storage.read_piece(piece_id)?;
+    let mut hashes = merkle::block_hashes(&data[..subtree.length.min(data.len())]);
+    hashes.resize(subtree.width, merkle::ZERO_HASH);
+    Ok(merkle::layer_proof(
+        &hashes,
+        MERKLE_BLOCK_SIZE,
+        index - subtree.first_block,
+        length,
+        proof_layers,
+    )
+    .map(|mut proof| {
+        proof.extend(outer);
+        proof
+    }))
+}
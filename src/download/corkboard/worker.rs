@@ -1,4 +1,6 @@
 use std::{
+    cmp::Reverse,
+    collections::VecDeque,
     error::Error,
     net::SocketAddr,
     sync::{atomic, Arc, RwLock},
@@ -6,15 +8,18 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+use rand::seq::IteratorRandom;
+
 use crate::{
     error::BitTorrentError,
     info::MetaInfo,
-    peer::PeerConnection,
+    peer::{blocks::PieceBlocks, PeerConnection},
+    storage::Geometry,
     torrent_source::TorrentSource,
-    util::{sha1_hash, sleep, timestr}, multithread::SyncDoor,
+    util::{sleep, timestr}, multithread::SyncDoor,
 };
 
-use super::{Benchmark, Config, Corkboard, PeerState, Piece, PieceLocation, PieceState};
+use super::{Benchmark, Config, Corkboard, PeerState, Piece, PieceState};
 
 /// maximum number of time a given peer can be reused before it should be dropped
 const MAX_PEER_USES: usize = 500;
@@ -22,8 +27,6 @@ const MAX_PEER_USES: usize = 500;
 const EMPTY_PEER_WAIT: Duration = Duration::from_secs(1);
 /// conversion factor of bytes per millisecond to mebibites per second
 const MB_S: f64 = 1048.576;
-/// maximum total torrent size before pieces are saved to disk as opposed to being cached in memory (bytes)
-const MAX_MEMORY_SIZE: usize = 52428800; // 50 MB
 /// maximum reconnection attempts to be made to a given peer
 // const MAX_RECONNECT_ATTEMPTS: usize = 5;
 
@@ -58,14 +61,10 @@ where
         .write()
         .map(|mut board| {
             // If all pieces have been acquired, exit
-            if board.finishing.load(atomic::Ordering::Relaxed)
-                || board
-                    .pieces
-                    .iter()
-                    .all(|piece| matches!(piece.state, PieceState::Fetched(_)))
-            {
+            if board.finishing.load(atomic::Ordering::Relaxed) || board.is_complete() {
                 log(format!("All pieces have been acquired, exiting"));
                 active_connection.as_ref().map(|conn| {
+                    board.clear_availability(conn.address());
                     board
                         .peers
                         .entry(*conn.address())
@@ -82,10 +81,12 @@ where
                     if peer.state == PeerState::Inactive || peer.state == PeerState::Fresh {
                         // existing peer is deactivated or refreshed: drop the connection and pick up a new one
                         log(format!("Existing peer is fresh or inactive, refetching"));
+                        board.clear_availability(&address);
                         PeerSearchResult::PromptRefetch
                     } else if uses >= MAX_PEER_USES {
                         // peer has been reused too many times, drop it and find another peer
                         log(format!("Existing peer is overused, refetching"));
+                        board.clear_availability(&address);
                         board
                             .peers
                             .entry(address)
@@ -157,12 +158,15 @@ where
 }
 
 /// mutual exclusion zone 2:
-/// * attempt to find a new piece to download
+/// * attempt to find a new piece to download, other than the pieces in `held` that are already
+///   being downloaded from the peer
+/// * drop the peer if it has no pieces to offer at all
 fn find_next_piece<T, F>(
     corkboard: &Arc<RwLock<Corkboard>>,
     connection: &T,
+    held: &[usize],
     log: F,
-) -> Option<usize>
+) -> Option<(usize, Arc<PieceBlocks>, bool)>
 where
     T: PeerConnection,
     F: Fn(String),
@@ -170,49 +174,93 @@ where
     corkboard
         .write()
         .map(|mut board| {
-            // once there are no unfetched pieces left to acquire,
-            // in progress pieces become fair game for other workers to pick up
-            // to speed up the final few piece downloads
-            let piece_valid_predicate = if board
+            // pick up any pieces the peer has announced since it was last seen
+            board.update_availability(connection.address(), connection.bitfield());
+
+            // once there are no unfetched pieces left to acquire, the download enters endgame:
+            // in progress pieces become fair game for other workers to pick up, requesting
+            // their outstanding blocks from several peers at once to speed up the final few
+            // piece downloads
+            let endgame = !board
                 .pieces
                 .iter()
-                .any(|piece| matches!(piece.state, PieceState::Unfetched))
-            {
+                .any(|piece| piece.is_wanted() && matches!(piece.state, PieceState::Unfetched));
+            let piece_valid_predicate = if !endgame {
                 |piece: &Piece| matches!(piece.state, PieceState::Unfetched)
             } else {
                 |piece: &Piece| matches!(piece.state, PieceState::Unfetched | PieceState::InProgress)
             };
 
-            // try to find a piece to download
-            let next_piece = board
+            // try to find the rarest piece of the highest priority to download,
+            // breaking ties at random
+            let candidates = board
                 .pieces
                 .iter()
                 .enumerate()
-                .zip(connection.bitfield().iter())
-                .find(|((_, piece), has_piece)| piece_valid_predicate(piece) && **has_piece)
-                .map(|((piece_id, _), _)| piece_id);
+                .filter(|(piece_id, piece)| {
+                    piece.is_wanted()
+                        && piece_valid_predicate(piece)
+                        && connection.has(*piece_id)
+                        && !held.contains(piece_id)
+                })
+                .collect::<Vec<_>>();
+            // in sequential mode, the pieces just ahead of the reader come first, and the
+            // rest follow in order of priority then position; partially downloaded pieces
+            // are finished before new ones are started
+            let window = board.sequential.then(|| board.sequential_window());
+            let rank = |piece_id: usize, piece: &Piece| {
+                let fresh = !piece.is_partial();
+                match &window {
+                    Some(window) if window.contains(&piece_id) => {
+                        (false, piece_id, Reverse(piece.priority), fresh, 0)
+                    }
+                    Some(_) => (true, 0, Reverse(piece.priority), fresh, piece_id),
+                    None => (true, 0, Reverse(piece.priority), fresh, piece.availability),
+                }
+            };
+            let best = candidates
+                .iter()
+                .map(|(piece_id, piece)| rank(*piece_id, piece))
+                .min();
+            let next_piece = candidates
+                .into_iter()
+                .filter(|(piece_id, piece)| Some(rank(*piece_id, piece)) == best)
+                .map(|(piece_id, _)| piece_id)
+                .choose(&mut rand::thread_rng());
             match next_piece {
                 // if piece was found, mark it as in progress
-                Some(piece) => {
-                    log(format!("Chose piece {piece}"));
-                    board.pieces.get_mut(piece).map(|piece| {
-                        piece.state = PieceState::InProgress;
-                    });
+                Some(piece_id) => {
+                    log(format!("Chose piece {piece_id}"));
+                    let piece_size = board.meta_info.piece_size(piece_id);
+                    let piece = &mut board.pieces[piece_id];
+                    piece.state = PieceState::InProgress;
+                    piece.downloaders += 1;
+                    let blocks = piece
+                        .blocks
+                        .get_or_insert_with(|| {
+                            Arc::new(PieceBlocks::new(piece_id as u32, piece_size as u32))
+                        })
+                        .clone();
+                    Some((piece_id, blocks, endgame))
                 }
 
+                // the peer is still busy with the pieces it holds
+                None if !held.is_empty() => None,
+
                 // if no piece was found, mark peer as superceded and try again
                 None => {
                     log(format!(
                         "No pieces available, dropping peer {}",
                         connection.address()
                     ));
+                    board.clear_availability(connection.address());
                     board
                         .peers
                         .entry(connection.address().clone())
                         .and_modify(|peer| peer.state = PeerState::Superceded);
+                    None
                 }
-            };
-            next_piece
+            }
         })
         .unwrap()
 }
@@ -220,25 +268,35 @@ where
 /// mutual exclusion zone 3:
 /// * check the download result
 /// * update the peer's performance statistics
+///
+/// then, without holding up the other workers:
 /// * check the downloaded data's hash
 /// * store the downloaded data
+///
+/// and finally mark the piece as fetched, or as unfetched if it could not be used
+#[allow(clippy::too_many_arguments)]
 fn finalize_download<T, F>(
     corkboard: &Arc<RwLock<Corkboard>>,
-    download_result: Result<Vec<u8>, T::Error>,
+    download_result: Result<(), T::Error>,
     download_duration: usize,
     piece_id: usize,
+    blocks: &PieceBlocks,
     connection: &T,
+    meta_info: &MetaInfo,
     log: F,
-    config: &Config,
 ) -> Result<LoopAction, BitTorrentError>
 where
     T: PeerConnection,
     T::Error: Error,
     F: Fn(String),
 {
-    corkboard
+    let assembled = corkboard
         .write()
         .map(|mut board| {
+            let piece = &mut board.pieces[piece_id];
+            piece.downloaders -= 1;
+            let abandoned = piece.downloaders == 0;
+
             match download_result {
                 // download failed
                 Err(err) => {
@@ -248,30 +306,44 @@ where
                     ));
 
                     // mark peer as disconnected & sever connection
+                    board.clear_availability(connection.address());
                     board
                         .peers
                         .entry(connection.address().clone())
                         .and_modify(|peer| peer.state = PeerState::Active(false));
                     connection.sever().unwrap();
 
-                    // mark piece as unfetched
-                    board.pieces.get_mut(piece_id).map(|piece| {
-                        if !matches!(piece.state, PieceState::Fetched(_)) {
-                            piece.state = PieceState::Unfetched;
-                        }
-                    });
+                    // mark piece as unfetched, unless other workers are still downloading it,
+                    // keeping the blocks received so far for the next peer to build on
+                    let piece = &mut board.pieces[piece_id];
+                    if abandoned && piece.state != PieceState::Fetched {
+                        piece.state = PieceState::Unfetched;
+                    }
 
                     // try again
-                    Ok(LoopAction::Continue)
+                    Err(LoopAction::Continue)
                 }
 
                 // download succeeded
-                Ok(data) => {
+                Ok(()) => {
                     log(format!(
                         "Finished downloading piece {piece_id} from {}",
                         connection.address()
                     ));
 
+                    // the piece may have been completed by another worker in endgame
+                    if board.pieces[piece_id].state == PieceState::Fetched {
+                        return Err(LoopAction::Pass);
+                    }
+                    let Some(data) = blocks.assemble() else {
+                        // the rest of the piece is still being downloaded by other workers, or
+                        // the peer choked us part way through
+                        if abandoned {
+                            board.pieces[piece_id].state = PieceState::Unfetched;
+                        }
+                        return Err(LoopAction::Pass);
+                    };
+
                     // update peer performance
                     board
                         .peers
@@ -289,55 +361,138 @@ where
                             ));
                         });
 
-                    // check hash
-                    let should_save_to_disk = board.meta_info.length() > MAX_MEMORY_SIZE;
-                    board
-                        .pieces
-                        .get_mut(piece_id)
-                        .map_or(Ok(LoopAction::Continue), |piece| {
-                            if piece.hash == sha1_hash(&data) {
-                                // if hash matches, store data & keep peer for next loop
-                                // if !config.verbose {
-                                //     println!(
-                                //         "Downloaded piece {piece_id} from {}",
-                                //         connection.address()
-                                //     );
-                                // }
-
-                                piece.state = PieceState::Fetched(if should_save_to_disk {
-                                    log(format!("Saving to disk"));
-                                    PieceLocation::save_to_disk(
-                                        config.temp_path.join(format!("piece-{}.dat", piece_id)),
-                                        data,
-                                    )?
-                                } else {
-                                    log(format!("Saving to memory"));
-                                    PieceLocation::Memory(data)
-                                });
-                                Ok(LoopAction::Pass)
-                            } else {
-                                log(format!(
-                                    "Hash of piece {piece_id} does not match, dropping data"
-                                ));
-
-                                // if hash does not match, mark piece as unfetched
-                                piece.state = PieceState::Unfetched;
-                                Ok(LoopAction::Continue)
-                            }
-                        })
+                    Ok((data, board.storage.clone()))
+                }
+            }
+        })
+        .unwrap();
+    let (mut data, storage) = match assembled {
+        Ok(assembled) => assembled,
+        Err(action) => return Ok(action),
+    };
+
+    // check hash
+    if !meta_info.check_piece(piece_id, &data) {
+        log(format!(
+            "Hash of piece {piece_id} does not match, dropping data"
+        ));
+
+        // if hash does not match, mark piece as unfetched, starting over from a fresh buffer, and
+        // drop the connection as the piece could not be used
+        blocks.reset();
+        corkboard
+            .write()
+            .map(|mut board| {
+                let piece = &mut board.pieces[piece_id];
+                if piece.state != PieceState::Fetched {
+                    piece.state = PieceState::Unfetched;
+                    piece.blocks = None;
+                }
+                board.clear_availability(connection.address());
+            })
+            .unwrap();
+        return Ok(LoopAction::Continue);
+    }
+
+    // if hash matches, store data & keep peer for next loop, along with the padding at the end
+    // of a file of a v2-only torrent, which peers don't send
+    log(format!("Saving piece {piece_id}"));
+    data.resize(Geometry::new(meta_info).piece_range(piece_id).1, 0);
+    let length = data.len();
+    let written = storage.write_piece(piece_id, data);
+
+    // ! mutual exclusion zone 4: mark the stored piece as fetched
+    corkboard
+        .write()
+        .map(|mut board| {
+            let fetched = board.fetched.clone();
+            let left = board.left.clone();
+            let piece = &mut board.pieces[piece_id];
+            if piece.state == PieceState::Fetched {
+                return Ok(LoopAction::Pass);
+            }
+            match written {
+                Ok(()) => {
+                    // the data is in storage now, so the blocks are no longer needed
+                    piece.state = PieceState::Fetched;
+                    piece.blocks = None;
+                    fetched.raise(piece_id);
+                    if piece.is_wanted() {
+                        left.fetch_sub(length, atomic::Ordering::Relaxed);
+                    }
+                    Ok(LoopAction::Pass)
+                }
+                Err(err) => {
+                    // give the piece back to be downloaded again
+                    log(format!("Failed to save piece {piece_id}: {err}"));
+                    piece.state = PieceState::Unfetched;
+                    Err(err)
                 }
             }
         })
         .unwrap()
 }
 
+/// Give back the pieces queued up to be downloaded from the peer at `address`, as the connection
+/// to it is being dropped.
+fn abandon_pieces(
+    corkboard: &Arc<RwLock<Corkboard>>,
+    queued: &mut VecDeque<(usize, Arc<PieceBlocks>, bool)>,
+    address: &SocketAddr,
+) {
+    if queued.is_empty() {
+        return;
+    }
+    corkboard
+        .write()
+        .map(|mut board| {
+            for (piece_id, blocks, _) in queued.drain(..) {
+                blocks.release_all(address);
+                let piece = &mut board.pieces[piece_id];
+                piece.downloaders -= 1;
+                if piece.downloaders == 0 && piece.state != PieceState::Fetched {
+                    piece.state = PieceState::Unfetched;
+                }
+            }
+        })
+        .unwrap();
+}
+
+/// Share the piece layers of a v2-only torrent fetched from a peer along with `fetched` with the
+/// corkboard, and pick up those fetched by other workers, so that each layer only has to be
+/// fetched once, both to check pieces against and to serve to other peers.
+fn share_piece_layers(
+    corkboard: &Arc<RwLock<Corkboard>>,
+    meta_info: &mut MetaInfo,
+    fetched: Option<&MetaInfo>,
+) {
+    if meta_info.is_v1() {
+        return;
+    }
+    corkboard
+        .write()
+        .map(|mut board| {
+            let layers = &mut board.meta_info.piece_layers;
+            for (root, layer) in fetched.iter().flat_map(|fetched| &fetched.piece_layers) {
+                layers.entry(*root).or_insert_with(|| layer.clone());
+            }
+            for (root, layer) in layers.iter() {
+                meta_info
+                    .piece_layers
+                    .entry(*root)
+                    .or_insert_with(|| layer.clone());
+            }
+        })
+        .unwrap();
+}
+
 /// Worker thread: connects to peers and downloads pieces from them
 /// * `corkboard`: shared corkboard for coordinating peer connections and downloaded pieces
 /// * `worker_id`: worker id number
 pub fn worker<T>(
     corkboard: Arc<RwLock<Corkboard>>,
     worker_id: usize,
-    meta_info: MetaInfo,
+    mut meta_info: MetaInfo,
     finished_door: Arc<SyncDoor>,
     config: Config,
 ) -> Result<(), BitTorrentError>
@@ -356,15 +511,26 @@ where
 
     let mut active_connection: Option<T> = None;
     let mut uses = 0;
+    // pieces already requested from the active connection, to download next
+    let mut queued = VecDeque::new();
 
     loop {
         // ! mutual exclusion zone 1: search for a peer to use / connect to
+        let address = active_connection
+            .as_ref()
+            .map(|connection| *connection.address());
         let peer_search_result = search_for_peer(&corkboard, active_connection, uses, log);
+        if let Some(address) = address {
+            if !matches!(peer_search_result, PeerSearchResult::Reuse(_)) {
+                abandon_pieces(&corkboard, &mut queued, &address);
+            }
+        }
 
         active_connection = None;
         let mut connection = match peer_search_result {
             PeerSearchResult::ConnectNew(address) => {
                 // try to connect to the new peer
+                share_piece_layers(&corkboard, &mut meta_info, None);
                 let connection_result = T::new(
                     address.clone(),
                     TorrentSource::File(meta_info.clone()),
@@ -381,6 +547,7 @@ where
                     // if successful, mark peer as active & claimed
                     Ok(connection) => {
                         log(format!("Connected to {address}"));
+                        share_piece_layers(&corkboard, &mut meta_info, connection.meta_info());
                         uses = 0;
                         corkboard
                             .write()
@@ -389,6 +556,7 @@ where
                                     .peers
                                     .entry(address.clone())
                                     .and_modify(|peer| peer.state = PeerState::Active(true));
+                                board.update_availability(&address, connection.bitfield());
                             })
                             .unwrap();
                         connection
@@ -431,8 +599,11 @@ where
             }
         };
 
-        // ! mutual exclusion zone 2: search for a piece to download
-        let piece_id = match find_next_piece(&corkboard, &connection, log) {
+        // ! mutual exclusion zone 2: search for a piece to download, unless one is queued up
+        let next_piece = queued
+            .pop_front()
+            .or_else(|| find_next_piece(&corkboard, &connection, &[], log));
+        let (piece_id, blocks, endgame) = match next_piece {
             Some(piece) => piece,
             None => continue,
         };
@@ -444,28 +615,59 @@ where
 
         // download piece, recording download time
         let start_time = SystemTime::now();
-        let result = connection.download_piece(piece_id as u32);
+        let downloaded = connection.downloaded();
+        let result = connection.download_blocks(&blocks, endgame, &mut |connection| {
+            // ! mutual exclusion zone 2: queue up the next piece before this one runs dry
+            let held = queued
+                .iter()
+                .map(|(piece_id, _, _)| *piece_id)
+                .chain([piece_id])
+                .collect::<Vec<_>>();
+            let piece = find_next_piece(&corkboard, connection, &held, log)?;
+            queued.push_back(piece.clone());
+            Some((piece.1, piece.2))
+        });
         let duration = SystemTime::now()
             .duration_since(start_time)
             .unwrap()
             .as_millis() as usize;
 
+        // credit the peer for the data it sent, for the choker to reciprocate
+        if let Ok(mut board) = corkboard.write() {
+            let downloaded = connection.downloaded() - downloaded;
+            board
+                .downloaded
+                .fetch_add(downloaded, atomic::Ordering::Relaxed);
+            if let Some(peer) = board.peers.get_mut(connection.address()) {
+                peer.downloaded += downloaded;
+            }
+        }
+
         // ! mutual exclusion zone 3: finalize & store the downloaded piece
-        if matches!(
-            finalize_download(
-                &corkboard,
-                result,
-                duration,
-                piece_id,
-                &connection,
-                log,
-                &config
-            )?,
-            LoopAction::Continue
-        ) {
+        let action = finalize_download(
+            &corkboard,
+            result,
+            duration,
+            piece_id,
+            &blocks,
+            &connection,
+            &meta_info,
+            log,
+        );
+        if !matches!(action, Ok(LoopAction::Pass)) {
+            abandon_pieces(&corkboard, &mut queued, connection.address());
+        }
+        if let LoopAction::Continue = action? {
             continue;
         }
 
+        // let the peer know about the new piece
+        if blocks.is_complete() {
+            if let Err(err) = connection.have(piece_id as u32) {
+                log(format!("Failed to announce piece {piece_id}: {err}"));
+            }
+        }
+
         active_connection = Some(connection);
         uses += 1;
     }
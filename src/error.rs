@@ -2,6 +2,7 @@ use std::array::TryFromSliceError;
 use std::error::Error;
 use std::fmt::Display;
 use std::num::{ParseIntError, TryFromIntError};
+use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 use std::sync::PoisonError;
 
@@ -64,6 +65,7 @@ make_errs!(
     FromHexError,
     multihash::Error,
     FromUtf8Error,
+    Utf8Error,
     ParseIntError,
     TryFromIntError
 );
@@ -75,3 +77,15 @@ impl<T> From<PoisonError<T>> for BitTorrentError {
 }
 
 impl Error for BitTorrentError {}
+
+impl serde::ser::Error for BitTorrentError {
+    fn custom<T: Display>(msg: T) -> Self {
+        BitTorrentError::new(msg.to_string())
+    }
+}
+
+impl serde::de::Error for BitTorrentError {
+    fn custom<T: Display>(msg: T) -> Self {
+        BitTorrentError::new(msg.to_string())
+    }
+}
@@ -1,7 +1,9 @@
 use std::{
+    collections::HashMap,
     fs::{self, create_dir_all},
-    io::Read,
-    path::PathBuf,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
 };
 
 use crate::{
@@ -10,60 +12,137 @@ use crate::{
     bytes::{Bytes, PullBytes},
     dict,
     error::BitTorrentError,
-    util::sha1_hash,
+    util::{sha1_hash, sha256_hash},
 };
 use anyhow::Context;
+use clap::ValueEnum;
+
+pub mod builder;
+pub mod merkle;
+pub mod verify;
 
 #[derive(Debug, Clone)]
 pub struct MetaInfo {
-    pub announce_list: Vec<String>,
+    /// tracker for clients that don't support announce lists
+    pub announce: Option<String>,
+    /// tiers of trackers (BEP 12), tried in order. Clients supporting them ignore `announce`.
+    pub announce_list: Vec<Vec<String>>,
     pub info: Info,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// seconds since the unix epoch
+    pub creation_date: Option<Number>,
+    /// web seeds (BEP 19), HTTP urls serving the torrent's files
+    pub url_list: Vec<String>,
+    /// v2 hashes of the pieces of every file longer than a piece, by the file's pieces root
+    pub piece_layers: HashMap<[u8; 32], Vec<[u8; 32]>>,
+    /// the info dict exactly as it was read, which the info hash is taken over. Must be cleared
+    /// if `info` is changed.
+    pub info_bytes: Option<Bytes>,
+    /// keys that aren't modelled, kept to be written back unchanged
+    pub extra: HashMap<Bytes, BencodedValue>,
 }
 
 impl From<MetaInfo> for BencodedValue {
     fn from(val: MetaInfo) -> Self {
-        let (announce, announce_list): (Option<&String>, Option<&[String]>) = val
-            .announce_list
-            .split_first()
-            .map(|(x, y)| (Some(x), Some(y)))
-            .unwrap_or_default();
+        let announce_list = (!val.announce_list.is_empty()).then(|| {
+            val.announce_list
+                .into_iter()
+                .map(|tier| tier.into_iter().map(Bytes::from).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        });
         dict! {
-            b"announce" => announce
-                .cloned()
-                .map(Bytes::from),
-            b"announce-list" => announce_list
-                .map(|v| v
-                    .into_iter()
-                    .map(|s| BencodedValue::List(vec![BencodedValue::Bytes(Bytes::from(s.clone()))])).collect::<Vec<_>>()),
+            b"announce" => val.announce.map(Bytes::from),
+            b"announce-list" => announce_list,
+            b"comment" => val.comment.map(Bytes::from),
+            b"created by" => val.created_by.map(Bytes::from),
+            b"creation date" => val.creation_date,
             b"info" => val.info,
+            // a single web seed is usually written as a plain string
+            b"url-list" => match &val.url_list[..] {
+                [] => BencodedValue::None,
+                [url] => Bytes::from(url.clone()).into(),
+                urls => urls.iter().cloned().map(Bytes::from).collect::<Vec<_>>().into(),
+            },
+            b"piece layers" => (!val.piece_layers.is_empty()).then(|| {
+                val.piece_layers
+                    .into_iter()
+                    .map(|(root, layer)| (Bytes(root.to_vec()), Bytes(layer.concat())))
+                    .collect::<HashMap<_, _>>()
+            }),
         }
+        .with_extra(val.extra)
     }
 }
 
 impl From<BencodedValue> for Result<MetaInfo, BitTorrentError> {
     fn from(value: BencodedValue) -> Self {
         if let BencodedValue::Dict(mut meta_info) = value {
+            let info: Info = meta_info
+                .pull(b"info")
+                .ok_or(bterror!("Missing info"))
+                .and_then(<Result<_, _>>::from)?;
+            let piece_layers = meta_info
+                .pull(b"piece layers")
+                .and_then(BencodedValue::into_dict)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(root, layer)| Some((root, layer.into_bytes()?)))
+                .map(|(root, layer)| {
+                    let root: [u8; 32] = root[..].try_into()?;
+                    let layer = layer
+                        .chunks(32)
+                        .map(<[u8; 32]>::try_from)
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if merkle::layer_root(&layer, info.piece_length) != root {
+                        return Err(bterror!("Piece layer does not match its pieces root"));
+                    }
+                    Ok((root, layer))
+                })
+                .collect::<Result<_, BitTorrentError>>()?;
             Ok(MetaInfo {
-                announce_list: meta_info
+                announce: meta_info
                     .pull(b"announce")
                     .and_then(BencodedValue::into_bytes)
+                    .map(Bytes::into_string),
+                announce_list: meta_info
+                    .pull(b"announce-list")
+                    .and_then(BencodedValue::into_list)
+                    .unwrap_or_default()
                     .into_iter()
-                    .chain(
-                        meta_info
-                            .pull(b"announce-list")
-                            .and_then(BencodedValue::into_list)
-                            .unwrap_or_default()
-                            .into_iter()
-                            .filter_map(BencodedValue::into_list)
-                            .flatten()
-                            .filter_map(BencodedValue::into_bytes),
-                    )
-                    .map(Bytes::into_string)
+                    .filter_map(BencodedValue::into_list)
+                    .map(|tier| {
+                        tier.into_iter()
+                            .filter_map(BencodedValue::into_bytes)
+                            .map(Bytes::into_string)
+                            .collect::<Vec<_>>()
+                    })
+                    .filter(|tier| !tier.is_empty())
                     .collect(),
-                info: meta_info
-                    .pull(b"info")
-                    .ok_or(bterror!("Missing info"))
-                    .and_then(<Result<_, _>>::from)?,
+                info,
+                comment: meta_info
+                    .pull(b"comment")
+                    .and_then(BencodedValue::into_bytes)
+                    .map(Bytes::into_string),
+                created_by: meta_info
+                    .pull(b"created by")
+                    .and_then(BencodedValue::into_bytes)
+                    .map(Bytes::into_string),
+                creation_date: meta_info
+                    .pull(b"creation date")
+                    .and_then(BencodedValue::into_int),
+                url_list: match meta_info.pull(b"url-list") {
+                    Some(BencodedValue::Bytes(url)) => vec![url.into_string()],
+                    Some(BencodedValue::List(urls)) => urls
+                        .into_iter()
+                        .filter_map(BencodedValue::into_bytes)
+                        .map(Bytes::into_string)
+                        .collect(),
+                    _ => Vec::new(),
+                },
+                piece_layers,
+                info_bytes: None,
+                extra: meta_info,
             })
         } else {
             Err(bterror!("Invalid meta info"))
@@ -75,89 +154,215 @@ impl From<BencodedValue> for Result<MetaInfo, BitTorrentError> {
 pub struct Info {
     pub name: String,
     pub piece_length: usize,
+    /// SHA1 hashes of the v1 pieces, empty for v2-only torrents
     pub pieces: Vec<[u8; 20]>,
+    /// v1 file list, derived from the file tree for v2-only torrents
     pub file_info: FileInfo,
+    /// only use the peers handed out by the torrent's trackers
+    pub private: bool,
+    /// `meta version` of v2 and hybrid torrents
+    pub meta_version: Option<Number>,
+    /// files of the v2 `file tree`, in order, empty for v1-only torrents
+    pub file_tree: Vec<TreeFile>,
+    /// keys that aren't modelled, kept to be written back unchanged
+    pub extra: HashMap<Bytes, BencodedValue>,
 }
 
 impl From<Info> for BencodedValue {
     fn from(val: Info) -> Self {
-        match val.file_info {
-            FileInfo::Length(length) => dict! {
-                b"name" => Bytes::from(val.name),
-                b"pieces" => Bytes(val.pieces.into_iter().flatten().collect()),
-                b"piece length" => val.piece_length as Number,
-                b"length" => length as Number,
-            },
-            FileInfo::Files(files) => dict! {
-                b"name" => Bytes::from(val.name),
-                b"pieces" => Bytes(val.pieces.into_iter().flatten().collect()),
-                b"piece length" => val.piece_length as Number,
-                b"files" => files.into_iter().map(|file| dict! {
-                    b"length" => file.length as Number,
-                    b"path" => file.path.into_iter().map(Bytes::from).collect::<Vec<_>>(),
-                }).collect::<Vec<_>>(),
-            },
+        // the files of v2-only torrents are only listed in the file tree
+        let v1 = !val.pieces.is_empty() || val.file_tree.is_empty();
+        let (length, files) = match val.file_info {
+            _ if !v1 => (None, None),
+            FileInfo::Length(length) => (Some(length as Number), None),
+            FileInfo::Files(files) => (
+                None,
+                Some(
+                    files
+                        .into_iter()
+                        .map(|file| {
+                            dict! {
+                                b"length" => file.length as Number,
+                                b"path" => file.path.into_iter().map(Bytes::from).collect::<Vec<_>>(),
+                            }
+                            .with_extra(file.extra)
+                        })
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+        };
+        dict! {
+            b"name" => Bytes::from(val.name),
+            b"pieces" => v1.then(|| Bytes(val.pieces.into_iter().flatten().collect())),
+            b"piece length" => val.piece_length as Number,
+            b"private" => val.private.then_some(1 as Number),
+            b"length" => length,
+            b"files" => files,
+            b"meta version" => val.meta_version,
+            b"file tree" => (!val.file_tree.is_empty()).then(|| encode_file_tree(val.file_tree)),
         }
+        .with_extra(val.extra)
     }
 }
 
 impl From<BencodedValue> for Result<Info, BitTorrentError> {
     fn from(value: BencodedValue) -> Self {
-        if let BencodedValue::Dict(mut info) = value {
-            Ok(Info {
-                name: info
-                    .pull(b"name")
-                    .and_then(BencodedValue::into_bytes)
-                    .map(Bytes::into_string)
-                    .ok_or(bterror!("Missing name"))?,
-                piece_length: info
-                    .pull(b"piece length")
-                    .and_then(BencodedValue::into_int)
-                    .ok_or(bterror!("Missing piece length"))?
-                    .try_into()?,
-                pieces: info
-                    .pull(b"pieces")
-                    .and_then(BencodedValue::into_bytes)
-                    .map(|value| value.chunks(20).filter_map(|x| x.try_into().ok()).collect())
-                    .ok_or(bterror!("Missing pieces"))?,
-                file_info: match info.pull(b"length").and_then(BencodedValue::into_int) {
-                    Some(length) => FileInfo::Length(length as usize),
-                    None => FileInfo::Files(
-                        info.pull(b"files")
-                            .and_then(BencodedValue::into_list)
-                            .map(|files| {
-                                files
-                                    .into_iter()
-                                    .filter_map(BencodedValue::into_dict)
-                                    .map(|mut file| {
-                                        Ok::<_, BitTorrentError>(File {
-                                            length: file
-                                                .pull(b"length")
-                                                .and_then(BencodedValue::into_int)
-                                                .ok_or(bterror!("Missing length"))?
-                                                as usize,
-                                            path: file
-                                                .pull(b"path")
-                                                .and_then(BencodedValue::into_list)
-                                                .map(|path| {
-                                                    path.into_iter()
-                                                        .filter_map(BencodedValue::into_bytes)
-                                                        .map(Bytes::into_string)
-                                                        .collect()
-                                                })
-                                                .ok_or(bterror!("Missing path"))?,
-                                        })
+        let BencodedValue::Dict(mut info) = value else {
+            return Err(bterror!("Invalid info"));
+        };
+        let name = info
+            .pull(b"name")
+            .and_then(BencodedValue::into_bytes)
+            .map(Bytes::into_string)
+            .ok_or(bterror!("Missing name"))?;
+        let piece_length: usize = info
+            .pull(b"piece length")
+            .and_then(BencodedValue::into_int)
+            .ok_or(bterror!("Missing piece length"))?
+            .try_into()?;
+        let mut file_tree = Vec::new();
+        if let Some(tree) = info.pull(b"file tree").and_then(BencodedValue::into_dict) {
+            flatten_file_tree(tree, &[], &mut file_tree)?;
+        }
+        // the merkle trees of v2 files are built over blocks, a power of two of which make a piece
+        if !file_tree.is_empty()
+            && (piece_length < merkle::MERKLE_BLOCK_SIZE || !piece_length.is_power_of_two())
+        {
+            return Err(bterror!("Invalid v2 piece length: {piece_length}"));
+        }
+        let pieces = match info.pull(b"pieces").and_then(BencodedValue::into_bytes) {
+            Some(pieces) => pieces
+                .chunks(20)
+                .filter_map(|x| x.try_into().ok())
+                .collect(),
+            None if !file_tree.is_empty() => Vec::new(),
+            None => return Err(bterror!("Missing pieces")),
+        };
+        let file_info = match info.pull(b"length").and_then(BencodedValue::into_int) {
+            Some(length) => FileInfo::Length(length as usize),
+            None => match info.pull(b"files").and_then(BencodedValue::into_list) {
+                Some(files) => FileInfo::Files(
+                    files
+                        .into_iter()
+                        .filter_map(BencodedValue::into_dict)
+                        .map(|mut file| {
+                            Ok::<_, BitTorrentError>(File {
+                                length: file
+                                    .pull(b"length")
+                                    .and_then(BencodedValue::into_int)
+                                    .ok_or(bterror!("Missing length"))?
+                                    as usize,
+                                path: file
+                                    .pull(b"path")
+                                    .and_then(BencodedValue::into_list)
+                                    .map(|path| {
+                                        path.into_iter()
+                                            .filter_map(BencodedValue::into_bytes)
+                                            .map(Bytes::into_string)
+                                            .collect()
                                     })
-                                    .collect::<Result<_, _>>()
+                                    .ok_or(bterror!("Missing path"))?,
+                                extra: file,
                             })
-                            .ok_or(bterror!("Missing length or path field"))??,
-                    ),
+                        })
+                        .collect::<Result<_, _>>()?,
+                ),
+                // v2-only torrents lay out their files as given by the file tree, each file
+                // starting at a new piece
+                None => match &file_tree[..] {
+                    [file] if file.path == [name.clone()] => FileInfo::Length(file.length),
+                    [] => return Err(bterror!("Missing length or path field")),
+                    files => FileInfo::Files(aligned_files(files, piece_length)),
                 },
-            })
+            },
+        };
+        Ok(Info {
+            name,
+            piece_length,
+            pieces,
+            file_info,
+            private: info.pull(b"private").and_then(BencodedValue::into_int) == Some(1),
+            meta_version: info.pull(b"meta version").and_then(BencodedValue::into_int),
+            file_tree,
+            extra: info,
+        })
+    }
+}
+
+/// Flatten the v2 `file tree` node `tree` at `path` into `files`, in order.
+fn flatten_file_tree(
+    tree: HashMap<Bytes, BencodedValue>,
+    path: &[String],
+    files: &mut Vec<TreeFile>,
+) -> Result<(), BitTorrentError> {
+    let mut entries = tree.into_iter().collect::<Vec<_>>();
+    entries.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+    for (name, node) in entries {
+        let mut node = node.into_dict().ok_or(bterror!("Invalid file tree node"))?;
+        if name.is_empty() {
+            // a file's properties are kept under an empty key
+            files.push(TreeFile {
+                path: path.to_vec(),
+                length: node
+                    .pull(b"length")
+                    .and_then(BencodedValue::into_int)
+                    .ok_or(bterror!("Missing length"))? as usize,
+                pieces_root: node
+                    .pull(b"pieces root")
+                    .and_then(BencodedValue::into_bytes)
+                    .map(|root| root[..].try_into())
+                    .transpose()?,
+            });
         } else {
-            Err(bterror!("Invalid info"))
+            let mut path = path.to_vec();
+            path.push(name.into_string());
+            flatten_file_tree(node, &path, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Files of a v2-only torrent in the order of its file tree, with padding files in between
+/// that align every file to a piece boundary, like the files of a hybrid torrent.
+fn aligned_files(files: &[TreeFile], piece_length: usize) -> Vec<File> {
+    let mut aligned = Vec::new();
+    for (file_id, file) in files.iter().enumerate() {
+        aligned.push(File {
+            length: file.length,
+            path: file.path.clone(),
+            extra: HashMap::new(),
+        });
+        let padding = file.length.next_multiple_of(piece_length) - file.length;
+        if padding > 0 && file_id + 1 < files.len() {
+            aligned.push(File::padding(padding));
         }
     }
+    aligned
+}
+
+/// Nest the files of a v2 torrent back into a `file tree`.
+fn encode_file_tree(files: Vec<TreeFile>) -> BencodedValue {
+    let mut tree = HashMap::new();
+    for file in files {
+        let mut node = &mut tree;
+        for part in file.path {
+            let entry = node
+                .entry(Bytes::from(part))
+                .or_insert_with(|| BencodedValue::Dict(HashMap::new()));
+            let BencodedValue::Dict(child) = entry else {
+                unreachable!()
+            };
+            node = child;
+        }
+        node.insert(
+            Bytes(Vec::new()),
+            dict! {
+                b"length" => file.length as Number,
+                b"pieces root" => file.pieces_root.map(|root| Bytes(root.to_vec())),
+            },
+        );
+    }
+    BencodedValue::Dict(tree)
 }
 
 #[derive(Debug, Clone)]
@@ -170,20 +375,338 @@ pub enum FileInfo {
 pub struct File {
     pub length: usize,
     pub path: Vec<String>,
+    /// keys that aren't modelled, such as `attr` or `md5sum`
+    pub extra: HashMap<Bytes, BencodedValue>,
+}
+
+impl File {
+    /// Padding file of `length` bytes, named as in BEP 47.
+    pub fn padding(length: usize) -> Self {
+        Self {
+            length,
+            path: vec![".pad".to_string(), length.to_string()],
+            extra: HashMap::from([(
+                Bytes::from(&b"attr"[..]),
+                BencodedValue::Bytes(Bytes::from(&b"p"[..])),
+            )]),
+        }
+    }
+
+    /// Check if the file is padding inserted to align the next file to a piece boundary, as in
+    /// hybrid torrents. Padding files hold zeros and are not saved.
+    pub fn is_padding(&self) -> bool {
+        self.extra
+            .get(&Bytes::from(&b"attr"[..]))
+            .and_then(BencodedValue::as_bytes)
+            .is_some_and(|attr| attr.contains(&b'p'))
+    }
+}
+
+/// File of a v2 torrent's `file tree`
+#[derive(Debug, Clone)]
+pub struct TreeFile {
+    pub path: Vec<String>,
+    pub length: usize,
+    /// root of the merkle tree over the file's blocks, absent for empty files
+    pub pieces_root: Option<[u8; 32]>,
+}
+
+/// Position of a piece of a v2 or hybrid torrent in the merkle tree of the file it holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceSubtree {
+    pub pieces_root: [u8; 32],
+    /// index of the piece's first block among the blocks of the file
+    pub first_block: usize,
+    /// number of blocks the piece spans in the tree, blocks past the end of the file included
+    pub width: usize,
+    /// hash of the piece, the root of the subtree over its blocks, if the piece layer is known
+    pub root: Option<[u8; 32]>,
+    /// bytes of the file held by the piece
+    pub length: usize,
+}
+
+/// Truncate a v2 info hash to the 20 bytes used on the wire.
+pub fn truncate_hash(hash: &[u8; 32]) -> [u8; 20] {
+    hash[..20].try_into().unwrap()
+}
+
+/// How eagerly a file of the torrent should be downloaded, if at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, ValueEnum)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    /// Look up the priority of file `file_id` in `priorities`, with unlisted files being normal priority.
+    pub fn of(priorities: &[FilePriority], file_id: usize) -> FilePriority {
+        priorities.get(file_id).copied().unwrap_or_default()
+    }
 }
 
 impl MetaInfo {
     /// Read the metainfo file into a `MetaInfo` result.
     pub fn from_file(filename: &str) -> Result<Self, BitTorrentError> {
         let content = fs::read(filename).with_context(|| "Error reading file")?;
-        let decoded_value = BencodedValue::ingest(&mut &content[..])?;
-        let meta_info = <Result<_, _>>::from(decoded_value)?;
+        Self::from_bytes(&content)
+    }
+
+    /// Decode a metainfo file, keeping the info dict's original bytes.
+    pub fn from_bytes(content: &[u8]) -> Result<Self, BitTorrentError> {
+        let (decoded_value, mut spans) = BencodedValue::ingest_with_spans(&mut &content[..])?;
+        let mut meta_info: MetaInfo = <Result<_, _>>::from(decoded_value)?;
+        meta_info.info_bytes = spans.pull(b"info").map(|span| Bytes::from(&content[span]));
         Ok(meta_info)
     }
 
-    /// Compute the SHA1 hash of the info dictionary.
+    /// Encode the metainfo file. The info dict is written exactly as it was read if its
+    /// original bytes are known, so only changes to the other keys are saved and the info hash
+    /// stays the same.
+    pub fn encode(mut self) -> Result<Vec<u8>, BitTorrentError> {
+        let Some(info_bytes) = self.info_bytes.take() else {
+            return BencodedValue::from(self).encode();
+        };
+        let BencodedValue::Dict(mut meta_info) = BencodedValue::from(self) else {
+            return Err(bterror!("Invalid meta info"));
+        };
+        meta_info.pull(b"info");
+        // dict keys are encoded in order, so the info dict goes between the keys sorting
+        // before and after it
+        let (before, after): (HashMap<_, _>, HashMap<_, _>) = meta_info
+            .into_iter()
+            .partition(|(key, _)| key[..] < b"info"[..]);
+        let before = BencodedValue::Dict(before).encode()?;
+        let after = BencodedValue::Dict(after).encode()?;
+        Ok([
+            &before[..before.len() - 1],
+            b"4:info",
+            &info_bytes,
+            &after[1..],
+        ]
+        .concat())
+    }
+
+    /// Tiers of trackers to announce to, in order: the announce list, or `announce` on its own
+    /// if there is none.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        if self.announce_list.is_empty() {
+            self.announce.iter().map(|url| vec![url.clone()]).collect()
+        } else {
+            self.announce_list.clone()
+        }
+    }
+
+    /// Make `url` the tracker written as `announce`, and the first one of the first tier.
+    pub fn set_announce(&mut self, url: impl Into<String>) {
+        let url = url.into();
+        if !self.announce_list.is_empty() {
+            self.remove_tracker(&url);
+            match self.announce_list.first_mut() {
+                Some(tier) => tier.insert(0, url.clone()),
+                None => self.announce_list.push(vec![url.clone()]),
+            }
+        }
+        self.announce = Some(url);
+    }
+
+    /// Add a tracker to the end of tier `tier`, or to a new tier after the others if there is no
+    /// such tier, unless it's already listed.
+    pub fn add_tracker(&mut self, url: impl Into<String>, tier: usize) {
+        let url = url.into();
+        if self.tiers().iter().flatten().any(|tracker| *tracker == url) {
+            return;
+        }
+        if self.announce.is_none() && self.announce_list.is_empty() {
+            self.announce = Some(url);
+            return;
+        }
+        // a lone `announce` becomes the first tier of the new announce list
+        self.announce_list = self.tiers();
+        match self.announce_list.get_mut(tier) {
+            Some(tier) => tier.push(url),
+            None => self.announce_list.push(vec![url]),
+        }
+    }
+
+    /// Remove a tracker from every tier, dropping tiers left empty, and returning whether it was
+    /// listed. If it was `announce`, the first remaining tracker takes its place.
+    pub fn remove_tracker(&mut self, url: &str) -> bool {
+        let count = self.announce_list.iter().map(Vec::len).sum::<usize>();
+        for tier in &mut self.announce_list {
+            tier.retain(|tracker| tracker != url);
+        }
+        self.announce_list.retain(|tier| !tier.is_empty());
+        let mut removed = self.announce_list.iter().map(Vec::len).sum::<usize>() != count;
+        if self.announce.as_deref() == Some(url) {
+            self.announce = self.announce_list.first().map(|tier| tier[0].clone());
+            removed = true;
+        }
+        removed
+    }
+
+    /// Add a web seed after the others, unless it's already listed.
+    pub fn add_web_seed(&mut self, url: impl Into<String>) {
+        let url = url.into();
+        if !self.url_list.contains(&url) {
+            self.url_list.push(url);
+        }
+    }
+
+    /// Remove a web seed, returning whether it was listed.
+    pub fn remove_web_seed(&mut self, url: &str) -> bool {
+        let count = self.url_list.len();
+        self.url_list.retain(|web_seed| web_seed != url);
+        self.url_list.len() != count
+    }
+
+    /// Compute the SHA1 hash of the info dictionary, as it was originally encoded if known.
     pub fn info_hash(&self) -> Result<[u8; 20], BitTorrentError> {
-        Ok(sha1_hash(&BencodedValue::encode(self.info.clone().into())?))
+        match &self.info_bytes {
+            Some(info_bytes) => Ok(sha1_hash(info_bytes)),
+            None => Ok(sha1_hash(&BencodedValue::encode(self.info.clone().into())?)),
+        }
+    }
+
+    /// Compute the SHA256 hash of the info dictionary of a v2 or hybrid torrent.
+    pub fn info_hash_v2(&self) -> Result<Option<[u8; 32]>, BitTorrentError> {
+        if self.info.meta_version != Some(2) {
+            return Ok(None);
+        }
+        match &self.info_bytes {
+            Some(info_bytes) => Ok(Some(sha256_hash(info_bytes))),
+            None => Ok(Some(sha256_hash(&BencodedValue::encode(
+                self.info.clone().into(),
+            )?))),
+        }
+    }
+
+    /// Check if the torrent has v1 pieces, i.e. is not v2-only.
+    pub fn is_v1(&self) -> bool {
+        !self.info.pieces.is_empty() || self.info.file_tree.is_empty()
+    }
+
+    /// Info hashes of the swarms the torrent is shared in, as used in handshakes and announces:
+    /// the v1 hash and the v2 hash truncated to 20 bytes, both for hybrid torrents.
+    pub fn swarm_hashes(&self) -> Result<Vec<[u8; 20]>, BitTorrentError> {
+        let v1 = self.is_v1().then(|| self.info_hash()).transpose()?;
+        let v2 = self.info_hash_v2()?.map(|hash| truncate_hash(&hash));
+        Ok(v1.into_iter().chain(v2).collect())
+    }
+
+    /// Number of pieces of the torrent. v2-only torrents have no SHA1 hashes to count the
+    /// pieces by, and take a piece for every piece length of data, padding included.
+    pub fn piece_count(&self) -> usize {
+        match self.is_v1() {
+            true => self.info.pieces.len(),
+            false => self.length().div_ceil(self.info.piece_length),
+        }
+    }
+
+    /// Bytes of data that peers serve for piece `piece_id`. The padding after the end of a file
+    /// of a v2-only torrent is not part of any piece, so the last piece of a file is short.
+    pub fn piece_size(&self, piece_id: usize) -> usize {
+        let piece_length = self.info.piece_length;
+        let offset = piece_id * piece_length;
+        match self.is_v1() {
+            true => self.length().saturating_sub(offset).min(piece_length),
+            false => self
+                .piece_subtree(piece_id)
+                .map_or(0, |subtree| subtree.length),
+        }
+    }
+
+    /// Check the data of piece `piece_id` against its SHA1 hash, and for hybrid torrents also
+    /// against the SHA256 hash of the v2 piece it holds, if the piece layers are known. Pieces
+    /// of v2-only torrents are only checked against the latter, and fail while it is unknown.
+    pub fn check_piece(&self, piece_id: usize, data: &[u8]) -> bool {
+        match self.is_v1() {
+            true => {
+                self.info
+                    .pieces
+                    .get(piece_id)
+                    .is_some_and(|hash| sha1_hash(data) == *hash)
+                    && self.check_piece_v2(piece_id, data).unwrap_or(true)
+            }
+            false => self.check_piece_v2(piece_id, data) == Some(true),
+        }
+    }
+
+    /// Check the data of piece `piece_id` of a v2 or hybrid torrent against the merkle tree of
+    /// the file it holds, if the hash of the piece is known.
+    fn check_piece_v2(&self, piece_id: usize, data: &[u8]) -> Option<bool> {
+        let subtree = self.piece_subtree(piece_id)?;
+        let data = &data[..data.len().min(subtree.length)];
+        Some(subtree.root? == merkle::blocks_root(data, subtree.width))
+    }
+
+    /// Position of piece `piece_id` of a v2 or hybrid torrent in the merkle tree of the file it
+    /// holds. Files are aligned to piece boundaries by padding files, so a piece holds data of
+    /// a single file, followed by padding at the end of the file.
+    pub fn piece_subtree(&self, piece_id: usize) -> Option<PieceSubtree> {
+        let piece_length = self.info.piece_length;
+        let offset = piece_id * piece_length;
+        let (range, path) = self
+            .file_ranges()
+            .into_iter()
+            .zip(self.file_paths())
+            .find(|(range, _)| range.contains(&offset))?;
+        let file = self.info.file_tree.iter().find(|file| file.path == path)?;
+        let pieces_root = file.pieces_root?;
+        let index = (offset - range.start) / piece_length;
+        let blocks_per_piece = piece_length / merkle::MERKLE_BLOCK_SIZE;
+        let (width, root) = match file.length <= piece_length {
+            // a file no longer than a piece has no piece layer, its pieces root covers its blocks
+            true => (
+                file.length
+                    .div_ceil(merkle::MERKLE_BLOCK_SIZE)
+                    .next_power_of_two(),
+                Some(pieces_root),
+            ),
+            false => (
+                blocks_per_piece,
+                self.piece_layers
+                    .get(&pieces_root)
+                    .and_then(|layer| layer.get(index))
+                    .copied(),
+            ),
+        };
+        Some(PieceSubtree {
+            pieces_root,
+            first_block: index * blocks_per_piece,
+            width,
+            root,
+            length: (range.end - offset).min(piece_length),
+        })
+    }
+
+    /// Piece of a v2 or hybrid torrent holding block `block` of the file with pieces root
+    /// `pieces_root`.
+    pub fn block_piece(&self, pieces_root: &[u8; 32], block: usize) -> Option<usize> {
+        let file = self
+            .info
+            .file_tree
+            .iter()
+            .find(|file| file.pieces_root.as_ref() == Some(pieces_root))?;
+        let (range, _) = self
+            .file_ranges()
+            .into_iter()
+            .zip(self.file_paths())
+            .find(|(_, path)| *path == file.path)?;
+        let offset = range.start + block * merkle::MERKLE_BLOCK_SIZE;
+        range
+            .contains(&offset)
+            .then(|| offset / self.info.piece_length)
+    }
+
+    /// Paths of the files of the torrent, in the order of `file_ranges`.
+    fn file_paths(&self) -> Vec<Vec<String>> {
+        match &self.info.file_info {
+            FileInfo::Length(_) => vec![vec![self.info.name.clone()]],
+            FileInfo::Files(files) => files.iter().map(|file| file.path.clone()).collect(),
+        }
     }
 
     /// Compute total torrent length.
@@ -194,16 +717,75 @@ impl MetaInfo {
         }
     }
 
-    /// Save the torrent data to the given path.
-    pub fn save_to_path(&self, path: &PathBuf, mut data: impl Read) -> std::io::Result<()> {
+    /// Byte range of every file within the torrent's contiguous data, in order.
+    pub fn file_ranges(&self) -> Vec<Range<usize>> {
+        match &self.info.file_info {
+            FileInfo::Length(length) => std::iter::once(0..*length).collect(),
+            FileInfo::Files(files) => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|file| {
+                        offset += file.length;
+                        offset - file.length..offset
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Range of pieces holding the data of every file, in order. Pieces on the boundary between
+    /// two files are part of both ranges.
+    pub fn file_piece_ranges(&self) -> Vec<Range<usize>> {
+        let piece_length = self.info.piece_length;
+        self.file_ranges()
+            .into_iter()
+            .map(|range| match range.is_empty() {
+                true => range.start / piece_length..range.start / piece_length,
+                false => range.start / piece_length..range.end.div_ceil(piece_length),
+            })
+            .collect()
+    }
+
+    /// Priority of every piece given the priorities of the files: the highest priority of any
+    /// file the piece holds data of.
+    pub fn piece_priorities(&self, file_priorities: &[FilePriority]) -> Vec<FilePriority> {
+        let piece_count = self.piece_count();
+        let mut priorities = vec![FilePriority::Skip; piece_count];
+        for (file_id, pieces) in self.file_piece_ranges().into_iter().enumerate() {
+            let file_priority = FilePriority::of(file_priorities, file_id);
+            let pieces = pieces.start.min(piece_count)..pieces.end.min(piece_count);
+            for priority in &mut priorities[pieces] {
+                *priority = file_priority.max(*priority);
+            }
+        }
+        priorities
+    }
+
+    /// Save the torrent data to the given path, leaving out files with a priority of
+    /// `FilePriority::Skip` in `file_priorities`.
+    pub fn save_to_path(
+        &self,
+        path: &Path,
+        mut data: impl Read + Seek,
+        file_priorities: &[FilePriority],
+    ) -> std::io::Result<()> {
         match &self.info.file_info {
             FileInfo::Length(_) => {
-                let mut file = fs::File::create(path.join(&self.info.name))?;
-                std::io::copy(&mut data, &mut file)?;
+                if FilePriority::of(file_priorities, 0) != FilePriority::Skip {
+                    let mut file = fs::File::create(path.join(&self.info.name))?;
+                    std::io::copy(&mut data, &mut file)?;
+                }
             }
             FileInfo::Files(files) => {
                 let base_path = path.join(&self.info.name);
-                for file_metadata in files {
+                for (file_id, file_metadata) in files.iter().enumerate() {
+                    if file_metadata.is_padding()
+                        || FilePriority::of(file_priorities, file_id) == FilePriority::Skip
+                    {
+                        data.seek(SeekFrom::Current(file_metadata.length as i64))?;
+                        continue;
+                    }
                     let mut file_path = base_path.clone();
                     for path_part in &file_metadata.path {
                         file_path.push(path_part);
@@ -220,3 +802,128 @@ impl MetaInfo {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// info dict with its keys out of order and a key that isn't modelled
+    const INFO: &[u8] =
+        b"d4:name1:f6:lengthi10e7:x-extrai7e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+
+    /// Torrent of `INFO` with a key that isn't modelled.
+    fn torrent() -> Vec<u8> {
+        [
+            &b"d8:announce17:http://t/announce7:comment2:hi4:info"[..],
+            INFO,
+            b"9:x-unknownli1ei2eee",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn info_hash_is_taken_over_the_original_info_bytes() {
+        let meta_info = MetaInfo::from_bytes(&torrent()).unwrap();
+        assert_eq!(meta_info.info_hash().unwrap(), sha1_hash(INFO));
+
+        // re-encoding the info dict would sort its keys and change the hash
+        let encoded = BencodedValue::from(meta_info.info.clone())
+            .encode()
+            .unwrap();
+        assert_ne!(encoded, INFO);
+    }
+
+    #[test]
+    fn encode_reproduces_the_original_bytes() {
+        let meta_info = MetaInfo::from_bytes(&torrent()).unwrap();
+        assert_eq!(meta_info.encode().unwrap(), torrent());
+    }
+
+    #[test]
+    fn encode_keeps_unknown_keys_and_the_info_dict_through_edits() {
+        let mut meta_info = MetaInfo::from_bytes(&torrent()).unwrap();
+        meta_info.comment = Some("edited".to_string());
+        let edited = MetaInfo::from_bytes(&meta_info.encode().unwrap()).unwrap();
+        assert_eq!(edited.comment.as_deref(), Some("edited"));
+        assert_eq!(edited.info_bytes.as_deref(), Some(INFO));
+        assert!(edited.extra.contains_key(&Bytes::from(&b"x-unknown"[..])));
+    }
+
+    /// Tiers of trackers as owned strings.
+    fn tiers(tiers: &[&[&str]]) -> Vec<Vec<String>> {
+        tiers
+            .iter()
+            .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn tracker_and_web_seed_edits_leave_the_info_dict_alone() {
+        let mut meta_info = MetaInfo::from_bytes(&torrent()).unwrap();
+        let info_hash = meta_info.info_hash().unwrap();
+        // save and reload the torrent, checking what must not change
+        let saved = |meta_info: &MetaInfo| {
+            let saved = MetaInfo::from_bytes(&meta_info.clone().encode().unwrap()).unwrap();
+            assert_eq!(saved.info_hash().unwrap(), info_hash);
+            assert_eq!(saved.info_bytes.as_deref(), Some(INFO));
+            assert_eq!(
+                saved.announce.as_ref(),
+                saved.tiers().first().and_then(|tier| tier.first())
+            );
+            saved
+        };
+
+        meta_info.add_tracker("http://u/announce", 0);
+        meta_info.add_tracker("http://v/announce", 5);
+        meta_info.add_tracker("http://u/announce", 1);
+        assert_eq!(
+            saved(&meta_info).tiers(),
+            tiers(&[
+                &["http://t/announce", "http://u/announce"],
+                &["http://v/announce"]
+            ])
+        );
+
+        meta_info.set_announce("http://v/announce");
+        let edited = saved(&meta_info);
+        assert_eq!(edited.announce.as_deref(), Some("http://v/announce"));
+        assert_eq!(
+            edited.tiers(),
+            tiers(&[&[
+                "http://v/announce",
+                "http://t/announce",
+                "http://u/announce"
+            ]])
+        );
+
+        assert!(meta_info.remove_tracker("http://v/announce"));
+        assert!(!meta_info.remove_tracker("http://v/announce"));
+        let edited = saved(&meta_info);
+        assert_eq!(edited.announce.as_deref(), Some("http://t/announce"));
+        assert_eq!(
+            edited.tiers(),
+            tiers(&[&["http://t/announce", "http://u/announce"]])
+        );
+
+        meta_info.add_web_seed("http://w/");
+        meta_info.add_web_seed("http://w/");
+        assert_eq!(saved(&meta_info).url_list, vec!["http://w/".to_string()]);
+        assert!(meta_info.remove_web_seed("http://w/"));
+        assert!(!meta_info.remove_web_seed("http://w/"));
+        assert!(saved(&meta_info).url_list.is_empty());
+    }
+
+    #[test]
+    fn tiers_fall_back_to_announce() {
+        let mut meta_info = MetaInfo::from_bytes(&torrent()).unwrap();
+        assert_eq!(meta_info.tiers(), tiers(&[&["http://t/announce"]]));
+
+        // clients supporting announce lists ignore `announce`
+        meta_info.announce_list = tiers(&[&["http://u/announce"], &["http://v/announce"]]);
+        assert_eq!(meta_info.tiers(), meta_info.announce_list);
+
+        meta_info.announce_list.clear();
+        meta_info.announce = None;
+        assert!(meta_info.tiers().is_empty());
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use rayon::prelude::*;
+
+use crate::{bencode::Number, bterror, error::BitTorrentError, util::sha1_hash};
+
+use super::{File, FileInfo, Info, MetaInfo};
+
+/// smallest piece length picked automatically (bytes)
+const MIN_PIECE_LENGTH: usize = 16384; // 16 KiB
+/// largest piece length picked automatically (bytes)
+const MAX_PIECE_LENGTH: usize = 16777216; // 16 MiB
+/// number of pieces the automatic piece length aims for
+const TARGET_PIECE_COUNT: usize = 1500;
+
+/// Builds the `MetaInfo` of a new torrent from a file or a directory tree
+#[derive(Debug, Clone)]
+pub struct MetaInfoBuilder {
+    path: PathBuf,
+    name: Option<String>,
+    piece_length: Option<usize>,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<Number>,
+    private: bool,
+}
+
+impl MetaInfoBuilder {
+    /// Start building a torrent of the file or directory at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            name: None,
+            piece_length: None,
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            private: false,
+        }
+    }
+
+    /// Name of the torrent, defaulting to the name of the file or directory.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Length of each piece, which must be a power of two. Picked from the size of the data
+    /// if not set.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Add a tier of trackers, which clients try in a random order, after any added before it.
+    pub fn announce_tier(mut self, urls: Vec<String>) -> Self {
+        if !urls.is_empty() {
+            self.announce_list.push(urls);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = Some(created_by.into());
+        self
+    }
+
+    /// Creation date, in seconds since the unix epoch.
+    pub fn creation_date(mut self, creation_date: Number) -> Self {
+        self.creation_date = Some(creation_date);
+        self
+    }
+
+    /// Mark the torrent as private, limiting it to the peers handed out by its trackers.
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Walk the file tree and hash its pieces in parallel.
+    pub fn build(self) -> Result<MetaInfo, BitTorrentError> {
+        let metadata = fs::metadata(&self.path)
+            .with_context(|| format!("Unable to read {}", self.path.display()))?;
+        let name = match self.name {
+            Some(name) => name,
+            None => path_component(&self.path)?,
+        };
+
+        // list the files making up the torrent's data, in order
+        let (file_info, paths) = if metadata.is_dir() {
+            let mut paths = Vec::new();
+            walk_directory(&self.path, &mut paths)?;
+            if paths.is_empty() {
+                return Err(bterror!("{} contains no files", self.path.display()));
+            }
+            let files = paths
+                .iter()
+                .map(|(path, length)| {
+                    Ok(File {
+                        length: *length,
+                        path: path
+                            .strip_prefix(&self.path)
+                            .context("File outside of the torrent's directory")?
+                            .iter()
+                            .map(|part| path_component(Path::new(part)))
+                            .collect::<Result<_, BitTorrentError>>()?,
+                        extra: HashMap::new(),
+                    })
+                })
+                .collect::<Result<Vec<_>, BitTorrentError>>()?;
+            (FileInfo::Files(files), paths)
+        } else {
+            let length = metadata.len() as usize;
+            (FileInfo::Length(length), vec![(self.path.clone(), length)])
+        };
+        let length = paths.iter().map(|(_, length)| length).sum::<usize>();
+
+        let piece_length = match self.piece_length {
+            Some(piece_length) if piece_length.is_power_of_two() => piece_length,
+            Some(piece_length) => {
+                return Err(bterror!(
+                    "Piece length {piece_length} is not a power of two"
+                ))
+            }
+            None => (length / TARGET_PIECE_COUNT)
+                .next_power_of_two()
+                .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH),
+        };
+
+        let pieces = (0..length.div_ceil(piece_length))
+            .into_par_iter()
+            .map(|piece_id| {
+                let offset = piece_id * piece_length;
+                let data = read_range(&paths, offset, piece_length.min(length - offset))?;
+                Ok(sha1_hash(&data))
+            })
+            .collect::<Result<Vec<_>, BitTorrentError>>()?;
+
+        // the first tracker doubles as `announce` for clients that don't support announce lists,
+        // which is all a torrent with a single tracker needs
+        let announce = self.announce_list.first().map(|tier| tier[0].clone());
+        let announce_list = match self.announce_list.concat().len() {
+            0 | 1 => Vec::new(),
+            _ => self.announce_list,
+        };
+        Ok(MetaInfo {
+            announce,
+            announce_list,
+            info: Info {
+                name,
+                piece_length,
+                pieces,
+                file_info,
+                private: self.private,
+                meta_version: None,
+                file_tree: Vec::new(),
+                extra: HashMap::new(),
+            },
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date,
+            url_list: Vec::new(),
+            piece_layers: HashMap::new(),
+            info_bytes: None,
+            extra: HashMap::new(),
+        })
+    }
+}
+
+/// Collect every file under `directory` with its length, ordered by path.
+fn walk_directory(
+    directory: &Path,
+    files: &mut Vec<(PathBuf, usize)>,
+) -> Result<(), BitTorrentError> {
+    let mut entries = fs::read_dir(directory)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_dir() {
+            walk_directory(&path, files)?;
+        } else {
+            files.push((path, metadata.len() as usize));
+        }
+    }
+    Ok(())
+}
+
+/// Read the byte range `offset..offset + length` of the data formed by joining `files`.
+fn read_range(
+    files: &[(PathBuf, usize)],
+    mut offset: usize,
+    mut length: usize,
+) -> Result<Vec<u8>, BitTorrentError> {
+    let mut data = Vec::with_capacity(length);
+    for (path, file_length) in files {
+        if length == 0 {
+            break;
+        }
+        if offset >= *file_length {
+            offset -= file_length;
+            continue;
+        }
+        let to_read = length.min(file_length - offset);
+        let mut file = fs::File::open(path)?;
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let start = data.len();
+        data.resize(start + to_read, 0);
+        file.read_exact(&mut data[start..])
+            .with_context(|| format!("{} changed while hashing", path.display()))?;
+        offset = 0;
+        length -= to_read;
+    }
+    Ok(data)
+}
+
+/// Final component of `path` as a string.
+fn path_component(path: &Path) -> Result<String, BitTorrentError> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| bterror!("{} is not a valid utf-8 file name", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    /// Data counting up from `seed`, wrapping around.
+    fn data(seed: u8, length: usize) -> Vec<u8> {
+        (0..length).map(|i| seed.wrapping_add(i as u8)).collect()
+    }
+
+    /// Paths of the files of a multi-file torrent.
+    fn paths(meta_info: &MetaInfo) -> Vec<Vec<String>> {
+        match &meta_info.info.file_info {
+            FileInfo::Length(_) => Vec::new(),
+            FileInfo::Files(files) => files.iter().map(|file| file.path.clone()).collect(),
+        }
+    }
+
+    /// Encode and decode `meta_info` again.
+    fn round_trip(meta_info: &MetaInfo) -> MetaInfo {
+        MetaInfo::from_bytes(&meta_info.clone().encode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn builds_a_single_file_torrent() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        let content = data(0, 40000);
+        fs::write(&path, &content).unwrap();
+
+        let meta_info = MetaInfoBuilder::new(&path)
+            .piece_length(16384)
+            .announce_tier(vec!["http://t/announce".to_string()])
+            .build()
+            .unwrap();
+        assert_eq!(meta_info.info.name, "file.bin");
+        assert!(matches!(meta_info.info.file_info, FileInfo::Length(40000)));
+        let hashes = content.chunks(16384).map(sha1_hash).collect::<Vec<_>>();
+        assert_eq!(meta_info.info.pieces, hashes);
+        assert_eq!(meta_info.announce.as_deref(), Some("http://t/announce"));
+        assert!(meta_info.announce_list.is_empty());
+
+        let decoded = round_trip(&meta_info);
+        assert_eq!(decoded.info.pieces, hashes);
+        assert_eq!(decoded.info_hash().unwrap(), meta_info.info_hash().unwrap());
+    }
+
+    #[test]
+    fn builds_a_multi_file_torrent_in_path_order() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("torrent");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("b"), data(1, 20000)).unwrap();
+        fs::write(root.join("a/x"), data(2, 5000)).unwrap();
+        fs::write(root.join("c"), data(3, 1)).unwrap();
+
+        let meta_info = MetaInfoBuilder::new(&root)
+            .name("renamed")
+            .piece_length(16384)
+            .build()
+            .unwrap();
+        assert_eq!(meta_info.info.name, "renamed");
+        let expected_paths = vec![
+            vec!["a".to_string(), "x".to_string()],
+            vec!["b".to_string()],
+            vec!["c".to_string()],
+        ];
+        assert_eq!(paths(&meta_info), expected_paths);
+
+        // pieces run across the files in order
+        let content = [data(2, 5000), data(1, 20000), data(3, 1)].concat();
+        let hashes = content.chunks(16384).map(sha1_hash).collect::<Vec<_>>();
+        assert_eq!(meta_info.info.pieces.len(), 2);
+        assert_eq!(meta_info.info.pieces, hashes);
+
+        let decoded = round_trip(&meta_info);
+        assert_eq!(paths(&decoded), expected_paths);
+        assert_eq!(decoded.info_hash().unwrap(), meta_info.info_hash().unwrap());
+    }
+
+    #[test]
+    fn rejects_piece_lengths_that_are_not_a_power_of_two() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("file.bin"), data(0, 10)).unwrap();
+        let builder = MetaInfoBuilder::new(dir.path().join("file.bin"));
+        assert!(builder.clone().piece_length(20000).build().is_err());
+        assert!(builder.build().is_ok());
+    }
+}
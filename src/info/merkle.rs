@@ -0,0 +1,224 @@
+use crate::util::sha256_hash;
+
+/// size of the blocks that form the leaves of a v2 file's merkle tree (bytes)
+pub const MERKLE_BLOCK_SIZE: usize = 16384;
+
+/// hash of a leaf past the end of a file
+pub const ZERO_HASH: [u8; 32] = [0; 32];
+
+/// Hash of the node with children `left` and `right`.
+fn parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut pair = [0; 64];
+    pair[..32].copy_from_slice(left);
+    pair[32..].copy_from_slice(right);
+    sha256_hash(&pair)
+}
+
+/// Every layer of the tree over `leaves` padded with `pad` up to `width` leaves, a power of
+/// two, from the leaves up to the root.
+pub fn tree(leaves: &[[u8; 32]], width: usize, pad: [u8; 32]) -> Vec<Vec<[u8; 32]>> {
+    let mut layers = vec![leaves.to_vec()];
+    let mut pad = pad;
+    let mut width = width.max(1);
+    while width > 1 {
+        let layer = layers.last().unwrap();
+        let next = layer
+            .chunks(2)
+            .map(|pair| parent(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        layers.push(next);
+        pad = parent(&pad, &pad);
+        width /= 2;
+    }
+    // the padding may cover the whole tree
+    if layers.last().unwrap().is_empty() {
+        layers.last_mut().unwrap().push(pad);
+    }
+    layers
+}
+
+/// Root of the tree over `leaves` padded with `pad` up to `width` leaves, a power of two.
+pub fn root(leaves: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    tree(leaves, width, pad).last().unwrap()[0]
+}
+
+/// Hashes of the blocks of `data`, the leaves of its merkle tree.
+pub fn block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(MERKLE_BLOCK_SIZE).map(sha256_hash).collect()
+}
+
+/// Root of the subtree over the blocks of `data`, padded with zero hashes up to `width` blocks:
+/// the hash of a piece in its file's piece layer, or the pieces root of a file no longer than
+/// a piece, which has no piece layer.
+pub fn blocks_root(data: &[u8], width: usize) -> [u8; 32] {
+    root(&block_hashes(data), width, ZERO_HASH)
+}
+
+/// Hash standing in for the pieces past the end of a file in its piece layer.
+pub fn piece_padding(piece_length: usize) -> [u8; 32] {
+    blocks_root(&[], piece_length / MERKLE_BLOCK_SIZE)
+}
+
+/// Pieces root of a file computed from its piece layer.
+pub fn layer_root(layer: &[[u8; 32]], piece_length: usize) -> [u8; 32] {
+    root(
+        layer,
+        layer.len().next_power_of_two(),
+        piece_padding(piece_length),
+    )
+}
+
+/// Hashes `index..index + length` of the piece layer `layer`, followed by up to `proof_layers`
+/// uncle hashes proving them against the pieces root, lowest first. This is the payload of a
+/// `hashes` message. `length` must be a power of two that `index` is a multiple of.
+pub fn layer_proof(
+    layer: &[[u8; 32]],
+    piece_length: usize,
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<[u8; 32]>> {
+    let width = layer.len().next_power_of_two();
+    if !length.is_power_of_two() || !index.is_multiple_of(length) || index + length > width {
+        return None;
+    }
+    let pad = piece_padding(piece_length);
+    let layers = tree(layer, width, pad);
+    let mut hashes = (index..index + length)
+        .map(|i| layer.get(i).copied().unwrap_or(pad))
+        .collect::<Vec<_>>();
+
+    // uncles of the subtree holding the requested hashes, up to the root
+    let mut pad = pad;
+    let mut level_pad = Vec::new();
+    for _ in &layers {
+        level_pad.push(pad);
+        pad = parent(&pad, &pad);
+    }
+    let mut node = index / length;
+    let uncles = (length.trailing_zeros() as usize..layers.len() - 1)
+        .map(|level| {
+            let sibling = node ^ 1;
+            node /= 2;
+            layers[level]
+                .get(sibling)
+                .copied()
+                .unwrap_or(level_pad[level])
+        })
+        .collect::<Vec<_>>();
+    hashes.extend(uncles.into_iter().take(proof_layers));
+    Some(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_pair(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        sha256_hash(&[left, right].concat())
+    }
+
+    /// Root reached by folding the uncles at the end of `proof` onto the subtree holding the
+    /// `length` hashes at its start, the way a peer receiving a `hashes` message checks it.
+    fn proven_root(proof: &[[u8; 32]], index: usize, length: usize) -> [u8; 32] {
+        let (hashes, uncles) = proof.split_at(length);
+        let mut subtree = tree(hashes, length, ZERO_HASH).last().unwrap()[0];
+        let mut node = index / length;
+        for uncle in uncles {
+            subtree = match node % 2 {
+                0 => hash_pair(subtree, *uncle),
+                _ => hash_pair(*uncle, subtree),
+            };
+            node /= 2;
+        }
+        subtree
+    }
+
+    #[test]
+    fn pads_roots_up_to_the_width() {
+        let leaves = [[1; 32], [2; 32], [3; 32]];
+        assert_eq!(root(&leaves[..1], 1, ZERO_HASH), [1; 32]);
+        assert_eq!(
+            root(&leaves, 4, ZERO_HASH),
+            hash_pair(hash_pair([1; 32], [2; 32]), hash_pair([3; 32], ZERO_HASH))
+        );
+        assert_eq!(
+            root(&[], 4, [9; 32]),
+            hash_pair(hash_pair([9; 32], [9; 32]), hash_pair([9; 32], [9; 32]))
+        );
+    }
+
+    #[test]
+    fn hashes_pieces_and_small_files() {
+        let data = (0..MERKLE_BLOCK_SIZE * 2 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let blocks = block_hashes(&data);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2], sha256_hash(&data[MERKLE_BLOCK_SIZE * 2..]));
+
+        assert_eq!(blocks_root(&data[..10], 1), sha256_hash(&data[..10]));
+        assert_eq!(
+            blocks_root(&data, 4),
+            hash_pair(
+                hash_pair(blocks[0], blocks[1]),
+                hash_pair(blocks[2], ZERO_HASH)
+            )
+        );
+        assert_eq!(blocks_root(&data, 8), root(&blocks, 8, ZERO_HASH));
+        assert_eq!(
+            piece_padding(MERKLE_BLOCK_SIZE * 2),
+            hash_pair(ZERO_HASH, ZERO_HASH)
+        );
+    }
+
+    #[test]
+    fn pads_piece_layers_with_empty_pieces() {
+        let piece_length = MERKLE_BLOCK_SIZE * 2;
+        let layer = [[1; 32], [2; 32], [3; 32]];
+        assert_eq!(
+            layer_root(&layer, piece_length),
+            hash_pair(
+                hash_pair([1; 32], [2; 32]),
+                hash_pair([3; 32], piece_padding(piece_length))
+            )
+        );
+    }
+
+    #[test]
+    fn proves_every_aligned_range_of_a_layer() {
+        let piece_length = MERKLE_BLOCK_SIZE * 4;
+        let layer = (1..=5).map(|i| [i; 32]).collect::<Vec<_>>();
+        let pieces_root = layer_root(&layer, piece_length);
+        for length in [1, 2, 4, 8] {
+            for index in (0..8).step_by(length) {
+                let proof = layer_proof(&layer, piece_length, index, length, usize::MAX).unwrap();
+                let depth = (8 / length).trailing_zeros() as usize;
+                assert_eq!(proof.len(), length + depth);
+                assert_eq!(proven_root(&proof, index, length), pieces_root);
+            }
+        }
+        // past the end of the layer, the proof covers padding pieces
+        let proof = layer_proof(&layer, piece_length, 6, 2, usize::MAX).unwrap();
+        assert_eq!(proof[..2], [piece_padding(piece_length); 2]);
+    }
+
+    #[test]
+    fn limits_proofs_to_the_requested_layers() {
+        let layer = (1..=5).map(|i| [i; 32]).collect::<Vec<_>>();
+        let full = layer_proof(&layer, MERKLE_BLOCK_SIZE, 0, 1, usize::MAX).unwrap();
+        let proof = layer_proof(&layer, MERKLE_BLOCK_SIZE, 0, 1, 2).unwrap();
+        assert_eq!(proof, full[..3]);
+        let proof = layer_proof(&layer, MERKLE_BLOCK_SIZE, 0, 1, 0).unwrap();
+        assert_eq!(proof, [[1; 32]]);
+    }
+
+    #[test]
+    fn rejects_unaligned_ranges() {
+        let layer = (1..=5).map(|i| [i; 32]).collect::<Vec<_>>();
+        assert!(layer_proof(&layer, MERKLE_BLOCK_SIZE, 0, 3, 0).is_none());
+        assert!(layer_proof(&layer, MERKLE_BLOCK_SIZE, 2, 4, 0).is_none());
+        assert!(layer_proof(&layer, MERKLE_BLOCK_SIZE, 8, 1, 0).is_none());
+        assert!(layer_proof(&layer, MERKLE_BLOCK_SIZE, 0, 16, 0).is_none());
+    }
+}
@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use anyhow::Context;
+use rayon::prelude::*;
+
+use crate::{error::BitTorrentError, storage::FileLayout};
+
+use super::MetaInfo;
+
+/// State of a piece or file's data on disk, from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DataState {
+    Complete,
+    /// some of the data could not be read, because a file is absent or too short
+    Missing,
+    /// the data is present but doesn't match the piece hashes
+    Corrupt,
+}
+
+/// Result of checking a torrent's data on disk
+#[derive(Debug, Clone)]
+pub struct Verification {
+    pub pieces: Vec<DataState>,
+    pub files: Vec<DataState>,
+}
+
+impl Verification {
+    /// Check if every piece is complete.
+    pub fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|state| *state == DataState::Complete)
+    }
+}
+
+impl MetaInfo {
+    /// Hash the torrent's data saved under the directory `path` in parallel, checking each
+    /// piece against its hash. A file takes the worst state of the pieces holding its data, or
+    /// is missing if it doesn't exist. Padding files are never stored, so they count as present.
+    /// Fails if the piece hashes don't cover the files.
+    pub fn verify(&self, path: &Path) -> Result<Verification, BitTorrentError> {
+        let layout = FileLayout::new(self, path, &[]);
+        let pieces = (0..self.piece_count())
+            .into_par_iter()
+            .map(|piece_id| {
+                let (offset, length) = layout.geometry.piece_range(piece_id);
+                match layout.read_at(offset, length) {
+                    Ok(data) if self.check_piece(piece_id, &data) => DataState::Complete,
+                    Ok(_) => DataState::Corrupt,
+                    Err(_) => DataState::Missing,
+                }
+            })
+            .collect::<Vec<_>>();
+        let files = layout
+            .files
+            .iter()
+            .zip(self.file_piece_ranges())
+            .map(|(file, piece_range)| {
+                let present = file.padding || file.path.exists();
+                Ok(match present {
+                    true => pieces
+                        .get(piece_range)
+                        .context("Piece hashes don't cover every file")?
+                        .iter()
+                        .copied()
+                        .max()
+                        .unwrap_or(DataState::Complete),
+                    false => DataState::Missing,
+                })
+            })
+            .collect::<Result<_, BitTorrentError>>()?;
+        Ok(Verification { pieces, files })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::info::builder::MetaInfoBuilder;
+    use DataState::*;
+
+    /// Torrent of the files a, b and c of 10000, 10000 and 5000 bytes, in two pieces: the first
+    /// spanning a and the start of b, the second the rest of b and c.
+    fn torrent() -> (TempDir, MetaInfo) {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("t");
+        fs::create_dir(&root).unwrap();
+        for (name, length) in [("a", 10000), ("b", 10000), ("c", 5000)] {
+            fs::write(root.join(name), vec![name.as_bytes()[0]; length]).unwrap();
+        }
+        let meta_info = MetaInfoBuilder::new(&root)
+            .piece_length(16384)
+            .build()
+            .unwrap();
+        (dir, meta_info)
+    }
+
+    #[test]
+    fn complete_data() {
+        let (dir, meta_info) = torrent();
+        let verification = meta_info.verify(dir.path()).unwrap();
+        assert!(verification.is_complete());
+        assert_eq!(verification.pieces, vec![Complete, Complete]);
+        assert_eq!(verification.files, vec![Complete, Complete, Complete]);
+    }
+
+    #[test]
+    fn corrupt_data_taints_every_file_of_the_piece() {
+        let (dir, meta_info) = torrent();
+        fs::write(dir.path().join("t/a"), vec![0; 10000]).unwrap();
+        let verification = meta_info.verify(dir.path()).unwrap();
+        assert!(!verification.is_complete());
+        assert_eq!(verification.pieces, vec![Corrupt, Complete]);
+        assert_eq!(verification.files, vec![Corrupt, Corrupt, Complete]);
+    }
+
+    #[test]
+    fn missing_file() {
+        let (dir, meta_info) = torrent();
+        fs::remove_file(dir.path().join("t/c")).unwrap();
+        let verification = meta_info.verify(dir.path()).unwrap();
+        assert_eq!(verification.pieces, vec![Complete, Missing]);
+        assert_eq!(verification.files, vec![Complete, Missing, Missing]);
+    }
+
+    #[test]
+    fn short_file() {
+        let (dir, meta_info) = torrent();
+        fs::write(dir.path().join("t/b"), vec![b'b'; 8000]).unwrap();
+        let verification = meta_info.verify(dir.path()).unwrap();
+        assert_eq!(verification.pieces, vec![Complete, Missing]);
+        assert_eq!(verification.files, vec![Complete, Missing, Missing]);
+    }
+
+    #[test]
+    fn fails_if_the_pieces_do_not_cover_the_files() {
+        let (dir, mut meta_info) = torrent();
+        meta_info.info.pieces.truncate(1);
+        assert!(meta_info.verify(dir.path()).is_err());
+    }
+}
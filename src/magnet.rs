@@ -1,10 +1,16 @@
 use multihash::Multihash;
 
-use crate::{bterror, error::BitTorrentError, util::querystring_decode};
+use crate::{bterror, error::BitTorrentError, info::truncate_hash, util::querystring_decode};
+
+/// multihash code of SHA2-256, the hash function of v2 info hashes
+const SHA2_256: u64 = 0x12;
 
 #[derive(Debug, Clone)]
 pub struct Magnet {
-    pub xt: [u8; 20],
+    /// v1 info hash (`urn:btih`)
+    pub xt: Option<[u8; 20]>,
+    /// v2 info hash (`urn:btmh`), both are given for hybrid torrents
+    pub xt_v2: Option<[u8; 32]>,
     pub dn: Option<String>,
     pub tr: Vec<String>,
     pub xpe: Vec<String>,
@@ -17,6 +23,7 @@ impl Magnet {
         }
 
         let mut xt = None;
+        let mut xt_v2 = None;
         let mut dn = None;
         let mut tr = Vec::new();
         let mut xpe = Vec::new();
@@ -26,22 +33,23 @@ impl Magnet {
             .filter_map(|kvpair| kvpair.split_once("="))
         {
             match key {
-                "xt" => {
-                    xt = match &value[..9] {
-                        "urn:btih:" => Some(
+                "xt" => match value.get(..9) {
+                    Some("urn:btih:") => {
+                        xt = Some(
                             hex::decode(&value[9..])?
                                 .try_into()
                                 .map_err(|_| bterror!("Could not decode info hash"))?,
-                        ),
-                        "urn:btmh:" => {
-                            let raw_multihash = &value[9..].as_bytes();
-                            let decoded: Multihash<20> =
-                                Multihash::<20>::from_bytes(raw_multihash)?;
-                            Some(decoded.digest().try_into()?)
+                        )
+                    }
+                    Some("urn:btmh:") => {
+                        let multihash = Multihash::<64>::from_bytes(&hex::decode(&value[9..])?)?;
+                        if multihash.code() != SHA2_256 {
+                            return Err(bterror!("Unsupported multihash: {:#x}", multihash.code()));
                         }
-                        _ => return Err(bterror!("Invalid xt")),
+                        xt_v2 = Some(multihash.digest().try_into()?);
                     }
-                }
+                    _ => return Err(bterror!("Invalid xt")),
+                },
                 "dn" => dn = Some(String::from_utf8(querystring_decode(value))?),
                 "tr" => tr.push(String::from_utf8(querystring_decode(value))?),
                 "x.pe" => xpe.push(String::from_utf8(querystring_decode(value))?),
@@ -49,9 +57,23 @@ impl Magnet {
             }
         }
 
-        match xt {
-            Some(xt) => Ok(Self { xt, dn, tr, xpe }),
-            None => return Err(bterror!("Missing xt")),
+        if xt.is_none() && xt_v2.is_none() {
+            return Err(bterror!("Missing xt"));
         }
+        Ok(Self {
+            xt,
+            xt_v2,
+            dn,
+            tr,
+            xpe,
+        })
+    }
+
+    /// Info hashes of the swarms the torrent is shared in, as used in handshakes and announces.
+    pub fn swarm_hashes(&self) -> Vec<[u8; 20]> {
+        self.xt
+            .into_iter()
+            .chain(self.xt_v2.as_ref().map(truncate_hash))
+            .collect()
     }
 }
@@ -2,33 +2,37 @@
 
 use std::{
     fs,
+    io::{self, Read, Seek, SeekFrom, Write},
     net::{AddrParseError, SocketAddr, TcpStream, UdpSocket},
     path::PathBuf,
     sync::{atomic::AtomicBool, Arc},
     thread,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, ValueEnum};
 use download::download_piece_from_peer;
 use error::BitTorrentError;
 use tracker::{
     dht::{Dht, DhtMessage, Query},
-    multimodal::Tracker,
+    multimodal::{AnnounceEvent, AnnounceMode, Tracker},
 };
 
 use crate::{
-    bencode::BencodedValue,
+    bencode::{borrowed::BencodedRef, json, BencodedValue, Number},
     download::{
-        corkboard::{corkboard_download, Config},
+        corkboard::{corkboard_download, corkboard_seed, start_corkboard_download, Config},
         download_file,
     },
-    info::{Info, MetaInfo},
+    info::{builder::MetaInfoBuilder, verify::DataState, FileInfo, FilePriority, Info, MetaInfo},
     peer::{
         message::{ExtensionHandshake, ExtensionMetadata, PeerMessageCodec},
+        pipeline::RequestPipeline,
         tcp::TcpPeer,
+        utp::UtpPeer,
     },
+    storage::StorageBackend,
     torrent_source::TorrentSource,
     tracker::{dht::KrpcMessage, TrackerResponse},
     util::bytes_to_hex,
@@ -44,6 +48,7 @@ mod magnet;
 mod multithread;
 mod peer;
 mod pool;
+mod storage;
 mod torrent_source;
 mod tracker;
 mod util;
@@ -68,6 +73,13 @@ enum Subcommand {
     DownloadPiece(DownloadPieceArgs),
     Download(DownloadArgs),
     DownloadV2(DownloadV2Args),
+    Stream(StreamArgs),
+    Seed(SeedArgs),
+    Create(CreateArgs),
+    Verify(VerifyArgs),
+    ToJson(ToJsonArgs),
+    FromJson(FromJsonArgs),
+    Edit(EditArgs),
 }
 
 #[derive(Parser)]
@@ -207,6 +219,272 @@ struct DownloadV2Args {
     /// Print verbose logging information
     #[arg(short, long, action = ArgAction::SetTrue)]
     verbose: bool,
+
+    /// Connect to peers over uTP instead of TCP
+    #[arg(long, action = ArgAction::SetTrue)]
+    utp: bool,
+
+    /// Where to keep pieces while downloading
+    #[arg(long, value_enum, default_value_t = StorageArg::InPlace)]
+    storage: StorageArg,
+
+    /// Comma separated download priority of each file in the torrent; unlisted files are normal priority
+    #[arg(long, value_enum, value_delimiter = ',')]
+    priorities: Vec<FilePriority>,
+
+    /// Download pieces in order, rather than rarest first
+    #[arg(long, action = ArgAction::SetTrue)]
+    sequential: bool,
+
+    /// After downloading, keep seeding until this ratio of uploaded to downloaded data is reached
+    #[arg(long)]
+    seed_ratio: Option<f64>,
+
+    /// After downloading, keep seeding for this many seconds
+    #[arg(long)]
+    seed_time: Option<u64>,
+
+    /// Which of the torrent's trackers to announce to at once
+    #[arg(long, value_enum, default_value_t = AnnounceMode::Sequential)]
+    announce_mode: AnnounceMode,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StorageArg {
+    /// Memory for small torrents, temporary piece files otherwise; copied into place once the
+    /// download completes
+    Auto,
+    /// Memory; copied into place once the download completes
+    Memory,
+    /// Temporary piece files; copied into place once the download completes
+    PieceFiles,
+    /// Straight into the output files
+    InPlace,
+    /// Straight into the output files, through memory maps
+    Mmap,
+}
+
+#[derive(Parser)]
+struct StreamArgs {
+    /// File with torrent information
+    #[arg(required = true)]
+    torrent_source: String,
+
+    /// File (or named pipe) to stream the data into as it downloads
+    #[arg(required = true, value_parser = pathbuf_parse)]
+    stream: PathBuf,
+
+    /// Index of the file within the torrent to stream
+    #[arg(short, long, default_value_t = 0)]
+    file: usize,
+
+    /// Output directory
+    #[arg(short, long, value_parser = pathbuf_parse, default_value = "downloads/")]
+    output: PathBuf,
+
+    /// Peer ID for handshake
+    #[arg(short = 'i', long, default_value = "00112233445566778899")]
+    peer_id: String,
+
+    /// Port for handshake
+    #[arg(short, long, default_value_t = 6881)]
+    port: u16,
+
+    /// Number of workers
+    #[arg(short, long, default_value_t = 32)]
+    workers: usize,
+
+    /// Seconds to wait for the next piece before giving up
+    #[arg(short, long, default_value_t = 300)]
+    timeout: u64,
+
+    /// Print verbose logging information
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    verbose: bool,
+}
+
+#[derive(Parser)]
+struct SeedArgs {
+    /// File with torrent information
+    #[arg(required = true)]
+    torrent: String,
+
+    /// Directory the torrent's data was downloaded to
+    #[arg(required = true, value_parser = pathbuf_parse)]
+    data: PathBuf,
+
+    /// Peer ID for handshake
+    #[arg(short = 'i', long, default_value = "00112233445566778899")]
+    peer_id: String,
+
+    /// Port to accept peer connections on
+    #[arg(short, long, default_value_t = 6881)]
+    port: u16,
+
+    /// Print verbose logging information
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    verbose: bool,
+
+    /// Stop seeding once this ratio of uploaded data to the size of the torrent is reached
+    #[arg(long)]
+    seed_ratio: Option<f64>,
+
+    /// Stop seeding after this many seconds
+    #[arg(long)]
+    seed_time: Option<u64>,
+
+    /// Which of the torrent's trackers to announce to at once
+    #[arg(long, value_enum, default_value_t = AnnounceMode::Sequential)]
+    announce_mode: AnnounceMode,
+}
+
+#[derive(Parser)]
+struct CreateArgs {
+    /// File or directory to create a torrent of
+    #[arg(required = true, value_parser = pathbuf_parse)]
+    path: PathBuf,
+
+    /// File to write the torrent to
+    #[arg(short, long, required = true, value_parser = pathbuf_parse)]
+    output: PathBuf,
+
+    /// Comma separated tracker URLs making up a tier, may be given multiple times for the
+    /// tiers tried after it
+    #[arg(short, long)]
+    announce: Vec<String>,
+
+    /// Name of the torrent, defaults to the name of the file or directory
+    #[arg(short, long)]
+    name: Option<String>,
+
+    /// Length of each piece (bytes, power of two), picked from the size of the data by default
+    #[arg(long)]
+    piece_length: Option<usize>,
+
+    /// Free-form comment stored in the torrent
+    #[arg(long)]
+    comment: Option<String>,
+
+    /// Program recorded as the creator of the torrent
+    #[arg(long, default_value = "MaurdekyeBitTorrent/1.0.0")]
+    created_by: String,
+
+    /// Leave out the creation date
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_date: bool,
+
+    /// Mark the torrent as private, disabling the DHT for it
+    #[arg(long, action = ArgAction::SetTrue)]
+    private: bool,
+}
+
+#[derive(Parser)]
+struct VerifyArgs {
+    /// File with torrent information
+    #[arg(required = true)]
+    torrent: String,
+
+    /// Directory the torrent's data was downloaded to
+    #[arg(required = true, value_parser = pathbuf_parse)]
+    data: PathBuf,
+
+    /// List the state of every piece, not just the ones that failed
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    verbose: bool,
+}
+
+#[derive(Parser)]
+struct ToJsonArgs {
+    /// File with bencoded data, or - for standard input
+    #[arg(required = true)]
+    input: String,
+
+    /// Read the input as a hex string, like decode-hex takes
+    #[arg(long, action = ArgAction::SetTrue)]
+    hex: bool,
+
+    /// File to write the JSON to, standard output by default
+    #[arg(short, long, value_parser = pathbuf_parse)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct FromJsonArgs {
+    /// File with JSON written by to-json, or - for standard input
+    #[arg(required = true)]
+    input: String,
+
+    /// Write the bencoded data as a hex string
+    #[arg(long, action = ArgAction::SetTrue)]
+    hex: bool,
+
+    /// File to write the bencoded data to, standard output by default
+    #[arg(short, long, value_parser = pathbuf_parse)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct EditArgs {
+    /// File with torrent information
+    #[arg(required = true)]
+    torrent: String,
+
+    /// File to write the edited torrent to, the torrent file itself by default
+    #[arg(short, long, value_parser = pathbuf_parse)]
+    output: Option<PathBuf>,
+
+    /// Tracker URL to make the first tracker, which clients without announce list support use
+    #[arg(long)]
+    announce: Option<String>,
+
+    /// Comma separated tracker URLs to add as a tier after the others, may be given multiple
+    /// times
+    #[arg(long)]
+    add_tracker: Vec<String>,
+
+    /// Tier to add the trackers to instead, counting from 0
+    #[arg(long)]
+    tier: Option<usize>,
+
+    /// Tracker URL to remove from every tier, may be given multiple times
+    #[arg(long)]
+    remove_tracker: Vec<String>,
+
+    /// Web seed URL to add after the others, may be given multiple times
+    #[arg(long)]
+    add_web_seed: Vec<String>,
+
+    /// Web seed URL to remove, may be given multiple times
+    #[arg(long)]
+    remove_web_seed: Vec<String>,
+
+    /// Free-form comment to store in the torrent, or an empty string to remove it
+    #[arg(long)]
+    comment: Option<String>,
+
+    /// Program to record as the creator of the torrent, or an empty string to remove it
+    #[arg(long)]
+    created_by: Option<String>,
+}
+
+/// Read the file at `path`, or standard input if it is `-`.
+fn read_input(path: &str) -> Result<Vec<u8>, BitTorrentError> {
+    if path == "-" {
+        let mut content = Vec::new();
+        io::stdin().read_to_end(&mut content)?;
+        Ok(content)
+    } else {
+        Ok(fs::read(path)?)
+    }
+}
+
+/// Write `content` to the file at `path`, or standard output if there is none.
+fn write_output(path: Option<PathBuf>, content: &[u8]) -> Result<(), BitTorrentError> {
+    match path {
+        Some(path) => fs::write(path, content)?,
+        None => io::stdout().write_all(content)?,
+    }
+    Ok(())
 }
 
 fn pathbuf_parse(val: &str) -> Result<PathBuf, String> {
@@ -231,12 +509,13 @@ fn main() -> Result<(), BitTorrentError> {
             let content = hex::decode(decode_args.raw_content)?;
             let mut cslice = &content[..];
             while cslice.len() > 0 {
-                let decoded = BencodedValue::ingest(&mut cslice)?;
+                let borrowed = BencodedRef::decode(&mut cslice)?;
+                let decoded = BencodedValue::from(borrowed);
                 dbg!(&decoded);
                 let _ = dbg!(<Result<MetaInfo, _>>::from(decoded.clone()));
                 let _ = dbg!(<Result<Info, _>>::from(decoded.clone()));
-                let _ = dbg!(<Result<KrpcMessage, _>>::from(decoded.clone()));
-                let _ = dbg!(<Result<ExtensionHandshake, _>>::from(decoded.clone()));
+                let _ = dbg!(<Result<KrpcMessage, _>>::from(borrowed));
+                let _ = dbg!(<Result<ExtensionHandshake, _>>::from(borrowed));
                 let _ = dbg!(<Result<ExtensionMetadata, _>>::from(decoded.clone()));
                 let _ = dbg!(<Result<TrackerResponse, _>>::from(decoded.clone()));
             }
@@ -247,9 +526,18 @@ fn main() -> Result<(), BitTorrentError> {
             println!("{:#?}", decoded_value);
             let meta_info = MetaInfo::from_file(&info_args.torrent_file)?;
             let info_hash = meta_info.info_hash()?;
-            println!("Tracker URL: {}", meta_info.announce_list.first().unwrap());
+            // trackerless torrents rely on the DHT alone
+            if let Some(url) = meta_info.tiers().first().and_then(|tier| tier.first()) {
+                println!("Tracker URL: {url}");
+            }
+            for (tier, trackers) in meta_info.tiers().iter().enumerate() {
+                println!("Tier {tier}: {}", trackers.join(" "));
+            }
             println!("Length: {}", meta_info.length());
             println!("Info Hash: {}", bytes_to_hex(&info_hash));
+            if let Some(info_hash_v2) = meta_info.info_hash_v2()? {
+                println!("Info Hash (v2): {}", bytes_to_hex(&info_hash_v2));
+            }
             println!("Piece Length: {}", meta_info.info.pieces.len());
             println!("Piece Hashes:");
             for hash in meta_info.info.pieces {
@@ -259,7 +547,7 @@ fn main() -> Result<(), BitTorrentError> {
         Subcommand::Peers(peers_args) => {
             let torrent_source = TorrentSource::from_string(&peers_args.torrent_source)?;
             let mut tracker = Tracker::new(torrent_source, peers_args.peer_id, peers_args.port)?;
-            let (peers, _) = tracker.query().unwrap();
+            let (peers, _) = tracker.query(AnnounceEvent::None).unwrap();
             for sock in peers {
                 println!("{}", sock);
             }
@@ -303,6 +591,9 @@ fn main() -> Result<(), BitTorrentError> {
                 encoder: PeerMessageCodec::default(),
                 decoder: PeerMessageCodec::default(),
                 choked: false,
+                pipeline: RequestPipeline::default(),
+                pieces: Vec::new(),
+                requested: Vec::new(),
             };
             let response = connection.handshake()?;
             println!("Peer ID: {}", bytes_to_hex(&response.peer_id));
@@ -336,23 +627,41 @@ fn main() -> Result<(), BitTorrentError> {
             let torrent_source = TorrentSource::from_string(&download_args.torrent_source)?;
             // dbg!(&torrent_source);
             let temp_path: PathBuf = PathBuf::from("tmp/in-progress/").join(torrent_source.name());
+            let config = Config {
+                peer_id: download_args.peer_id,
+                port: download_args.port,
+                workers: download_args.workers,
+                verbose: download_args.verbose,
+                temp_path: temp_path.clone(),
+                storage: match download_args.storage {
+                    StorageArg::Auto => StorageBackend::Auto,
+                    StorageArg::Memory => StorageBackend::Memory,
+                    StorageArg::PieceFiles => StorageBackend::PieceFiles(temp_path.clone()),
+                    StorageArg::InPlace => StorageBackend::InPlace(download_args.output.clone()),
+                    StorageArg::Mmap => StorageBackend::Mmap(download_args.output.clone()),
+                },
+                file_priorities: download_args.priorities.clone(),
+                sequential: download_args.sequential,
+                seed_ratio: download_args.seed_ratio,
+                seed_time: download_args.seed_time.map(Duration::from_secs),
+                announce_mode: download_args.announce_mode,
+                ..Default::default()
+            };
             thread::scope(|scope| {
-                let (full_file, meta_info) = corkboard_download::<TcpPeer>(
-                    torrent_source,
-                    &scope,
-                    Config {
-                        peer_id: download_args.peer_id,
-                        port: download_args.port,
-                        workers: download_args.workers,
-                        verbose: download_args.verbose,
-                        temp_path,
-                        ..Default::default()
-                    },
-                )?;
-                println!("Saving to file");
-                meta_info
-                    .save_to_path(&download_args.output, full_file)
-                    .with_context(|| "Error saving torrent file(s)")?;
+                let (full_file, meta_info) = if download_args.utp {
+                    corkboard_download::<UtpPeer>(torrent_source, &scope, config)?
+                } else {
+                    corkboard_download::<TcpPeer>(torrent_source, &scope, config)?
+                };
+                if matches!(
+                    download_args.storage,
+                    StorageArg::Auto | StorageArg::Memory | StorageArg::PieceFiles
+                ) {
+                    println!("Saving to file");
+                    meta_info
+                        .save_to_path(&download_args.output, full_file, &download_args.priorities)
+                        .with_context(|| "Error saving torrent file(s)")?;
+                }
                 println!(
                     "Downloaded {} to {}.",
                     meta_info.info.name,
@@ -361,6 +670,205 @@ fn main() -> Result<(), BitTorrentError> {
                 Ok::<_, BitTorrentError>(())
             })?;
         }
+        Subcommand::Stream(stream_args) => {
+            let torrent_source = TorrentSource::from_string(&stream_args.torrent_source)?;
+            let temp_path: PathBuf = PathBuf::from("tmp/in-progress/").join(torrent_source.name());
+            let config = Config {
+                peer_id: stream_args.peer_id,
+                port: stream_args.port,
+                workers: stream_args.workers,
+                verbose: stream_args.verbose,
+                temp_path,
+                storage: StorageBackend::InPlace(stream_args.output.clone()),
+                sequential: true,
+                ..Default::default()
+            };
+            let no_file = || bterror!("Torrent has no file {}", stream_args.file);
+            if let TorrentSource::File(meta_info) = &torrent_source {
+                if stream_args.file >= meta_info.file_ranges().len() {
+                    return Err(no_file());
+                }
+            }
+            thread::scope(|scope| {
+                let download = start_corkboard_download::<TcpPeer>(torrent_source, scope, config)?;
+                // the download has to be shut down however streaming ends
+                let streamed = (|| {
+                    let range = download
+                        .meta_info
+                        .file_ranges()
+                        .get(stream_args.file)
+                        .cloned()
+                        .ok_or_else(no_file)?;
+                    let mut reader =
+                        download.reader(Some(Duration::from_secs(stream_args.timeout)));
+                    reader.seek(SeekFrom::Start(range.start as u64))?;
+                    let mut stream = fs::File::create(&stream_args.stream)?;
+                    io::copy(&mut reader.take(range.len() as u64), &mut stream)
+                        .with_context(|| "Error streaming torrent data")?;
+                    Ok::<_, BitTorrentError>(())
+                })();
+                match streamed {
+                    Ok(()) => {
+                        println!(
+                            "Streamed file {} to {}.",
+                            stream_args.file,
+                            stream_args.stream.display()
+                        );
+                        download.finish()?;
+                        Ok(())
+                    }
+                    Err(err) => {
+                        download.abort()?;
+                        Err(err)
+                    }
+                }
+            })?;
+        }
+        Subcommand::Seed(seed_args) => {
+            let meta_info = MetaInfo::from_file(&seed_args.torrent)?;
+            let config = Config {
+                peer_id: seed_args.peer_id,
+                port: seed_args.port,
+                verbose: seed_args.verbose,
+                temp_path: PathBuf::from("tmp/in-progress/").join(&meta_info.info.name),
+                storage: StorageBackend::ReadOnly(seed_args.data),
+                seed_ratio: seed_args.seed_ratio,
+                seed_time: seed_args.seed_time.map(Duration::from_secs),
+                announce_mode: seed_args.announce_mode,
+                ..Default::default()
+            };
+            thread::scope(|scope| corkboard_seed(meta_info, scope, config))?;
+        }
+        Subcommand::Create(create_args) => {
+            let mut builder = MetaInfoBuilder::new(create_args.path)
+                .created_by(create_args.created_by)
+                .private(create_args.private);
+            for tier in create_args.announce {
+                builder = builder.announce_tier(tier.split(',').map(String::from).collect());
+            }
+            if let Some(name) = create_args.name {
+                builder = builder.name(name);
+            }
+            if let Some(piece_length) = create_args.piece_length {
+                builder = builder.piece_length(piece_length);
+            }
+            if let Some(comment) = create_args.comment {
+                builder = builder.comment(comment);
+            }
+            if !create_args.no_date {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .context("System clock is set before the unix epoch")?;
+                builder = builder.creation_date(now.as_secs() as Number);
+            }
+            let meta_info = builder.build()?;
+            let info_hash = meta_info.info_hash()?;
+            let pieces = meta_info.info.pieces.len();
+            fs::write(&create_args.output, meta_info.encode()?)?;
+            println!(
+                "Created {} with {pieces} pieces, info hash {}",
+                create_args.output.display(),
+                bytes_to_hex(&info_hash)
+            );
+        }
+        Subcommand::Verify(verify_args) => {
+            let meta_info = MetaInfo::from_file(&verify_args.torrent)?;
+            let verification = meta_info.verify(&verify_args.data)?;
+            for (piece_id, state) in verification.pieces.iter().enumerate() {
+                if verify_args.verbose || *state != DataState::Complete {
+                    println!("Piece {piece_id}: {state:?}");
+                }
+            }
+            let paths = match &meta_info.info.file_info {
+                FileInfo::Length(_) => vec![Some(meta_info.info.name.clone())],
+                FileInfo::Files(files) => files
+                    .iter()
+                    .map(|file| (!file.is_padding()).then(|| file.path.join("/")))
+                    .collect(),
+            };
+            for (path, state) in paths.iter().zip(&verification.files) {
+                if let Some(path) = path {
+                    println!("{state:?}: {path}");
+                }
+            }
+            let complete = verification
+                .pieces
+                .iter()
+                .filter(|state| **state == DataState::Complete)
+                .count();
+            println!(
+                "Verified {complete}/{} pieces of {}",
+                verification.pieces.len(),
+                meta_info.info.name
+            );
+            if !verification.is_complete() {
+                return Err(bterror!("Data of {} is incomplete", meta_info.info.name));
+            }
+        }
+        Subcommand::ToJson(to_json_args) => {
+            let mut content = read_input(&to_json_args.input)?;
+            if to_json_args.hex {
+                content = hex::decode(String::from_utf8(content)?.trim())?;
+            }
+            let decoded = BencodedValue::ingest_with(&mut &content[..], json::DECODE_OPTIONS)?;
+            let mut json = serde_json::to_vec_pretty(&serde_json::Value::from(decoded))?;
+            json.push(b'\n');
+            write_output(to_json_args.output, &json)?;
+        }
+        Subcommand::FromJson(from_json_args) => {
+            let content = read_input(&from_json_args.input)?;
+            let json: serde_json::Value = serde_json::from_slice(&content)?;
+            let mut encoded = BencodedValue::try_from(json)?.encode()?;
+            if from_json_args.hex {
+                encoded = format!("{}\n", bytes_to_hex(&encoded)).into_bytes();
+            }
+            write_output(from_json_args.output, &encoded)?;
+        }
+        Subcommand::Edit(edit_args) => {
+            let mut meta_info = MetaInfo::from_file(&edit_args.torrent)?;
+            let info_hash = meta_info.info_hash()?;
+            for url in &edit_args.remove_tracker {
+                if !meta_info.remove_tracker(url) {
+                    println!("Tracker {url} is not listed");
+                }
+            }
+            for urls in edit_args.add_tracker {
+                let tier = edit_args.tier.unwrap_or(meta_info.tiers().len());
+                for url in urls.split(',') {
+                    meta_info.add_tracker(url, tier);
+                }
+            }
+            if let Some(url) = edit_args.announce {
+                meta_info.set_announce(url);
+            }
+            for url in &edit_args.remove_web_seed {
+                if !meta_info.remove_web_seed(url) {
+                    println!("Web seed {url} is not listed");
+                }
+            }
+            for url in edit_args.add_web_seed {
+                meta_info.add_web_seed(url);
+            }
+            if let Some(comment) = edit_args.comment {
+                meta_info.comment = (!comment.is_empty()).then_some(comment);
+            }
+            if let Some(created_by) = edit_args.created_by {
+                meta_info.created_by = (!created_by.is_empty()).then_some(created_by);
+            }
+            let encoded = meta_info.encode()?;
+            if MetaInfo::from_bytes(&encoded)?.info_hash()? != info_hash {
+                return Err(bterror!("Editing the torrent would change its info hash"));
+            }
+            let output = edit_args
+                .output
+                .unwrap_or_else(|| PathBuf::from(&edit_args.torrent));
+            fs::write(&output, encoded)?;
+            println!(
+                "Edited {}, info hash {}",
+                output.display(),
+                bytes_to_hex(&info_hash)
+            );
+        }
     }
     Ok(())
 }
@@ -1,6 +1,9 @@
 #![allow(unused)]
 
-use std::sync::{Condvar, Mutex};
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
 
 pub struct Semaphore {
     value: Mutex<usize>,
@@ -78,3 +81,59 @@ impl SyncDoor {
         self.lock.lock().map(|lock| *lock)
     }
 }
+
+/// Fixed set of flags that threads can wait on being raised
+pub struct SyncFlags {
+    flags: Mutex<Vec<bool>>,
+    cvar: Condvar,
+}
+
+impl SyncFlags {
+    pub fn new(count: usize) -> Self {
+        Self {
+            flags: Mutex::new(vec![false; count]),
+            cvar: Condvar::new(),
+        }
+    }
+
+    pub fn raise(&self, index: usize) {
+        let mut flags = self.flags.lock().unwrap();
+        if let Some(flag) = flags.get_mut(index) {
+            *flag = true;
+        }
+        self.cvar.notify_all();
+    }
+
+    pub fn is_raised(&self, index: usize) -> bool {
+        self.flags
+            .lock()
+            .unwrap()
+            .get(index)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Wait until flag `index` is raised, giving up after `timeout` if one is given.
+    /// Returns whether the flag was raised.
+    pub fn wait(&self, index: usize, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut flags = self.flags.lock().unwrap();
+        loop {
+            match flags.get(index) {
+                Some(true) => return true,
+                Some(false) => {}
+                None => return false,
+            }
+            flags = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return false;
+                    }
+                    self.cvar.wait_timeout(flags, remaining).unwrap().0
+                }
+                None => self.cvar.wait(flags).unwrap(),
+            };
+        }
+    }
+}
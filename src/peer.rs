@@ -1,14 +1,24 @@
 use std::{
     error,
+    io::{self, Read, Write},
     net::SocketAddr,
     sync::{atomic::AtomicBool, Arc},
+    time::Duration,
 };
 
 use crate::{info::MetaInfo, torrent_source::TorrentSource};
 
+use self::blocks::PieceBlocks;
+
+pub mod blocks;
 pub mod message;
+pub mod pipeline;
 pub mod tcp;
 pub mod utp;
+pub mod wire;
+
+/// Picks the next piece to download from a connection, and whether it is in endgame mode
+pub type NextPiece<'a, T> = dyn FnMut(&T) -> Option<(Arc<PieceBlocks>, bool)> + 'a;
 
 pub trait PeerConnection {
     type Error: error::Error;
@@ -25,6 +35,26 @@ pub trait PeerConnection {
         Self: Sized;
     fn download_piece(&mut self, piece_id: u32) -> Result<Vec<u8>, Self::Error>;
 
+    /// Download the blocks of a piece that are still missing from `blocks`, alongside any other
+    /// connections working on the same piece. In endgame mode, blocks already requested from
+    /// other peers are requested as well, and cancelled once they arrive from elsewhere. The
+    /// piece may be left incomplete if the peer stops serving it, with every block received
+    /// up to that point kept in `blocks`. To keep the peer busy while the last blocks of the
+    /// piece arrive, the pieces to download next are taken from `next`, and requested ahead of
+    /// the `download_blocks` calls that are made for them.
+    fn download_blocks(
+        &mut self,
+        blocks: &Arc<PieceBlocks>,
+        endgame: bool,
+        next: &mut NextPiece<Self>,
+    ) -> Result<(), Self::Error>;
+
+    /// Let the peer know that piece `piece_id` is now available from us.
+    fn have(&mut self, piece_id: u32) -> Result<(), Self::Error>;
+
+    /// Total bytes of block data received from the peer over this connection.
+    fn downloaded(&self) -> usize;
+
     fn sever(&self) -> Result<(), Self::Error>;
 
     fn address(&self) -> &SocketAddr;
@@ -36,3 +66,15 @@ pub trait PeerConnection {
         *self.bitfield().get(piece_id).unwrap_or(&false)
     }
 }
+
+/// Reliable, ordered byte stream to a peer that wire protocol messages can be carried over
+pub trait PeerStream: Read + Write + Sized {
+    /// Open a new stream to the peer at `address`.
+    fn connect(address: &SocketAddr) -> io::Result<Self>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Close the stream in both directions.
+    fn sever(&self) -> io::Result<()>;
+}
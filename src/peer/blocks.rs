@@ -0,0 +1,352 @@
+use std::{net::SocketAddr, sync::Mutex};
+
+use crate::{bterror, error::BitTorrentError, util::sha256_hash};
+
+/// size of the blocks pieces are requested from peers in (bytes)
+pub const BLOCK_SIZE: u32 = 16384;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    /// not yet received, and requested from each of the listed peers
+    Pending(Vec<SocketAddr>),
+    Received(Vec<u8>),
+}
+
+/// Block level progress of a single piece, shared by every connection downloading it, so
+/// that requests can be spread across peers and cancelled once a block arrives
+#[derive(Debug)]
+pub struct PieceBlocks {
+    pub piece_id: u32,
+    pub length: u32,
+    blocks: Mutex<Vec<BlockState>>,
+    /// SHA256 hashes of the blocks of a v2 piece, once known, that every block is checked
+    /// against as it arrives. Always locked after `blocks`.
+    hashes: Mutex<Vec<[u8; 32]>>,
+}
+
+impl PieceBlocks {
+    pub fn new(piece_id: u32, length: u32) -> Self {
+        Self {
+            piece_id,
+            length,
+            blocks: Mutex::new(vec![
+                BlockState::Pending(Vec::new());
+                length.div_ceil(BLOCK_SIZE) as usize
+            ]),
+            hashes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Offset and length of block `index` within the piece.
+    fn block_range(&self, index: usize) -> (u32, u32) {
+        let begin = index as u32 * BLOCK_SIZE;
+        (begin, (self.length - begin).min(BLOCK_SIZE))
+    }
+
+    /// Claim the next block to request from the peer at `address`, returning its offset and
+    /// length. Blocks that have not been requested from anyone come first; in endgame mode,
+    /// blocks already requested from other peers may be claimed as well, least requested first.
+    pub fn claim(&self, address: &SocketAddr, endgame: bool) -> Option<(u32, u32)> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let index = blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, block)| match block {
+                BlockState::Pending(peers) if !peers.contains(address) => {
+                    (peers.is_empty() || endgame).then_some((index, peers.len()))
+                }
+                _ => None,
+            })
+            .min_by_key(|(_, requesters)| *requesters)
+            .map(|(index, _)| index)?;
+        if let BlockState::Pending(peers) = &mut blocks[index] {
+            peers.push(*address);
+        }
+        Some(self.block_range(index))
+    }
+
+    /// Store the block at `begin` received from the peer at `address`. Returns whether the
+    /// block was needed, as opposed to a duplicate or not matching the piece's blocks, and
+    /// fails if the block doesn't match its hash.
+    pub fn receive(
+        &self,
+        address: &SocketAddr,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> Result<bool, BitTorrentError> {
+        let index = (begin / BLOCK_SIZE) as usize;
+        if !begin.is_multiple_of(BLOCK_SIZE) {
+            return Ok(false);
+        }
+        let expected_length = match index < self.block_count() {
+            true => self.block_range(index).1,
+            false => return Ok(false),
+        };
+        let mut blocks = self.blocks.lock().unwrap();
+        let hashes = self.hashes.lock().unwrap();
+        match &blocks[index] {
+            BlockState::Pending(_) if data.len() != expected_length as usize => {
+                release_claim(&mut blocks, address, index);
+                Ok(false)
+            }
+            BlockState::Pending(_)
+                if hashes
+                    .get(index)
+                    .is_some_and(|hash| sha256_hash(&data) != *hash) =>
+            {
+                release_claim(&mut blocks, address, index);
+                Err(bterror!(
+                    "Block {begin} of piece {} does not match its hash",
+                    self.piece_id
+                ))
+            }
+            BlockState::Pending(_) => {
+                blocks[index] = BlockState::Received(data);
+                Ok(true)
+            }
+            BlockState::Received(_) => Ok(false),
+        }
+    }
+
+    /// Check if the hashes of the blocks are known.
+    pub fn has_hashes(&self) -> bool {
+        !self.hashes.lock().unwrap().is_empty()
+    }
+
+    /// Check every block against `hashes` from now on, the hashes of the blocks in order,
+    /// dropping the blocks received so far that don't match. Returns the number of blocks
+    /// dropped.
+    pub fn set_hashes(&self, hashes: Vec<[u8; 32]>) -> usize {
+        let mut blocks = self.blocks.lock().unwrap();
+        let mut dropped = 0;
+        for (block, hash) in blocks.iter_mut().zip(&hashes) {
+            if matches!(block, BlockState::Received(data) if sha256_hash(data) != *hash) {
+                *block = BlockState::Pending(Vec::new());
+                dropped += 1;
+            }
+        }
+        *self.hashes.lock().unwrap() = hashes;
+        dropped
+    }
+
+    /// Drop the claim of the peer at `address` on the block at `begin`, e.g. after the request
+    /// was rejected.
+    pub fn release(&self, address: &SocketAddr, begin: u32) {
+        let mut blocks = self.blocks.lock().unwrap();
+        let index = (begin / BLOCK_SIZE) as usize;
+        if index < blocks.len() {
+            release_claim(&mut blocks, address, index);
+        }
+    }
+
+    /// Drop every claim of the peer at `address`, e.g. after it disconnects or chokes us.
+    pub fn release_all(&self, address: &SocketAddr) {
+        let mut blocks = self.blocks.lock().unwrap();
+        for index in 0..blocks.len() {
+            release_claim(&mut blocks, address, index);
+        }
+    }
+
+    /// Check if the block at `begin` has been received.
+    pub fn is_received(&self, begin: u32) -> bool {
+        let blocks = self.blocks.lock().unwrap();
+        matches!(
+            blocks.get((begin / BLOCK_SIZE) as usize),
+            Some(BlockState::Received(_))
+        )
+    }
+
+    /// Check if every block of the piece has been received.
+    pub fn is_complete(&self) -> bool {
+        let blocks = self.blocks.lock().unwrap();
+        blocks
+            .iter()
+            .all(|block| matches!(block, BlockState::Received(_)))
+    }
+
+    /// Number of blocks received so far.
+    pub fn received_count(&self) -> usize {
+        let blocks = self.blocks.lock().unwrap();
+        blocks
+            .iter()
+            .filter(|block| matches!(block, BlockState::Received(_)))
+            .count()
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.length.div_ceil(BLOCK_SIZE) as usize
+    }
+
+    /// Join the received blocks into the piece's data, once every block has been received.
+    pub fn assemble(&self) -> Option<Vec<u8>> {
+        let blocks = self.blocks.lock().unwrap();
+        blocks
+            .iter()
+            .map(|block| match block {
+                BlockState::Received(data) => Some(data.as_slice()),
+                BlockState::Pending(_) => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|blocks| blocks.concat())
+    }
+
+    /// Discard every received block and claim, e.g. after the assembled piece failed its hash check.
+    pub fn reset(&self) {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.fill(BlockState::Pending(Vec::new()));
+    }
+}
+
+/// Remove the peer at `address` from the requesters of block `index`.
+fn release_claim(blocks: &mut [BlockState], address: &SocketAddr, index: usize) {
+    if let BlockState::Pending(peers) = &mut blocks[index] {
+        peers.retain(|peer| peer != address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn claim_all(blocks: &PieceBlocks, address: &SocketAddr, endgame: bool) -> Vec<(u32, u32)> {
+        std::iter::from_fn(|| blocks.claim(address, endgame)).collect()
+    }
+
+    #[test]
+    fn splits_pieces_into_blocks() {
+        let blocks = PieceBlocks::new(0, BLOCK_SIZE * 2 + 100);
+        assert_eq!(blocks.block_count(), 3);
+        assert_eq!(
+            claim_all(&blocks, &peer(1), false),
+            [
+                (0, BLOCK_SIZE),
+                (BLOCK_SIZE, BLOCK_SIZE),
+                (BLOCK_SIZE * 2, 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn spreads_claims_across_peers() {
+        let blocks = PieceBlocks::new(0, BLOCK_SIZE * 3);
+        assert_eq!(blocks.claim(&peer(1), false), Some((0, BLOCK_SIZE)));
+        assert_eq!(
+            blocks.claim(&peer(2), false),
+            Some((BLOCK_SIZE, BLOCK_SIZE))
+        );
+        assert_eq!(
+            blocks.claim(&peer(1), false),
+            Some((BLOCK_SIZE * 2, BLOCK_SIZE))
+        );
+        assert_eq!(blocks.claim(&peer(2), false), None);
+    }
+
+    #[test]
+    fn shares_claims_in_endgame() {
+        let blocks = PieceBlocks::new(0, BLOCK_SIZE * 2);
+        claim_all(&blocks, &peer(1), false);
+        blocks.claim(&peer(2), true);
+        // the least requested block comes first, and never twice from the same peer
+        assert_eq!(blocks.claim(&peer(3), true), Some((BLOCK_SIZE, BLOCK_SIZE)));
+        assert_eq!(blocks.claim(&peer(3), true), Some((0, BLOCK_SIZE)));
+        assert_eq!(blocks.claim(&peer(3), true), None);
+        assert_eq!(blocks.claim(&peer(1), true), None);
+    }
+
+    #[test]
+    fn releases_claims() {
+        let blocks = PieceBlocks::new(0, BLOCK_SIZE * 2);
+        claim_all(&blocks, &peer(1), false);
+        blocks.release(&peer(1), BLOCK_SIZE);
+        assert_eq!(
+            blocks.claim(&peer(2), false),
+            Some((BLOCK_SIZE, BLOCK_SIZE))
+        );
+        assert_eq!(blocks.claim(&peer(2), false), None);
+
+        blocks.release_all(&peer(1));
+        assert_eq!(blocks.claim(&peer(3), false), Some((0, BLOCK_SIZE)));
+        // releasing a block past the end of the piece is ignored
+        blocks.release(&peer(3), BLOCK_SIZE * 5);
+    }
+
+    #[test]
+    fn receives_each_block_once() {
+        let blocks = PieceBlocks::new(7, BLOCK_SIZE + 10);
+        claim_all(&blocks, &peer(1), false);
+        assert!(blocks.receive(&peer(1), BLOCK_SIZE, vec![2; 10]).unwrap());
+        assert!(blocks.is_received(BLOCK_SIZE));
+        assert!(!blocks.receive(&peer(2), BLOCK_SIZE, vec![3; 10]).unwrap());
+        assert_eq!(blocks.received_count(), 1);
+        assert!(!blocks.is_complete());
+        assert_eq!(blocks.assemble(), None);
+        // received blocks are no longer handed out, even in endgame
+        assert_eq!(blocks.claim(&peer(2), true), Some((0, BLOCK_SIZE)));
+        assert_eq!(blocks.claim(&peer(3), true), Some((0, BLOCK_SIZE)));
+
+        assert!(blocks
+            .receive(&peer(3), 0, vec![1; BLOCK_SIZE as usize])
+            .unwrap());
+        assert!(blocks.is_complete());
+        let mut expected = vec![1; BLOCK_SIZE as usize];
+        expected.extend([2; 10]);
+        assert_eq!(blocks.assemble(), Some(expected));
+    }
+
+    #[test]
+    fn rejects_blocks_that_do_not_fit() {
+        let blocks = PieceBlocks::new(0, BLOCK_SIZE * 2);
+        assert!(!blocks
+            .receive(&peer(1), 1, vec![0; BLOCK_SIZE as usize])
+            .unwrap());
+        assert!(!blocks
+            .receive(&peer(1), BLOCK_SIZE * 2, vec![0; BLOCK_SIZE as usize])
+            .unwrap());
+
+        // a block of the wrong length also drops the sender's claim on it
+        blocks.claim(&peer(1), false);
+        assert!(!blocks.receive(&peer(1), 0, vec![0; 10]).unwrap());
+        assert_eq!(blocks.received_count(), 0);
+        assert_eq!(blocks.claim(&peer(2), false), Some((0, BLOCK_SIZE)));
+    }
+
+    #[test]
+    fn resets_after_a_failed_hash_check() {
+        let blocks = PieceBlocks::new(0, 10);
+        blocks.claim(&peer(1), false);
+        assert!(blocks.receive(&peer(1), 0, vec![0; 10]).unwrap());
+        blocks.reset();
+        assert!(!blocks.is_received(0));
+        assert_eq!(blocks.claim(&peer(1), false), Some((0, 10)));
+    }
+
+    #[test]
+    fn checks_blocks_against_their_hashes() {
+        let blocks = PieceBlocks::new(0, BLOCK_SIZE + 10);
+        blocks.claim(&peer(1), false);
+        blocks.claim(&peer(1), false);
+        assert!(blocks
+            .receive(&peer(1), 0, vec![1; BLOCK_SIZE as usize])
+            .unwrap());
+
+        // learning the hashes drops the bad block received already
+        let hashes = vec![
+            sha256_hash(&[2; BLOCK_SIZE as usize]),
+            sha256_hash(&[3; 10]),
+        ];
+        assert_eq!(blocks.set_hashes(hashes), 1);
+        assert!(blocks.has_hashes());
+        assert!(!blocks.is_received(0));
+
+        // and mismatching blocks are refused from then on
+        assert!(blocks.receive(&peer(1), BLOCK_SIZE, vec![4; 10]).is_err());
+        assert_eq!(blocks.claim(&peer(2), false), Some((0, BLOCK_SIZE)));
+        assert!(blocks
+            .receive(&peer(2), 0, vec![2; BLOCK_SIZE as usize])
+            .unwrap());
+    }
+}
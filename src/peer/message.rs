@@ -3,9 +3,14 @@ use std::iter::{empty, once};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::ops::Deref;
 
-use crate::bencode::{BencodedValue, Number};
-use crate::bytes::{Bytes, PullBytes};
-use crate::dict;
+use serde::{Deserialize, Serialize};
+
+use crate::bencode::{
+    self,
+    borrowed::{BencodedRef, DecodeOptions},
+    BencodedValue, Number,
+};
+use crate::bytes::Bytes;
 use crate::torrent_source::TorrentSource;
 use crate::{
     bterror, bytes,
@@ -44,6 +49,11 @@ pub enum PeerMessage {
     RejectRequest(RequestMessage), // 16
     AllowFast(u32),                // 17
     Extension(ExtensionMessage),   // 20
+
+    // v2 messages
+    HashRequest(HashRequestMessage), // 21
+    Hashes(HashesMessage),           // 22
+    HashReject(HashRequestMessage),  // 23
 }
 
 #[derive(Debug)]
@@ -60,6 +70,25 @@ pub struct PieceMessage {
     pub block: Vec<u8>,
 }
 
+/// Request for a run of hashes of a v2 file's merkle tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashRequestMessage {
+    pub pieces_root: [u8; 32],
+    /// layer of the tree the hashes are taken from, counting up from the blocks
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    /// number of layers of uncle hashes to include above the requested ones
+    pub proof_layers: u32,
+}
+
+#[derive(Debug)]
+pub struct HashesMessage {
+    pub request: HashRequestMessage,
+    /// the requested hashes, followed by the uncle hashes proving them
+    pub hashes: Vec<[u8; 32]>,
+}
+
 #[derive(Debug)]
 pub enum ExtensionMessage {
     Handshake(ExtensionHandshake),
@@ -75,77 +104,90 @@ impl ExtensionMessage {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ExtensionHandshake {
+    #[serde(rename = "m")]
     pub messages: Option<HashMap<Bytes, Number>>,
+    #[serde(rename = "p")]
     pub port: Option<u16>,
+    #[serde(rename = "v")]
     pub version: Option<Bytes>,
+    #[serde(default, with = "compact_ip")]
     pub yourip: Option<IpAddr>,
+    #[serde(default, with = "compact_ip")]
     pub ipv6: Option<Ipv6Addr>,
+    #[serde(default, with = "compact_ip")]
     pub ipv4: Option<Ipv4Addr>,
     pub reqq: Option<Number>,
     pub metadata_size: Option<Number>,
 }
 
-impl From<BencodedValue> for Result<ExtensionHandshake, BitTorrentError> {
-    fn from(value: BencodedValue) -> Self {
-        if let BencodedValue::Dict(mut handshake) = value {
-            Ok(ExtensionHandshake {
-                messages: handshake
-                    .pull(b"m")
-                    .and_then(BencodedValue::into_dict)
-                    .and_then(|map| {
-                        let map = map
-                            .into_iter()
-                            .filter_map(|(k, v)| v.into_int().map(|i| (k, i)))
-                            .collect::<HashMap<_, _>>();
-                        (!map.is_empty()).then_some(map)
-                    }),
-                port: handshake
-                    .pull(b"p")
-                    .and_then(BencodedValue::into_int)
-                    .map(|inner| inner as u16),
-                version: handshake.pull(b"v").and_then(BencodedValue::into_bytes),
-                yourip: handshake
-                    .pull(b"yourip")
-                    .and_then(BencodedValue::into_bytes)
-                    .map(<Result<IpAddr, _>>::from)
-                    .transpose()?,
-                ipv6: handshake
-                    .pull(b"ipv6")
-                    .and_then(BencodedValue::into_bytes)
-                    .map(Ipv6Addr::from),
-                ipv4: handshake
-                    .pull(b"ipv4")
-                    .and_then(BencodedValue::into_bytes)
-                    .map(Ipv4Addr::from),
-                reqq: handshake.pull(b"reqq").and_then(BencodedValue::into_int),
-                metadata_size: handshake
-                    .pull(b"metadata_size")
-                    .and_then(BencodedValue::into_int),
-            })
-        } else {
-            Err(bterror!("Invalid extension handshake"))
-        }
+impl From<BencodedRef<'_>> for Result<ExtensionHandshake, BitTorrentError> {
+    fn from(value: BencodedRef<'_>) -> Self {
+        bencode::from_ref(value)
     }
 }
 
-impl From<ExtensionHandshake> for BencodedValue {
-    fn from(val: ExtensionHandshake) -> Self {
-        dict! {
-            b"m" => val.messages,
-            b"p" => val.port.map(|x| x as Number),
-            b"v" => val.version,
-            b"yourip" => val.yourip.map(Bytes::from),
-            b"ipv6" => val.ipv6.map(Bytes::from),
-            b"ipv4" => val.ipv4.map(Bytes::from),
-            b"reqq" => val.reqq,
-            b"metadata_size" => val.metadata_size,
+/// Optional ip addresses in their compact form, their 4 or 16 bytes in network order
+mod compact_ip {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::{bytes::Bytes, error::BitTorrentError};
+
+    /// Address that an ip address of either version may or may not fit
+    pub trait Address: Copy + Into<IpAddr> {
+        fn from_ip(ip: IpAddr) -> Option<Self>;
+    }
+
+    impl Address for IpAddr {
+        fn from_ip(ip: IpAddr) -> Option<Self> {
+            Some(ip)
+        }
+    }
+
+    impl Address for Ipv4Addr {
+        fn from_ip(ip: IpAddr) -> Option<Self> {
+            match ip {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            }
+        }
+    }
+
+    impl Address for Ipv6Addr {
+        fn from_ip(ip: IpAddr) -> Option<Self> {
+            match ip {
+                IpAddr::V4(_) => None,
+                IpAddr::V6(ip) => Some(ip),
+            }
         }
     }
+
+    pub fn serialize<S: Serializer, A: Address>(
+        ip: &Option<A>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        ip.map(|ip| Bytes::from(ip.into())).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, A: Address>(
+        deserializer: D,
+    ) -> Result<Option<A>, D::Error> {
+        Option::<Bytes>::deserialize(deserializer)?
+            .map(|bytes| {
+                let length = bytes.len();
+                <Result<IpAddr, BitTorrentError>>::from(bytes)
+                    .ok()
+                    .and_then(A::from_ip)
+                    .ok_or_else(|| de::Error::custom(format!("Invalid ip address: {length}")))
+            })
+            .transpose()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ExtensionMetadata {
     pub msg_type: Number,
     pub piece: Number,
@@ -154,35 +196,13 @@ pub struct ExtensionMetadata {
 
 impl From<BencodedValue> for Result<ExtensionMetadata, BitTorrentError> {
     fn from(value: BencodedValue) -> Self {
-        if let BencodedValue::Dict(mut metadata) = value {
-            Ok(ExtensionMetadata {
-                msg_type: metadata
-                    .pull(b"msg_type")
-                    .and_then(BencodedValue::into_int)
-                    .ok_or(bterror!("msg_type missing"))?
-                    .into(),
-                piece: metadata
-                    .pull(b"piece")
-                    .and_then(BencodedValue::into_int)
-                    .ok_or(bterror!("piece missing"))?
-                    .into(),
-                total_size: metadata
-                    .pull(b"total_size")
-                    .and_then(BencodedValue::into_int),
-            })
-        } else {
-            Err(bterror!("Invalid extension metadata"))
-        }
+        bencode::from_value(value)
     }
 }
 
-impl From<ExtensionMetadata> for BencodedValue {
-    fn from(val: ExtensionMetadata) -> Self {
-        dict! {
-            b"msg_type" => val.msg_type,
-            b"piece" => val.piece,
-            b"total_size" => val.total_size,
-        }
+impl From<BencodedRef<'_>> for Result<ExtensionMetadata, BitTorrentError> {
+    fn from(value: BencodedRef<'_>) -> Self {
+        bencode::from_ref(value)
     }
 }
 
@@ -249,6 +269,13 @@ impl PeerMessageCodec {
             Some(20) => Ok(PeerMessage::Extension(
                 self.extension_codec.decode(&bytes[1..])?,
             )),
+            Some(21) => Ok(PeerMessage::HashRequest(HashRequestMessage::decode(
+                &bytes[1..],
+            )?)),
+            Some(22) => Ok(PeerMessage::Hashes(HashesMessage::decode(&bytes[1..])?)),
+            Some(23) => Ok(PeerMessage::HashReject(HashRequestMessage::decode(
+                &bytes[1..],
+            )?)),
             Some(byte) => Err(bterror!("Invalid peer message: {byte}")),
         }
     }
@@ -265,9 +292,12 @@ impl PeerMessageCodec {
             PeerMessage::Have(index) => once(4).chain(index.to_be_bytes()).collect(),
             PeerMessage::Bitfield(bitfield) => once(5)
                 .chain(
-                    bitfield
-                        .chunks(8)
-                        .map(|bits| encode_bitfield_be(bits.try_into().unwrap())),
+                    // the spare bits of the last byte are left unset
+                    bitfield.chunks(8).map(|bits| {
+                        let mut byte = [false; 8];
+                        byte[..bits.len()].copy_from_slice(bits);
+                        encode_bitfield_be(byte)
+                    }),
                 )
                 .collect(),
             PeerMessage::Request(req) => once(6).chain(req.encode()?).collect(),
@@ -281,6 +311,9 @@ impl PeerMessageCodec {
             PeerMessage::Extension(ext) => {
                 once(20).chain(self.extension_codec.encode(ext)?).collect()
             }
+            PeerMessage::HashRequest(req) => once(21).chain(req.encode()?).collect(),
+            PeerMessage::Hashes(hashes) => once(22).chain(hashes.encode()?).collect(),
+            PeerMessage::HashReject(req) => once(23).chain(req.encode()?).collect(),
             PeerMessage::Handshake(handshake) => return Ok(handshake.encode()),
         };
         let length = base_message.len() as u32;
@@ -319,11 +352,8 @@ impl ExtensionMessageCodec {
             .ok_or(bterror!("Extension '{}' is unsupported by peer", name))?;
         Ok(once(*code as u8)
             .chain(match message {
-                ExtensionMessage::Handshake(handshake) => {
-                    BencodedValue::from(handshake).encode()?
-                }
-                ExtensionMessage::Metadata(metadata, data) => BencodedValue::from(metadata)
-                    .encode()?
+                ExtensionMessage::Handshake(handshake) => bencode::to_bytes(&handshake)?,
+                ExtensionMessage::Metadata(metadata, data) => bencode::to_bytes(&metadata)?
                     .into_iter()
                     .chain(data.unwrap_or_default())
                     .collect::<Vec<_>>(),
@@ -340,10 +370,13 @@ impl ExtensionMessageCodec {
             .ok_or(bterror!("Unrecognized extension code: {}", code))?;
         match name.deref() {
             b"handshake" => Ok(ExtensionMessage::Handshake(<Result<_, _>>::from(
-                BencodedValue::ingest(&mut bytes)?,
+                BencodedRef::decode_with(&mut bytes, DecodeOptions::UNTRUSTED)?,
             )?)),
             b"ut_metadata" => Ok(ExtensionMessage::Metadata(
-                <Result<_, _>>::from(BencodedValue::ingest(&mut bytes)?)?,
+                <Result<_, _>>::from(BencodedRef::decode_with(
+                    &mut bytes,
+                    DecodeOptions::UNTRUSTED,
+                )?)?,
                 (!bytes.is_empty()).then_some(bytes.to_vec()),
             )),
             name => Err(bterror!(
@@ -395,6 +428,64 @@ impl Codec for RequestMessage {
     }
 }
 
+impl Codec for HashRequestMessage {
+    type Error = BitTorrentError;
+
+    fn encode(self) -> Result<Vec<u8>, Self::Error> {
+        Ok(empty()
+            .chain(self.pieces_root)
+            .chain(self.base_layer.to_be_bytes())
+            .chain(self.index.to_be_bytes())
+            .chain(self.length.to_be_bytes())
+            .chain(self.proof_layers.to_be_bytes())
+            .collect())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let field = |offset: usize| -> Result<u32, BitTorrentError> {
+            Ok(u32::from_be_bytes(
+                bytes
+                    .get(offset..offset + 4)
+                    .ok_or(bterror!("Insufficient bytes"))?
+                    .try_into()?,
+            ))
+        };
+        Ok(Self {
+            pieces_root: bytes
+                .get(0..32)
+                .ok_or(bterror!("Insufficient bytes"))?
+                .try_into()?,
+            base_layer: field(32)?,
+            index: field(36)?,
+            length: field(40)?,
+            proof_layers: field(44)?,
+        })
+    }
+}
+
+impl Codec for HashesMessage {
+    type Error = BitTorrentError;
+
+    fn encode(self) -> Result<Vec<u8>, Self::Error> {
+        Ok(empty()
+            .chain(self.request.encode()?)
+            .chain(self.hashes.concat())
+            .collect())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Self {
+            request: HashRequestMessage::decode(bytes)?,
+            hashes: bytes
+                .get(48..)
+                .ok_or(bterror!("Insufficient bytes"))?
+                .chunks(32)
+                .map(<[u8; 32]>::try_from)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
 impl Codec for PieceMessage {
     type Error = BitTorrentError;
 
@@ -460,7 +551,7 @@ impl HandshakeMessage {
             .chain(b"BitTorrent protocol".into_iter())
             .chain(&[
                 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00011000, 0b00000000,
-                0b00000101,
+                0b00010101,
             ])
             .chain(&self.info_hash)
             .chain(&self.peer_id)
@@ -468,3 +559,60 @@ impl HandshakeMessage {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Result<ExtensionHandshake, BitTorrentError> {
+        BencodedRef::decode(&mut &bytes[..])?.into()
+    }
+
+    #[test]
+    fn decodes_extension_handshakes() {
+        let handshake = decode(
+            b"d1:md11:ut_metadatai3ee13:metadata_sizei31235e1:pi6881e4:reqqi500e\
+              1:v8:qB 4.6.06:yourip4:\x7f\x00\x00\x017:x-extrai1ee",
+        )
+        .unwrap();
+        assert_eq!(
+            handshake.messages,
+            Some(HashMap::from([(Bytes::from("ut_metadata".to_string()), 3)]))
+        );
+        assert_eq!(handshake.port, Some(6881));
+        assert_eq!(handshake.version, Some(Bytes::from("qB 4.6.0".to_string())));
+        assert_eq!(handshake.yourip, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!(handshake.ipv4, None);
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.metadata_size, Some(31235));
+
+        let empty = decode(b"de").unwrap();
+        assert!(empty.messages.is_none() && empty.yourip.is_none());
+    }
+
+    #[test]
+    fn encodes_extension_handshakes() {
+        let handshake = ExtensionHandshake {
+            messages: Some(HashMap::from([(Bytes::from("ut_metadata".to_string()), 1)])),
+            port: Some(6881),
+            ipv6: Some(Ipv6Addr::LOCALHOST),
+            ..Default::default()
+        };
+        let bytes = bencode::to_bytes(&handshake).unwrap();
+        assert_eq!(
+            bytes,
+            b"d4:ipv616:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x011:md11:ut_metadatai1ee1:pi6881ee"
+        );
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.ipv6, Some(Ipv6Addr::LOCALHOST));
+        assert_eq!(decoded.messages, handshake.messages);
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        assert!(decode(b"d6:yourip3:abce").is_err());
+        assert!(decode(b"d4:ipv44:\x7f\x00\x00\x01e").is_ok());
+        assert!(decode(b"d4:ipv416:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01e").is_err());
+        assert!(decode(b"d1:pi70000ee").is_err());
+    }
+}
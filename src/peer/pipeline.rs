@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::bencode::Number;
+
+use super::blocks::BLOCK_SIZE;
+
+/// number of requests kept in flight before any throughput has been measured
+const INITIAL_DEPTH: usize = 4;
+/// fewest requests kept in flight, however slow the peer
+const MIN_DEPTH: usize = 2;
+/// request queue size assumed for peers that do not advertise `reqq`
+const DEFAULT_REQQ: usize = 250;
+/// most requests kept in flight, however large the peer's `reqq`
+const MAX_DEPTH: usize = 500;
+/// weight of the newest sample in the smoothed download rate
+const RATE_SMOOTHING: f64 = 0.2;
+/// multiple of the bandwidth-delay product kept in flight, leaving room for the rate to grow
+const HEADROOM: f64 = 2.0;
+
+/// Adaptive size of the outstanding request queue of a single connection. The queue grows
+/// towards the measured bandwidth-delay product of the peer, capped by the peer's advertised
+/// `reqq`, and is halved whenever the peer rejects a request.
+#[derive(Debug, Clone)]
+pub struct RequestPipeline {
+    /// most requests the peer is willing to queue
+    limit: usize,
+    /// number of requests to keep in flight
+    depth: usize,
+    /// smoothed download rate (bytes/s)
+    rate: f64,
+    /// lowest observed time between sending a request and receiving its block
+    min_rtt: Option<Duration>,
+    /// send times of outstanding requests, keyed by piece index and block offset
+    outstanding: HashMap<(u32, u32), Instant>,
+    /// arrival of the last block, or the first request after the queue ran empty
+    last_arrival: Option<Instant>,
+    /// total bytes of block data received
+    downloaded: usize,
+}
+
+impl Default for RequestPipeline {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_REQQ,
+            depth: INITIAL_DEPTH,
+            rate: 0.0,
+            min_rtt: None,
+            outstanding: HashMap::new(),
+            last_arrival: None,
+            downloaded: 0,
+        }
+    }
+}
+
+impl RequestPipeline {
+    /// Number of requests to keep in flight.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Total bytes of block data received over the connection.
+    pub fn downloaded(&self) -> usize {
+        self.downloaded
+    }
+
+    /// Cap the queue at the `reqq` advertised in the peer's extension handshake.
+    pub fn set_limit(&mut self, reqq: Option<Number>) {
+        self.limit = reqq
+            .map_or(DEFAULT_REQQ, |reqq| reqq.max(0) as usize)
+            .clamp(MIN_DEPTH, MAX_DEPTH);
+        self.depth = self.depth.min(self.limit);
+    }
+
+    /// Record a request for the block at `begin` of piece `index` being sent.
+    pub fn sent(&mut self, index: u32, begin: u32) {
+        let now = Instant::now();
+        self.last_arrival.get_or_insert(now);
+        self.outstanding.insert((index, begin), now);
+    }
+
+    /// Record the block at `begin` of piece `index` arriving, and resize the queue to the
+    /// bandwidth-delay product measured so far.
+    pub fn received(&mut self, index: u32, begin: u32, length: usize) {
+        let now = Instant::now();
+        self.downloaded += length;
+        let Some(sent) = self.outstanding.remove(&(index, begin)) else {
+            return;
+        };
+        let rtt = now - sent;
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+
+        // only time spent with requests in flight counts towards the rate
+        if let Some(last_arrival) = self.last_arrival {
+            let elapsed = (now - last_arrival).max(Duration::from_millis(1));
+            let sample = length as f64 / elapsed.as_secs_f64();
+            self.rate = if self.rate > 0.0 {
+                self.rate * (1.0 - RATE_SMOOTHING) + sample * RATE_SMOOTHING
+            } else {
+                sample
+            };
+        }
+        self.last_arrival = (!self.outstanding.is_empty()).then_some(now);
+
+        // grow by at most one request per block received, doubling the queue each round trip
+        // while the rate keeps up, and shrink straight to the target otherwise
+        let bdp = self.rate * self.min_rtt.unwrap_or(rtt).as_secs_f64() / BLOCK_SIZE as f64;
+        let target = ((bdp * HEADROOM).ceil() as usize).clamp(MIN_DEPTH, self.limit);
+        self.depth = target.min(self.depth + 1);
+    }
+
+    /// Record the request for the block at `begin` of piece `index` being rejected, halving
+    /// the queue.
+    pub fn rejected(&mut self, index: u32, begin: u32) {
+        self.forget(index, begin);
+        self.depth = (self.depth / 2).max(MIN_DEPTH);
+    }
+
+    /// Forget the request for the block at `begin` of piece `index`, e.g. after cancelling it.
+    pub fn forget(&mut self, index: u32, begin: u32) {
+        self.outstanding.remove(&(index, begin));
+        if self.outstanding.is_empty() {
+            self.last_arrival = None;
+        }
+    }
+
+    /// Forget every outstanding request, e.g. after the peer chokes us.
+    pub fn clear(&mut self) {
+        self.outstanding.clear();
+        self.last_arrival = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    const BLOCK: usize = BLOCK_SIZE as usize;
+
+    #[test]
+    fn caps_the_queue_at_the_peers_reqq() {
+        let mut pipeline = RequestPipeline::default();
+        assert_eq!(pipeline.depth(), INITIAL_DEPTH);
+        pipeline.set_limit(Some(3));
+        assert_eq!(pipeline.depth(), 3);
+        pipeline.set_limit(Some(-1));
+        assert_eq!((pipeline.limit, pipeline.depth()), (MIN_DEPTH, MIN_DEPTH));
+        pipeline.set_limit(Some(100_000));
+        assert_eq!((pipeline.limit, pipeline.depth()), (MAX_DEPTH, MIN_DEPTH));
+        pipeline.set_limit(None);
+        assert_eq!(pipeline.limit, DEFAULT_REQQ);
+    }
+
+    #[test]
+    fn grows_towards_the_bandwidth_delay_product() {
+        let mut pipeline = RequestPipeline::default();
+        for begin in 0..8 {
+            pipeline.sent(0, begin * BLOCK_SIZE);
+        }
+        // a long round trip, then a burst of blocks: far more than the queue fits in flight
+        sleep(Duration::from_millis(100));
+        let mut depth = pipeline.depth();
+        for begin in 0..8 {
+            pipeline.received(0, begin * BLOCK_SIZE, BLOCK);
+            assert!(pipeline.depth() <= depth + 1);
+            depth = pipeline.depth();
+        }
+        assert!(pipeline.depth() > INITIAL_DEPTH);
+        assert_eq!(pipeline.downloaded(), 8 * BLOCK);
+    }
+
+    #[test]
+    fn growth_stays_within_the_limit() {
+        let mut pipeline = RequestPipeline::default();
+        pipeline.set_limit(Some(3));
+        for begin in 0..8 {
+            pipeline.sent(0, begin * BLOCK_SIZE);
+        }
+        sleep(Duration::from_millis(100));
+        for begin in 0..8 {
+            pipeline.received(0, begin * BLOCK_SIZE, BLOCK);
+            assert!(pipeline.depth() <= 3);
+        }
+    }
+
+    #[test]
+    fn shrinks_when_round_trips_are_short() {
+        // each block arrives before the next is requested, so one request in flight suffices
+        let mut pipeline = RequestPipeline::default();
+        for begin in 0..4 {
+            pipeline.sent(0, begin * BLOCK_SIZE);
+            pipeline.received(0, begin * BLOCK_SIZE, BLOCK);
+        }
+        assert_eq!(pipeline.depth(), MIN_DEPTH);
+    }
+
+    #[test]
+    fn halves_the_queue_on_rejections() {
+        let mut pipeline = RequestPipeline {
+            depth: 100,
+            ..Default::default()
+        };
+        pipeline.sent(0, 0);
+        pipeline.rejected(0, 0);
+        assert_eq!(pipeline.depth(), 50);
+        assert!(pipeline.outstanding.is_empty());
+        for _ in 0..10 {
+            pipeline.rejected(0, 0);
+        }
+        assert_eq!(pipeline.depth(), MIN_DEPTH);
+    }
+
+    #[test]
+    fn ignores_blocks_it_did_not_request() {
+        let mut pipeline = RequestPipeline::default();
+        pipeline.sent(0, 0);
+        pipeline.sent(0, BLOCK_SIZE);
+        pipeline.forget(0, 0);
+        pipeline.received(0, 0, BLOCK);
+        assert_eq!(pipeline.depth(), INITIAL_DEPTH);
+        assert_eq!(pipeline.downloaded(), BLOCK);
+
+        pipeline.clear();
+        assert!(pipeline.outstanding.is_empty());
+        assert_eq!(pipeline.last_arrival, None);
+        pipeline.received(0, BLOCK_SIZE, BLOCK);
+        assert_eq!(pipeline.depth(), INITIAL_DEPTH);
+    }
+}
@@ -1,96 +1,850 @@
 use std::{
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    iter::{empty, once},
     net::{SocketAddr, UdpSocket},
-    sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Context;
+use crate::{bterror, error::BitTorrentError};
 
-use crate::{error::BitTorrentError, info::MetaInfo, torrent_source::TorrentSource};
+use super::{wire::WirePeer, PeerStream};
 
-use super::PeerConnection;
+/// uTP protocol version
+const VERSION: u8 = 1;
+/// size of a uTP packet header, excluding extensions (bytes)
+const HEADER_SIZE: usize = 20;
+/// extension id of the selective ack extension
+const SELECTIVE_ACK: u8 = 1;
+/// maximum payload carried by a single data packet, kept below common path MTUs (bytes)
+const MAX_PAYLOAD: usize = 1400;
+/// receive window advertised to the remote end (bytes)
+const RECEIVE_WINDOW: usize = 1 << 20;
+/// smallest congestion window the LEDBAT controller may shrink to (bytes)
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+/// congestion window used before any acks have arrived (bytes)
+const INITIAL_WINDOW: f64 = (4 * MAX_PAYLOAD) as f64;
+/// LEDBAT target queueing delay (microseconds)
+const CCONTROL_TARGET: f64 = 100_000.0;
+/// maximum congestion window growth per round trip (bytes)
+const MAX_CWND_INCREASE_BYTES_PER_RTT: f64 = 3000.0;
+/// retransmission timeout used until a round trip time has been measured
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(1000);
+/// lower bound on the retransmission timeout
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+/// time to wait for the remote end to answer a SYN before retrying
+const UTP_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+/// number of SYN packets sent before the connection attempt is abandoned
+const SYN_ATTEMPTS: usize = 3;
+/// number of times a single packet may be sent before the connection is considered lost
+const MAX_TRANSMISSIONS: usize = 6;
+/// number of duplicate acks that trigger a fast retransmit
+const DUPLICATE_ACK_THRESHOLD: usize = 3;
+/// how far ahead of the last in-order packet out-of-order packets are buffered (packets)
+const REORDER_LIMIT: u16 = 1024;
+/// length of the history the base delay is the minimum of
+const BASE_DELAY_HISTORY: Duration = Duration::from_secs(2 * 60);
 
-#[allow(unused)]
+/// uTP packet types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UtpMessage {
-    Data,
-    Fin,
-    State,
-    Reset,
-    Syn,
+    Data,  // 0
+    Fin,   // 1
+    State, // 2
+    Reset, // 3
+    Syn,   // 4
 }
 
-#[allow(unused)]
 impl UtpMessage {
-    fn encode(&self) -> Vec<u8> {
-        unimplemented!()
+    /// Encode the packet type and protocol version into the first header byte.
+    fn encode(&self) -> u8 {
+        let kind = match self {
+            UtpMessage::Data => 0,
+            UtpMessage::Fin => 1,
+            UtpMessage::State => 2,
+            UtpMessage::Reset => 3,
+            UtpMessage::Syn => 4,
+        };
+        kind << 4 | VERSION
+    }
+
+    /// Decode the packet type from the first header byte.
+    fn decode(byte: u8) -> Result<Self, BitTorrentError> {
+        if byte & 0x0f != VERSION {
+            return Err(bterror!("Unsupported uTP version: {}", byte & 0x0f));
+        }
+        match byte >> 4 {
+            0 => Ok(UtpMessage::Data),
+            1 => Ok(UtpMessage::Fin),
+            2 => Ok(UtpMessage::State),
+            3 => Ok(UtpMessage::Reset),
+            4 => Ok(UtpMessage::Syn),
+            kind => Err(bterror!("Invalid uTP packet type: {kind}")),
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct UtpPeer {
-    pub address: SocketAddr,
-    pub torrent_source: TorrentSource,
-    pub peer_id: String,
-    pub socket: UdpSocket,
-    pub bitfield: Vec<bool>,
+#[derive(Debug, Clone)]
+pub struct UtpPacket {
+    pub message: UtpMessage,
     pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    pub wnd_size: u32,
     pub seq_nr: u16,
     pub ack_nr: u16,
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl UtpPacket {
+    /// Encode a packet into a datagram.
+    pub fn encode(&self) -> Vec<u8> {
+        let extension = match self.selective_ack {
+            Some(_) => SELECTIVE_ACK,
+            None => 0,
+        };
+        empty()
+            .chain(once(self.message.encode()))
+            .chain(once(extension))
+            .chain(self.connection_id.to_be_bytes())
+            .chain(self.timestamp.to_be_bytes())
+            .chain(self.timestamp_difference.to_be_bytes())
+            .chain(self.wnd_size.to_be_bytes())
+            .chain(self.seq_nr.to_be_bytes())
+            .chain(self.ack_nr.to_be_bytes())
+            .chain(
+                self.selective_ack
+                    .iter()
+                    .flat_map(|mask| [0, mask.len() as u8].into_iter().chain(mask.clone())),
+            )
+            .chain(self.payload.iter().copied())
+            .collect()
+    }
+
+    /// Decode a packet from a datagram.
+    pub fn decode(bytes: &[u8]) -> Result<Self, BitTorrentError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(bterror!("uTP packet too short: {}", bytes.len()));
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+
+        // walk the extension chain
+        let mut selective_ack = None;
+        let mut extension = bytes[1];
+        let mut rest = &bytes[HEADER_SIZE..];
+        while extension != 0 {
+            let [next, length, ..] = *rest else {
+                return Err(bterror!("Truncated uTP extension header"));
+            };
+            let data = rest
+                .get(2..2 + length as usize)
+                .ok_or(bterror!("Truncated uTP extension"))?;
+            if extension == SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            rest = &rest[2 + length as usize..];
+        }
+
+        Ok(UtpPacket {
+            message: UtpMessage::decode(bytes[0])?,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: rest.to_vec(),
+        })
+    }
+}
+
+/// Packet that has been sent but not yet acknowledged
+#[derive(Debug)]
+struct OutgoingPacket {
+    packet: UtpPacket,
+    sent_at: Instant,
+    transmissions: usize,
+}
+
+/// Tracks the minimum one way delay seen over the recent past, in one minute buckets
+#[derive(Debug, Default)]
+struct BaseDelay {
+    buckets: VecDeque<(Instant, u32)>,
+}
+
+impl BaseDelay {
+    fn add_sample(&mut self, delay: u32) {
+        let now = Instant::now();
+        self.buckets
+            .retain(|(start, _)| now.duration_since(*start) < BASE_DELAY_HISTORY);
+        match self.buckets.back_mut() {
+            Some((start, min)) if now.duration_since(*start) < BASE_DELAY_HISTORY / 2 => {
+                *min = (*min).min(delay)
+            }
+            _ => self.buckets.push_back((now, delay)),
+        }
+    }
+
+    fn get(&self) -> Option<u32> {
+        self.buckets.iter().map(|(_, delay)| *delay).min()
+    }
+}
+
+/// Micro transport protocol stream (BEP 29), offering a reliable byte stream over udp
+/// with LEDBAT congestion control.
+#[derive(Debug)]
+pub struct UtpStream {
+    socket: UdpSocket,
+    /// connection id expected on incoming packets
+    connection_id: u16,
+    /// connection id stamped on outgoing packets
+    send_connection_id: u16,
+    /// sequence number of the next packet to be sent
+    seq_nr: u16,
+    /// sequence number of the last packet received in order
+    ack_nr: u16,
+    read_timeout: Cell<Option<Duration>>,
+    write_timeout: Cell<Option<Duration>>,
+    // outgoing state
+    send_buffer: VecDeque<OutgoingPacket>,
+    max_window: f64,
+    peer_window: usize,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    last_ack: u16,
+    duplicate_acks: usize,
+    reply_micro: u32,
+    base_delay: BaseDelay,
+    // incoming state
+    reorder_buffer: HashMap<u16, UtpPacket>,
+    read_buffer: VecDeque<u8>,
+    fin_seq_nr: Option<u16>,
+    eof: bool,
 }
 
-impl UtpPeer {}
-
-impl PeerConnection for UtpPeer {
-    type Error = BitTorrentError;
-
-    fn new(
-        peer: SocketAddr,
-        torrent_source: TorrentSource,
-        peer_id: String,
-        _port: u16,
-        _verbose: bool,
-        _killswitch: Arc<AtomicBool>,
-    ) -> Result<Self, Self::Error>
-    where
-        Self: Sized,
-    {
-        let connection = UtpPeer {
-            socket: UdpSocket::bind("0.0.0.0:0")
-                .with_context(|| "Unable to bind to local socket")?,
-            bitfield: Vec::new(),
-            connection_id: 0,
+/// Current time as a wrapping microsecond timestamp.
+fn timestamp_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u32
+}
+
+/// Check if sequence number `a` comes strictly before `b`, accounting for wraparound.
+fn seq_before(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+impl UtpStream {
+    /// Stream over `socket`, connected to the peer, as it stands before the SYN / STATE
+    /// exchange.
+    fn new(socket: UdpSocket, connection_id: u16) -> Self {
+        UtpStream {
+            socket,
+            connection_id,
+            send_connection_id: connection_id.wrapping_add(1),
             seq_nr: 1,
             ack_nr: 0,
-            address: peer,
-            torrent_source,
-            peer_id,
+            read_timeout: Cell::new(None),
+            write_timeout: Cell::new(None),
+            send_buffer: VecDeque::new(),
+            max_window: INITIAL_WINDOW,
+            peer_window: RECEIVE_WINDOW,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_TIMEOUT,
+            last_ack: 0,
+            duplicate_acks: 0,
+            reply_micro: 0,
+            base_delay: BaseDelay::default(),
+            reorder_buffer: HashMap::new(),
+            read_buffer: VecDeque::new(),
+            fin_seq_nr: None,
+            eof: false,
+        }
+    }
+
+    /// Size of the send window: the smaller of our congestion window and the peer's receive window.
+    fn window(&self) -> usize {
+        (self.max_window as usize).min(self.peer_window)
+    }
+
+    /// Number of payload bytes sent but not yet acknowledged.
+    fn bytes_in_flight(&self) -> usize {
+        self.send_buffer
+            .iter()
+            .map(|outgoing| outgoing.packet.payload.len())
+            .sum()
+    }
+
+    fn make_packet(&self, message: UtpMessage, seq_nr: u16, payload: Vec<u8>) -> UtpPacket {
+        UtpPacket {
+            message,
+            connection_id: match message {
+                UtpMessage::Syn => self.connection_id,
+                _ => self.send_connection_id,
+            },
+            timestamp: timestamp_micros(),
+            timestamp_difference: self.reply_micro,
+            wnd_size: RECEIVE_WINDOW.saturating_sub(self.read_buffer.len()) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+            selective_ack: None,
+            payload,
+        }
+    }
+
+    fn send_packet(&self, packet: &UtpPacket) -> io::Result<()> {
+        self.socket.send(&packet.encode())?;
+        Ok(())
+    }
+
+    /// Acknowledge everything received so far, reporting out of order packets with a selective ack.
+    fn send_ack(&self) -> io::Result<()> {
+        let mut packet = self.make_packet(UtpMessage::State, self.seq_nr, Vec::new());
+        if !self.reorder_buffer.is_empty() {
+            let mut mask = vec![0u8; 4];
+            for seq_nr in self.reorder_buffer.keys() {
+                let bit = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+                if bit >= mask.len() * 8 {
+                    mask.resize((bit / 32 + 1) * 4, 0);
+                }
+                mask[bit / 8] |= 1 << (bit % 8);
+            }
+            packet.selective_ack = Some(mask);
+        }
+        self.send_packet(&packet)
+    }
+
+    /// Send a data packet, blocking until the send window has room for it.
+    fn send_data(&mut self, payload: Vec<u8>, deadline: Option<Instant>) -> io::Result<()> {
+        while !self.send_buffer.is_empty() && self.bytes_in_flight() + payload.len() > self.window()
+        {
+            self.pump(deadline)?;
+        }
+        let packet = self.make_packet(UtpMessage::Data, self.seq_nr, payload);
+        self.send_packet(&packet)?;
+        self.send_buffer.push_back(OutgoingPacket {
+            packet,
+            sent_at: Instant::now(),
+            transmissions: 1,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Resend the oldest unacknowledged packet.
+    fn resend_oldest(&mut self) -> io::Result<()> {
+        let (timestamp_difference, ack_nr) = (self.reply_micro, self.ack_nr);
+        if let Some(outgoing) = self.send_buffer.front_mut() {
+            if outgoing.transmissions >= MAX_TRANSMISSIONS {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "uTP connection lost: too many retransmissions",
+                ));
+            }
+            outgoing.packet.timestamp = timestamp_micros();
+            outgoing.packet.timestamp_difference = timestamp_difference;
+            outgoing.packet.ack_nr = ack_nr;
+            outgoing.sent_at = Instant::now();
+            outgoing.transmissions += 1;
+            self.socket.send(&outgoing.packet.encode())?;
+        }
+        Ok(())
+    }
+
+    /// Time at which the oldest unacknowledged packet should be retransmitted.
+    fn retransmit_deadline(&self) -> Option<Instant> {
+        self.send_buffer
+            .front()
+            .map(|outgoing| outgoing.sent_at + self.rto)
+    }
+
+    /// Wait for and process a single incoming packet, retransmitting if the retransmission
+    /// timer fires first. Fails with `TimedOut` once `deadline` passes.
+    fn pump(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "uTP timeout"));
+        }
+        let wake = match (deadline, self.retransmit_deadline()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.socket.set_read_timeout(
+            wake.map(|wake| wake.duration_since(now).max(Duration::from_millis(1))),
+        )?;
+
+        let mut buf = [0u8; 65536];
+        match self.socket.recv(&mut buf) {
+            Ok(num_read) => match UtpPacket::decode(&buf[..num_read]) {
+                Ok(packet) => self.handle_packet(packet),
+                // ignore malformed datagrams
+                Err(_) => Ok(()),
+            },
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if self
+                    .retransmit_deadline()
+                    .is_some_and(|retransmit| Instant::now() >= retransmit)
+                {
+                    // packet loss: collapse the window and back off the timer
+                    self.max_window = MIN_WINDOW;
+                    self.rto = (self.rto * 2).min(Duration::from_secs(60));
+                    self.resend_oldest()?;
+                }
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn handle_packet(&mut self, packet: UtpPacket) -> io::Result<()> {
+        if packet.connection_id != self.connection_id {
+            return Ok(());
+        }
+        if packet.message == UtpMessage::Reset {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "uTP connection reset by peer",
+            ));
+        }
+
+        self.reply_micro = timestamp_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.wnd_size as usize;
+        self.handle_ack(&packet)?;
+
+        match packet.message {
+            UtpMessage::Data | UtpMessage::Fin => {
+                if packet.message == UtpMessage::Fin {
+                    self.fin_seq_nr = Some(packet.seq_nr);
+                }
+                let expected = self.ack_nr.wrapping_add(1);
+                if packet.seq_nr == expected {
+                    self.deliver(packet);
+                    while let Some(next) = self.reorder_buffer.remove(&self.ack_nr.wrapping_add(1))
+                    {
+                        self.deliver(next);
+                    }
+                } else if seq_before(expected, packet.seq_nr)
+                    && packet.seq_nr.wrapping_sub(expected) < REORDER_LIMIT
+                {
+                    self.reorder_buffer.insert(packet.seq_nr, packet);
+                }
+                self.send_ack()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Accept an in-order packet into the read buffer.
+    fn deliver(&mut self, packet: UtpPacket) {
+        self.ack_nr = packet.seq_nr;
+        self.read_buffer.extend(packet.payload);
+        if self.fin_seq_nr == Some(self.ack_nr) {
+            self.eof = true;
+        }
+    }
+
+    /// Process the cumulative and selective acks carried by a packet.
+    fn handle_ack(&mut self, packet: &UtpPacket) -> io::Result<()> {
+        let now = Instant::now();
+        let mut bytes_acked = 0;
+        let mut rtt_samples = Vec::new();
+        let mut acknowledge = |outgoing: OutgoingPacket| {
+            bytes_acked += outgoing.packet.payload.len();
+            if outgoing.transmissions == 1 {
+                rtt_samples.push(now.duration_since(outgoing.sent_at));
+            }
         };
 
-        connection
-            .socket
-            .connect(peer)
-            .with_context(|| "Unable to connect to peer")?;
+        // cumulative ack
+        while let Some(outgoing) = self.send_buffer.front() {
+            if seq_before(packet.ack_nr, outgoing.packet.seq_nr) {
+                break;
+            }
+            acknowledge(self.send_buffer.pop_front().unwrap());
+        }
+
+        // selective ack
+        let mut selectively_acked = 0;
+        if let Some(mask) = &packet.selective_ack {
+            let acked = |seq_nr: u16| {
+                let bit = seq_nr.wrapping_sub(packet.ack_nr).wrapping_sub(2) as usize;
+                mask.get(bit / 8)
+                    .is_some_and(|byte| byte >> (bit % 8) & 1 == 1)
+            };
+            let (acked, unacked): (Vec<_>, Vec<_>) = self
+                .send_buffer
+                .drain(..)
+                .partition(|outgoing| acked(outgoing.packet.seq_nr));
+            selectively_acked = acked.len();
+            acked.into_iter().for_each(&mut acknowledge);
+            self.send_buffer = unacked.into();
+        }
+
+        for sample in rtt_samples {
+            self.update_rtt(sample);
+        }
+
+        if bytes_acked > 0 {
+            self.duplicate_acks = 0;
+            if packet.timestamp_difference != 0 {
+                self.update_window(packet.timestamp_difference, bytes_acked);
+            }
+        } else if packet.message == UtpMessage::State
+            && packet.ack_nr == self.last_ack
+            && !self.send_buffer.is_empty()
+        {
+            self.duplicate_acks += 1;
+        }
+        self.last_ack = packet.ack_nr;
+
+        // fast retransmit
+        if self.duplicate_acks >= DUPLICATE_ACK_THRESHOLD
+            || selectively_acked >= DUPLICATE_ACK_THRESHOLD
+        {
+            self.duplicate_acks = 0;
+            self.max_window = (self.max_window / 2.0).max(MIN_WINDOW);
+            self.resend_oldest()?;
+        }
+        Ok(())
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        self.rto = (self.rtt.unwrap() + self.rtt_var * 4).max(MIN_TIMEOUT);
+    }
+
+    /// LEDBAT: grow or shrink the congestion window depending on how far the measured
+    /// queueing delay is from the target.
+    fn update_window(&mut self, delay: u32, bytes_acked: usize) {
+        self.base_delay.add_sample(delay);
+        let our_delay = delay.wrapping_sub(self.base_delay.get().unwrap_or(delay)) as f64;
+        let off_target = (CCONTROL_TARGET - our_delay) / CCONTROL_TARGET;
+        let window_factor = bytes_acked as f64 / self.max_window;
+        let scaled_gain = MAX_CWND_INCREASE_BYTES_PER_RTT * off_target * window_factor;
+        self.max_window = (self.max_window + scaled_gain).max(MIN_WINDOW);
+    }
+
+    fn deadline(timeout: &Cell<Option<Duration>>) -> Option<Instant> {
+        timeout.get().map(|timeout| Instant::now() + timeout)
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Self::deadline(&self.read_timeout);
+        while self.read_buffer.is_empty() && !self.eof {
+            self.pump(deadline)?;
+        }
+        let num_read = buf.len().min(self.read_buffer.len());
+        for (dest, byte) in buf.iter_mut().zip(self.read_buffer.drain(..num_read)) {
+            *dest = byte;
+        }
+        Ok(num_read)
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let deadline = Self::deadline(&self.write_timeout);
+        for chunk in buf.chunks(MAX_PAYLOAD) {
+            self.send_data(chunk.to_vec(), deadline)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let deadline = Self::deadline(&self.write_timeout);
+        while !self.send_buffer.is_empty() {
+            self.pump(deadline)?;
+        }
+        Ok(())
+    }
+}
+
+impl PeerStream for UtpStream {
+    /// Connect to a peer, performing the SYN / STATE exchange.
+    fn connect(address: &SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(match address {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        })?;
+        socket.connect(address)?;
 
-        Ok(connection)
+        let connection_id: u16 = rand::random();
+        let mut stream = UtpStream::new(socket, connection_id);
+
+        let syn = stream.make_packet(UtpMessage::Syn, stream.seq_nr, Vec::new());
+        for _ in 0..SYN_ATTEMPTS {
+            let sent_at = Instant::now();
+            stream.send_packet(&syn)?;
+            let deadline = sent_at + UTP_CONNECTION_TIMEOUT;
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                stream
+                    .socket
+                    .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+                let mut buf = [0u8; 65536];
+                let num_read = match stream.socket.recv(&mut buf) {
+                    Ok(num_read) => num_read,
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        break
+                    }
+                    Err(err) => return Err(err),
+                };
+                let Ok(packet) = UtpPacket::decode(&buf[..num_read]) else {
+                    continue;
+                };
+                match packet.message {
+                    UtpMessage::State
+                        if packet.connection_id == connection_id && packet.ack_nr == syn.seq_nr =>
+                    {
+                        // the responder's first data packet reuses the sequence number of its STATE
+                        stream.ack_nr = packet.seq_nr.wrapping_sub(1);
+                        stream.seq_nr = stream.seq_nr.wrapping_add(1);
+                        stream.last_ack = packet.ack_nr;
+                        stream.peer_window = packet.wnd_size as usize;
+                        stream.reply_micro = timestamp_micros().wrapping_sub(packet.timestamp);
+                        stream.update_rtt(sent_at.elapsed());
+                        return Ok(stream);
+                    }
+                    UtpMessage::Reset if packet.connection_id == connection_id => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionRefused,
+                            "uTP connection refused",
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "uTP connection timed out",
+        ))
     }
 
-    #[allow(unused)]
-    fn download_piece(&mut self, piece_id: u32) -> Result<Vec<u8>, Self::Error> {
-        unimplemented!()
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout.set(timeout);
+        Ok(())
     }
 
-    fn address(&self) -> &SocketAddr {
-        &self.address
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.write_timeout.set(timeout);
+        Ok(())
     }
 
-    fn meta_info(&self) -> Option<&MetaInfo> {
-        unimplemented!()
+    fn sever(&self) -> io::Result<()> {
+        self.send_packet(&self.make_packet(UtpMessage::Fin, self.seq_nr, Vec::new()))
     }
+}
+
+pub type UtpPeer = WirePeer<UtpStream>;
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
 
-    fn bitfield(&self) -> &Vec<bool> {
-        &self.bitfield
+    fn packet(message: UtpMessage, seq_nr: u16, payload: &[u8]) -> UtpPacket {
+        UtpPacket {
+            message,
+            connection_id: 0x1234,
+            timestamp: 0xdeadbeef,
+            timestamp_difference: 7,
+            wnd_size: RECEIVE_WINDOW as u32,
+            seq_nr,
+            ack_nr: seq_nr.wrapping_sub(1),
+            selective_ack: None,
+            payload: payload.to_vec(),
+        }
     }
 
-    fn sever(&self) -> Result<(), Self::Error> {
-        unimplemented!()
+    /// Two streams over loopback sockets connected to each other, as if the SYN / STATE
+    /// exchange had just completed, with the sender's sequence numbers about to wrap around.
+    fn pair(seq_nr: u16) -> (UtpStream, UtpStream) {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        receiver.connect(sender.local_addr().unwrap()).unwrap();
+
+        let mut sender = UtpStream::new(sender, 100);
+        sender.seq_nr = seq_nr;
+        let mut receiver = UtpStream::new(receiver, 101);
+        receiver.send_connection_id = 100;
+        receiver.ack_nr = seq_nr.wrapping_sub(1);
+        for stream in [&sender, &receiver] {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream
+                .set_write_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        (sender, receiver)
+    }
+
+    #[test]
+    fn encodes_packet_headers() {
+        let mut syn = packet(UtpMessage::Syn, 0x0102, b"");
+        syn.ack_nr = 0x0304;
+        assert_eq!(
+            syn.encode(),
+            [0x41, 0, 0x12, 0x34, 0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 7, 0, 0x10, 0, 0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn round_trips_packets() {
+        let mut data = packet(UtpMessage::Data, 65535, b"hello");
+        data.selective_ack = Some(vec![0b101, 0, 0, 0x80]);
+        let bytes = data.encode();
+        assert_eq!(bytes.len(), HEADER_SIZE + 2 + 4 + 5);
+
+        let decoded = UtpPacket::decode(&bytes).unwrap();
+        assert_eq!(decoded.message, UtpMessage::Data);
+        assert_eq!(decoded.connection_id, 0x1234);
+        assert_eq!(decoded.timestamp, 0xdeadbeef);
+        assert_eq!(decoded.timestamp_difference, 7);
+        assert_eq!(decoded.wnd_size, RECEIVE_WINDOW as u32);
+        assert_eq!((decoded.seq_nr, decoded.ack_nr), (65535, 65534));
+        assert_eq!(decoded.selective_ack, data.selective_ack);
+        assert_eq!(decoded.payload, b"hello");
+
+        for message in [
+            UtpMessage::Fin,
+            UtpMessage::State,
+            UtpMessage::Reset,
+            UtpMessage::Syn,
+        ] {
+            let bytes = packet(message, 1, b"").encode();
+            assert_eq!(UtpPacket::decode(&bytes).unwrap().message, message);
+        }
+    }
+
+    #[test]
+    fn skips_unknown_extensions() {
+        let mut bytes = packet(UtpMessage::State, 1, b"").encode();
+        bytes[1] = 2;
+        bytes.extend([SELECTIVE_ACK, 2, 0xaa, 0xbb, 0, 4, 1, 2, 3, 4, b'x']);
+        let decoded = UtpPacket::decode(&bytes).unwrap();
+        assert_eq!(decoded.selective_ack, Some(vec![1, 2, 3, 4]));
+        assert_eq!(decoded.payload, b"x");
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let bytes = packet(UtpMessage::Data, 1, b"").encode();
+        assert!(UtpPacket::decode(&bytes[..HEADER_SIZE - 1]).is_err());
+
+        let mut bad_version = bytes.clone();
+        bad_version[0] = 0x02;
+        assert!(UtpPacket::decode(&bad_version).is_err());
+
+        let mut bad_type = bytes.clone();
+        bad_type[0] = 0x51;
+        assert!(UtpPacket::decode(&bad_type).is_err());
+
+        let mut truncated = bytes.clone();
+        truncated[1] = SELECTIVE_ACK;
+        assert!(UtpPacket::decode(&truncated).is_err());
+        truncated.extend([0, 4, 0xff]);
+        assert!(UtpPacket::decode(&truncated).is_err());
+    }
+
+    #[test]
+    fn orders_sequence_numbers_across_wraparound() {
+        assert!(seq_before(1, 2));
+        assert!(!seq_before(2, 1));
+        assert!(!seq_before(5, 5));
+        assert!(seq_before(65535, 0));
+        assert!(!seq_before(0, 65535));
+        assert!(seq_before(65000, 100));
+        assert!(seq_before(0, 0x7fff));
+        assert!(!seq_before(0, 0x8000));
+    }
+
+    #[test]
+    fn reorders_packets_across_wraparound() {
+        let (sender, mut receiver) = pair(65535);
+        let ack = |receiver: &mut UtpStream, packet: UtpPacket| {
+            receiver.handle_packet(packet).unwrap();
+            let mut buf = [0; 1500];
+            let num_read = sender.socket.recv(&mut buf).unwrap();
+            UtpPacket::decode(&buf[..num_read]).unwrap()
+        };
+        let data = |seq_nr: u16, payload: &[u8]| UtpPacket {
+            connection_id: 101,
+            ..packet(UtpMessage::Data, seq_nr, payload)
+        };
+
+        // packets past the one expected are held back and reported in a selective ack
+        let state = ack(&mut receiver, data(1, b"c"));
+        assert_eq!(state.ack_nr, 65534);
+        assert_eq!(state.selective_ack, Some(vec![0b10, 0, 0, 0]));
+        let state = ack(&mut receiver, data(0, b"b"));
+        assert_eq!(state.selective_ack, Some(vec![0b11, 0, 0, 0]));
+        assert!(receiver.read_buffer.is_empty());
+
+        // the missing packet releases the rest in order
+        let state = ack(&mut receiver, data(65535, b"a"));
+        assert_eq!(state.ack_nr, 1);
+        assert_eq!(state.selective_ack, None);
+        assert_eq!(receiver.read_buffer, b"abc");
+
+        // duplicates and packets too far ahead are dropped
+        ack(&mut receiver, data(0, b"b"));
+        ack(&mut receiver, data(1 + REORDER_LIMIT + 1, b"z"));
+        assert!(receiver.reorder_buffer.is_empty());
+        assert_eq!(receiver.read_buffer, b"abc");
+    }
+
+    #[test]
+    fn transfers_data_across_wraparound() {
+        let (mut sender, mut receiver) = pair(65530);
+        let data = (0..MAX_PAYLOAD * 20)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let reader = thread::spawn(move || {
+            let mut received = vec![0; MAX_PAYLOAD * 20];
+            receiver.read_exact(&mut received).unwrap();
+            (receiver, received)
+        });
+        sender.write_all(&data).unwrap();
+        sender.flush().unwrap();
+        let (receiver, received) = reader.join().unwrap();
+
+        assert_eq!(received, data);
+        assert_eq!(sender.seq_nr, 65530u16.wrapping_add(20));
+        assert_eq!(receiver.ack_nr, sender.seq_nr.wrapping_sub(1));
+        assert!(sender.send_buffer.is_empty());
     }
 }
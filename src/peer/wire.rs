@@ -0,0 +1,715 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use std::default::Default;
+
+use crate::{
+    bencode::{
+        borrowed::{BencodedRef, DecodeOptions},
+        BencodedValue, Number,
+    },
+    bterror, bytes,
+    bytes::Bytes,
+    error::BitTorrentError,
+    info::{
+        merkle::{self, MERKLE_BLOCK_SIZE},
+        MetaInfo,
+    },
+    storage::Geometry,
+    torrent_source::TorrentSource,
+    util::{bytes_to_hex, cap_length, sha1_hash, timestr},
+};
+
+use super::{
+    blocks::{PieceBlocks, BLOCK_SIZE},
+    message::{
+        ExtensionHandshake, ExtensionMessage, ExtensionMetadata, HandshakeMessage,
+        HashRequestMessage, HashesMessage, PeerMessage, PeerMessageCodec, RequestMessage,
+    },
+    pipeline::RequestPipeline,
+    NextPiece, PeerConnection, PeerStream,
+};
+
+/// timeout while waiting for a peer message to arrive, or while attempting to write to a peer's buffer
+const READWRITE_TIMEOUT: Duration = Duration::from_secs(60);
+/// maximum number of allowed rejections before the peer is disconnected
+const MAX_REJECTIONS: usize = 64;
+/// largest info dict accepted from a peer (bytes)
+const MAX_METADATA_SIZE: usize = 1 << 24;
+/// limits for decoding an info dict received from a peer, which may well be larger than any
+/// other message
+const METADATA_DECODE_OPTIONS: DecodeOptions = DecodeOptions {
+    max_size: MAX_METADATA_SIZE,
+    ..DecodeOptions::UNTRUSTED
+};
+/// most hashes asked for in a single hash request
+const MAX_HASH_REQUEST: usize = 512;
+/// supported extension message codes
+const EXTENSION_CONFIG: &[(&[u8], u8)] = &[
+    // ("ut_pex", 1),
+    (b"ut_metadata", 2),
+    // ("upload_only", 3),
+    // ("ut_holepunch", 4),
+    // ("lt_donthave", 7),
+    // ("share_mode", 8),
+];
+
+lazy_static! {
+    pub static ref CODEC_EXTENSION_CONFIG: Vec<(Bytes, Number)> = EXTENSION_CONFIG
+        .iter()
+        .map(|(k, v)| (Bytes::from(*k), *v as Number))
+        .collect::<Vec<_>>();
+    pub static ref HANDSHAKE_EXTENSION_CONFIG: HashMap<Bytes, Number> = CODEC_EXTENSION_CONFIG
+        .iter()
+        .cloned()
+        .collect::<HashMap<_, _>>();
+}
+
+/// Peer wire protocol connection, carried over any reliable `PeerStream` transport
+#[derive(Debug)]
+pub struct WirePeer<S: PeerStream> {
+    #[allow(unused)]
+    pub address: SocketAddr,
+    pub torrent_source: TorrentSource,
+    pub peer_id: String,
+    pub stream: S,
+    pub bitfield: Vec<bool>,
+    pub port: u16,
+    pub verbose: bool,
+    pub timeout: Option<Duration>,
+    pub killswitch: Arc<AtomicBool>,
+    pub encoder: PeerMessageCodec,
+    pub decoder: PeerMessageCodec,
+    pub choked: bool,
+    pub pipeline: RequestPipeline,
+    /// pieces being downloaded from the peer, and whether each is in endgame mode
+    pub pieces: Vec<(Arc<PieceBlocks>, bool)>,
+    /// piece index and offset of the blocks currently requested from the peer
+    pub requested: Vec<(u32, u32)>,
+}
+
+impl<S: PeerStream> WirePeer<S> {
+    /// Wait for a peer message to arrive from the peer and return it.
+    pub fn await_peer_message(&mut self) -> Result<PeerMessage, BitTorrentError> {
+        self.log("<...<");
+        let buf = self.read_n_bytes(4)?;
+        match buf.get(..4) {
+            Some(b"\x13Bit") => {
+                let rest_of_handshake = self.read_n_bytes(64)?;
+                let handshake = PeerMessage::Handshake(HandshakeMessage::decode(
+                    &buf.into_iter().chain(rest_of_handshake).collect::<Vec<_>>(),
+                )?);
+                self.log(cap_length(format!("<-<-< {handshake:?}"), 106));
+                Ok(handshake)
+            }
+            Some(buf) => {
+                let length = u32::from_be_bytes(buf.try_into().unwrap());
+                self.log(format!("<.<.< {length}"));
+                let buf: Vec<u8> = self.read_n_bytes(length as usize)?;
+                // println!("{}", pretty_print_hex(&buf));
+                let response = self.decoder.decode(&buf)?;
+                self.log(cap_length(format!("<<<<< {response:?}"), 106));
+                Ok(response)
+            }
+            None => unreachable!(),
+        }
+    }
+
+    /// Send a peer message `message` to the peer.
+    pub fn send_peer_message(&mut self, message: PeerMessage) -> Result<(), BitTorrentError> {
+        self.log(format!(">...> {:?}", message));
+        self.stream
+            .write(&self.encoder.encode(message)?)
+            .with_context(|| "Error sending peer message")?;
+        self.log(">>>>>");
+        Ok(())
+    }
+
+    /// Send a handshake message to the peer.
+    pub fn handshake(&mut self) -> Result<HandshakeMessage, BitTorrentError> {
+        self.log("Sending handshake");
+        self.stream
+            .write(&HandshakeMessage::new(&self.torrent_source, &self.peer_id)?.encode())
+            .with_context(|| "Unable to write to peer")?;
+        self.log("Waiting for handshake response");
+        let buf = self.read_n_bytes(68)?;
+        let handshake = HandshakeMessage::decode(&buf)?;
+        self.log(format!("Handshake response: {:?}", handshake));
+        Ok(handshake)
+    }
+
+    /// Request and receive blocks of a piece until every block is received, keeping as many
+    /// requests outstanding as the connection's pipeline allows. Once every block of the pieces
+    /// being downloaded has been requested, another piece is taken from `next`, so that the
+    /// queue doesn't run dry at the end of a piece; its requests are left outstanding for the
+    /// calls that follow. Returns early, leaving the rest of the piece to other connections, if
+    /// the peer chokes us.
+    fn request_blocks(
+        &mut self,
+        blocks: &Arc<PieceBlocks>,
+        endgame: bool,
+        next: &mut NextPiece<Self>,
+    ) -> Result<(), BitTorrentError> {
+        let piece_id = blocks.piece_id;
+        if self.piece(piece_id).is_none() {
+            self.pieces.insert(0, (blocks.clone(), endgame));
+            self.request_block_hashes(blocks)?;
+        }
+        let mut rejections = 0;
+
+        while !blocks.is_complete() {
+            // cancel requests for blocks that have since arrived from other peers
+            self.cancel_received()?;
+
+            // send requests that may be sent, earlier pieces first, taking on at most one more
+            // piece at a time
+            let mut taken_next = false;
+            while self.requested.len() < self.pipeline.depth() && !self.choked {
+                let claimed = self.pieces.iter().find_map(|(piece, endgame)| {
+                    piece
+                        .claim(&self.address, *endgame)
+                        .map(|(begin, length)| (piece.piece_id, begin, length))
+                });
+                let Some((index, begin, length)) = claimed else {
+                    match (taken_next, next(self)) {
+                        (false, Some(piece)) => {
+                            self.request_block_hashes(&piece.0)?;
+                            self.pieces.push(piece);
+                            taken_next = true;
+                            continue;
+                        }
+                        _ => break,
+                    }
+                };
+                self.send_peer_message(PeerMessage::Request(RequestMessage {
+                    index,
+                    begin,
+                    length,
+                }))?;
+                self.pipeline.sent(index, begin);
+                self.requested.push((index, begin));
+            }
+
+            // every remaining block is being fetched by other connections
+            if !self.requested.iter().any(|(index, _)| *index == piece_id) && !self.choked {
+                return Ok(());
+            }
+
+            // respond to incoming data
+            match self.await_peer_message()? {
+                PeerMessage::Piece(piece) => {
+                    if let Some(blocks) = self.piece(piece.index) {
+                        self.requested
+                            .retain(|request| *request != (piece.index, piece.begin));
+                        self.pipeline
+                            .received(piece.index, piece.begin, piece.block.len());
+                        blocks.receive(&self.address, piece.begin, piece.block)?;
+                    }
+                }
+                PeerMessage::Hashes(hashes) => self.receive_block_hashes(hashes)?,
+                PeerMessage::RejectRequest(request) => {
+                    if let Some(blocks) = self.piece(request.index) {
+                        self.requested
+                            .retain(|requested| *requested != (request.index, request.begin));
+                        self.pipeline.rejected(request.index, request.begin);
+                        blocks.release(&self.address, request.begin);
+                        rejections += 1;
+                        if rejections >= MAX_REJECTIONS {
+                            return Err(bterror!("Too many rejections"));
+                        }
+                    }
+                }
+                PeerMessage::Choke => {
+                    // outstanding requests are discarded by the peer when it chokes us, so
+                    // hand them back for other connections to pick up
+                    self.choked = true;
+                    self.pipeline.clear();
+                    for (index, begin) in std::mem::take(&mut self.requested) {
+                        if let Some(blocks) = self.piece(index) {
+                            blocks.release(&self.address, begin);
+                        }
+                    }
+                    return Ok(());
+                }
+                PeerMessage::Unchoke => {
+                    self.choked = false;
+                }
+                PeerMessage::Have(index) => {
+                    if let Some(has) = self.bitfield.get_mut(index as usize) {
+                        *has = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.cancel_received()
+    }
+
+    /// Request the piece layers missing from `meta_info` from the peer, a run of hashes at a
+    /// time, checking each layer against the pieces root of its file.
+    fn fetch_piece_layers(&mut self, meta_info: &mut MetaInfo) -> Result<(), BitTorrentError> {
+        let piece_length = meta_info.info.piece_length;
+        let base_layer = (piece_length / MERKLE_BLOCK_SIZE).trailing_zeros();
+        let missing = meta_info
+            .info
+            .file_tree
+            .iter()
+            .filter(|file| file.length > piece_length)
+            .filter_map(|file| Some((file.pieces_root?, file.length.div_ceil(piece_length))))
+            .filter(|(root, _)| !meta_info.piece_layers.contains_key(root))
+            .collect::<Vec<_>>();
+
+        for (pieces_root, pieces) in missing {
+            self.log(format!(
+                "Requesting piece layer {}",
+                bytes_to_hex(&pieces_root)
+            ));
+            let length = pieces.next_power_of_two().min(MAX_HASH_REQUEST);
+            let mut layer = Vec::new();
+            for index in (0..pieces).step_by(length) {
+                let request = HashRequestMessage {
+                    pieces_root,
+                    base_layer,
+                    index: index as u32,
+                    length: length as u32,
+                    proof_layers: 0,
+                };
+                self.send_peer_message(PeerMessage::HashRequest(request.clone()))?;
+                loop {
+                    match self.await_peer_message()? {
+                        PeerMessage::Hashes(hashes) if hashes.request == request => {
+                            if hashes.hashes.len() != length {
+                                return Err(bterror!("Peer sent {} hashes", hashes.hashes.len()));
+                            }
+                            layer.extend(hashes.hashes);
+                            break;
+                        }
+                        PeerMessage::HashReject(rejected) if rejected == request => {
+                            return Err(bterror!("Peer rejected a piece layer request"));
+                        }
+                        PeerMessage::Choke => self.choked = true,
+                        PeerMessage::Unchoke => self.choked = false,
+                        PeerMessage::Have(index) => {
+                            if let Some(has) = self.bitfield.get_mut(index as usize) {
+                                *has = true;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            // the hashes past the end of the layer are padding
+            layer.truncate(pieces);
+            if merkle::layer_root(&layer, piece_length) != pieces_root {
+                return Err(bterror!("Piece layer does not match its pieces root"));
+            }
+            meta_info.piece_layers.insert(pieces_root, layer);
+        }
+        Ok(())
+    }
+
+    /// Ask the peer for the hashes of the blocks of a piece of a v2-only torrent, to check
+    /// every block of the piece as it arrives, unless they are known already.
+    fn request_block_hashes(&mut self, blocks: &PieceBlocks) -> Result<(), BitTorrentError> {
+        let Some(meta_info) = self.meta_info().filter(|meta_info| !meta_info.is_v1()) else {
+            return Ok(());
+        };
+        let subtree = match meta_info.piece_subtree(blocks.piece_id as usize) {
+            Some(subtree) if subtree.width > 1 && !blocks.has_hashes() => subtree,
+            _ => return Ok(()),
+        };
+        self.send_peer_message(PeerMessage::HashRequest(HashRequestMessage {
+            pieces_root: subtree.pieces_root,
+            base_layer: 0,
+            index: subtree.first_block as u32,
+            length: subtree.width as u32,
+            proof_layers: 0,
+        }))
+    }
+
+    /// Check the block hashes sent by the peer against the hash of their piece, and hand them
+    /// to the piece to check its blocks with.
+    fn receive_block_hashes(&mut self, message: HashesMessage) -> Result<(), BitTorrentError> {
+        let request = &message.request;
+        let Some(meta_info) = self.meta_info() else {
+            return Ok(());
+        };
+        // hashes for a piece no longer being downloaded are of no use
+        let Some((blocks, subtree)) = self.pieces.iter().find_map(|(piece, _)| {
+            let subtree = meta_info.piece_subtree(piece.piece_id as usize)?;
+            let matches = request.base_layer == 0
+                && subtree.pieces_root == request.pieces_root
+                && subtree.first_block == request.index as usize
+                && subtree.width == request.length as usize;
+            matches.then(|| (piece.clone(), subtree))
+        }) else {
+            return Ok(());
+        };
+        if message.hashes.len() != subtree.width
+            || subtree.root
+                != Some(merkle::root(
+                    &message.hashes,
+                    subtree.width,
+                    merkle::ZERO_HASH,
+                ))
+        {
+            return Err(bterror!(
+                "Block hashes do not match piece {}",
+                blocks.piece_id
+            ));
+        }
+        let dropped = blocks.set_hashes(message.hashes);
+        if dropped > 0 {
+            self.log(format!(
+                "Dropped {dropped} bad blocks of piece {}",
+                blocks.piece_id
+            ));
+        }
+        Ok(())
+    }
+
+    /// Piece with index `index` among the pieces being downloaded from the peer.
+    fn piece(&self, index: u32) -> Option<Arc<PieceBlocks>> {
+        self.pieces
+            .iter()
+            .find(|(piece, _)| piece.piece_id == index)
+            .map(|(piece, _)| piece.clone())
+    }
+
+    /// Send a `Cancel` for every outstanding request whose block has already been received.
+    fn cancel_received(&mut self) -> Result<(), BitTorrentError> {
+        let received = self
+            .requested
+            .iter()
+            .filter_map(|&(index, begin)| {
+                let blocks = self.piece(index)?;
+                blocks
+                    .is_received(begin)
+                    .then(|| (index, begin, (blocks.length - begin).min(BLOCK_SIZE)))
+            })
+            .collect::<Vec<_>>();
+        for (index, begin, length) in received {
+            self.requested.retain(|request| *request != (index, begin));
+            self.pipeline.forget(index, begin);
+            self.send_peer_message(PeerMessage::Cancel(RequestMessage {
+                index,
+                begin,
+                length,
+            }))?;
+        }
+        Ok(())
+    }
+
+    fn log(&self, message: impl Display) {
+        if self.verbose {
+            println!("[{}][{}] {}", timestr(), self.address, message);
+        }
+    }
+
+    pub fn read_n_bytes(&mut self, mut n: usize) -> Result<Vec<u8>, BitTorrentError> {
+        let deadline = self.timeout.map(|timeout| SystemTime::now() + timeout);
+        let mut bytes = Vec::new();
+        while n > 0 {
+            if self.killswitch.load(atomic::Ordering::Relaxed) {
+                return Err(bterror!("Peer killed"));
+            }
+            let timeout = deadline.map(|deadline| {
+                deadline
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .max(Duration::from_millis(1))
+            });
+            self.stream.set_read_timeout(timeout)?;
+            let mut buf = vec![0u8; n];
+            let num_read = self
+                .stream
+                .read(&mut buf)
+                .with_context(|| "Error reading peer stream")?;
+            if num_read == 0 {
+                if deadline.map_or(false, |deadline| SystemTime::now() > deadline) {
+                    return Err(bterror!("Peer read timeout"));
+                }
+            } else {
+                bytes.extend(&buf[..num_read]);
+                n -= num_read;
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+impl<S: PeerStream> PeerConnection for WirePeer<S> {
+    type Error = BitTorrentError;
+
+    /// Create a new peer connection.
+    fn new(
+        peer: SocketAddr,
+        torrent_source: TorrentSource,
+        peer_id: String,
+        port: u16,
+        verbose: bool,
+        killswitch: Arc<AtomicBool>,
+    ) -> Result<Self, BitTorrentError> {
+        let handshake = PeerMessage::Handshake(HandshakeMessage::new(&torrent_source, &peer_id)?);
+
+        let mut connection = WirePeer {
+            address: peer,
+            stream: S::connect(&peer).with_context(|| "Error connecting to peer")?,
+            torrent_source,
+            peer_id,
+            bitfield: vec![],
+            port,
+            verbose,
+            timeout: Some(READWRITE_TIMEOUT),
+            killswitch,
+            encoder: PeerMessageCodec::new(CODEC_EXTENSION_CONFIG.clone()),
+            decoder: PeerMessageCodec::default(),
+            choked: true,
+            pipeline: RequestPipeline::default(),
+            pieces: Vec::new(),
+            requested: Vec::new(),
+        };
+
+        connection.stream.set_read_timeout(connection.timeout)?;
+        connection.stream.set_write_timeout(connection.timeout)?;
+
+        connection.log("Connection established");
+
+        let mut bitfield_source: Option<Box<dyn Iterator<Item = bool>>> = None;
+        let meta_info = match &connection.torrent_source {
+            TorrentSource::File(meta_info) => Some(meta_info.clone()),
+            TorrentSource::Magnet(_) => None,
+        };
+        let mut meta_info_pieces = Vec::new();
+        let mut meta_info_acquired = meta_info.is_some();
+        let mut recieved_extension_handshake = false;
+        let mut sent_initial_metadata_request = false;
+
+        // send opening handshake
+        connection.send_peer_message(handshake)?;
+
+        // listen for peer messages until initialization is complete
+        loop {
+            match connection.await_peer_message()? {
+                PeerMessage::Handshake(handshake) => {
+                    // send extension handshake
+                    connection.log(format!("{:?}", handshake));
+                    connection.send_peer_message(PeerMessage::Extension(
+                        ExtensionMessage::Handshake(ExtensionHandshake {
+                            messages: Some(HANDSHAKE_EXTENSION_CONFIG.clone()),
+                            version: Some(bytes!(b"MaurdekyeBitTorrent/1.0.0")),
+                            yourip: Some(peer.ip().into()),
+                            reqq: Some(500),
+                            ..Default::default()
+                        }),
+                    ))?;
+                }
+                PeerMessage::Extension(ExtensionMessage::Handshake(handshake)) => {
+                    connection.decoder = PeerMessageCodec::from_handshake(&handshake)?;
+                    connection.pipeline.set_limit(handshake.reqq);
+                    connection.log(format!("{:#?}", handshake));
+                    recieved_extension_handshake = true;
+                }
+                PeerMessage::Bitfield(bitfield) => {
+                    bitfield_source = Some(Box::new(bitfield.into_iter()))
+                }
+                PeerMessage::HaveAll => bitfield_source = Some(Box::new((0..).map(|_| true))),
+                PeerMessage::HaveNone => {
+                    // peer has no pieces, send notinterested and close the connection
+                    connection.send_peer_message(PeerMessage::NotInterested)?;
+                    return Err(bterror!("Peer has no data"));
+                }
+                PeerMessage::Extension(ExtensionMessage::Metadata(
+                    ExtensionMetadata {
+                        msg_type: 1,
+                        total_size: Some(total_size),
+                        ..
+                    },
+                    Some(data),
+                )) => {
+                    if total_size < 0 || total_size as usize > MAX_METADATA_SIZE {
+                        return Err(bterror!("Metadata of {total_size} bytes is too large"));
+                    }
+                    meta_info_pieces.push(data);
+                    if meta_info_pieces.iter().flatten().count() < total_size as usize {
+                        // not all pieces acquired, ask for more
+                        connection.send_peer_message(PeerMessage::Extension(
+                            ExtensionMessage::Metadata(
+                                ExtensionMetadata {
+                                    msg_type: 0,
+                                    piece: meta_info_pieces.len() as Number,
+                                    total_size: None,
+                                },
+                                None,
+                            ),
+                        ))?;
+                    } else {
+                        // mark meta_info as acquired
+                        meta_info_acquired = true;
+                    }
+                }
+                PeerMessage::Choke => connection.choked = true,
+                PeerMessage::Unchoke => connection.choked = false,
+                _ => (),
+            }
+
+            // request meta_info
+            if recieved_extension_handshake
+                && meta_info.is_none()
+                && !sent_initial_metadata_request
+            {
+                connection.send_peer_message(PeerMessage::Extension(
+                    ExtensionMessage::Metadata(
+                        ExtensionMetadata {
+                            msg_type: 0,
+                            piece: 0,
+                            total_size: None,
+                        },
+                        None,
+                    ),
+                ))?;
+                sent_initial_metadata_request = true;
+            }
+
+            // send interested & exit listen loop
+            if bitfield_source.is_some() && meta_info_acquired {
+                connection.send_peer_message(PeerMessage::Interested)?;
+                break;
+            }
+        }
+
+        // construct meta_info
+        let meta_info = match meta_info {
+            Some(meta_info) => meta_info,
+            None => {
+                let info_bytes = meta_info_pieces.into_iter().flatten().collect::<Vec<_>>();
+                MetaInfo {
+                    announce: None,
+                    announce_list: Vec::new(),
+                    info: <Result<_, _>>::from(BencodedValue::from(BencodedRef::decode_with(
+                        &mut &info_bytes[..],
+                        METADATA_DECODE_OPTIONS,
+                    )?))?,
+                    comment: None,
+                    created_by: None,
+                    creation_date: None,
+                    url_list: Vec::new(),
+                    piece_layers: HashMap::new(),
+                    info_bytes: Some(Bytes(info_bytes)),
+                    extra: HashMap::new(),
+                }
+            }
+        };
+
+        if let Some(bitfield_source) = bitfield_source {
+            connection.bitfield = bitfield_source.take(meta_info.piece_count()).collect();
+        } else {
+            unreachable!()
+        }
+
+        // pieces of v2-only torrents can only be checked once the piece layers are known, which
+        // meta info from a magnet link lacks
+        let mut meta_info = meta_info;
+        if !meta_info.is_v1() {
+            connection.fetch_piece_layers(&mut meta_info)?;
+        }
+        connection.torrent_source = TorrentSource::File(meta_info);
+
+        Ok(connection)
+    }
+
+    /// Download a piece of the file, with `piece_id` corresponding to the piece to download.
+    fn download_piece(&mut self, piece_id: u32) -> Result<Vec<u8>, BitTorrentError> {
+        let meta_info = self
+            .meta_info()
+            .ok_or(bterror!("Can't download a file without meta info!"))?;
+        let piece_length = meta_info.piece_size(piece_id as usize) as u32;
+
+        let blocks = Arc::new(PieceBlocks::new(piece_id, piece_length));
+        self.download_blocks(&blocks, false, &mut |_| None)?;
+        let mut full_piece = blocks
+            .assemble()
+            .ok_or(bterror!("Piece {piece_id} is incomplete"))?;
+
+        // check hash
+        let meta_info = self
+            .meta_info()
+            .ok_or(bterror!("Can't download a file without meta info!"))?;
+        if !meta_info.check_piece(piece_id as usize, &full_piece) {
+            Err(match meta_info.info.pieces.get(piece_id as usize) {
+                Some(hash) => bterror!(
+                    "Piece hash mismatch: meta info hash: {}, actual hash: {}",
+                    bytes_to_hex(hash),
+                    bytes_to_hex(&sha1_hash(&full_piece))
+                ),
+                None => bterror!("Piece {piece_id} does not match its merkle tree"),
+            })
+        } else {
+            // fill in the padding at the end of a file of a v2-only torrent
+            full_piece.resize(Geometry::new(meta_info).piece_range(piece_id as usize).1, 0);
+            self.have(piece_id)?;
+            Ok(full_piece)
+        }
+    }
+
+    /// Download the missing blocks of a piece, releasing any blocks still claimed from this
+    /// peer, of this piece and the ones taken on after it, if the download fails.
+    fn download_blocks(
+        &mut self,
+        blocks: &Arc<PieceBlocks>,
+        endgame: bool,
+        next: &mut NextPiece<Self>,
+    ) -> Result<(), BitTorrentError> {
+        let result = self.request_blocks(blocks, endgame, next);
+        if result.is_err() {
+            blocks.release_all(&self.address);
+            for (piece, _) in self.pieces.drain(..) {
+                piece.release_all(&self.address);
+            }
+            self.requested.clear();
+        }
+        self.pieces
+            .retain(|(piece, _)| piece.piece_id != blocks.piece_id);
+        result
+    }
+
+    fn have(&mut self, piece_id: u32) -> Result<(), BitTorrentError> {
+        self.send_peer_message(PeerMessage::Have(piece_id))
+    }
+
+    fn downloaded(&self) -> usize {
+        self.pipeline.downloaded()
+    }
+
+    fn sever(&self) -> Result<(), Self::Error> {
+        self.stream.sever()?;
+        Ok(())
+    }
+
+    fn address(&self) -> &SocketAddr {
+        &self.address
+    }
+
+    fn meta_info<'a>(&'a self) -> Option<&'a MetaInfo> {
+        match &self.torrent_source {
+            TorrentSource::File(meta_info) => Some(meta_info),
+            _ => None,
+        }
+    }
+
+    fn bitfield(&self) -> &Vec<bool> {
+        &self.bitfield
+    }
+}
@@ -0,0 +1,760 @@
+use std::{
+    collections::HashMap,
+    fs::{self, create_dir_all, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use memmap2::MmapMut;
+
+use crate::{
+    bterror,
+    error::BitTorrentError,
+    info::{FileInfo, FilePriority, MetaInfo},
+};
+
+/// maximum total torrent size before pieces are saved to disk as opposed to being cached in memory (bytes)
+const MAX_MEMORY_SIZE: usize = 52428800; // 50 MB
+
+/// Backend that verified piece data is stored in and served from
+pub trait Storage: Send + Sync {
+    /// Size of piece `piece_id` in bytes.
+    fn piece_size(&self, piece_id: usize) -> usize;
+
+    /// Read `length` bytes from piece `piece_id`, starting at `begin`.
+    fn read_block(
+        &self,
+        piece_id: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, BitTorrentError>;
+
+    /// Store the complete data of piece `piece_id`.
+    fn write_piece(&self, piece_id: usize, data: Vec<u8>) -> Result<(), BitTorrentError>;
+
+    /// Persist any buffered writes.
+    fn flush(&self) -> Result<(), BitTorrentError>;
+
+    /// Read the complete data of piece `piece_id`.
+    fn read_piece(&self, piece_id: usize) -> Result<Vec<u8>, BitTorrentError> {
+        self.read_block(piece_id, 0, self.piece_size(piece_id))
+    }
+
+    /// Check if the stored data of piece `piece_id` is present and matches its hashes in
+    /// `meta_info`.
+    fn verify(&self, piece_id: usize, meta_info: &MetaInfo) -> Result<bool, BitTorrentError> {
+        Ok(self
+            .read_piece(piece_id)
+            .is_ok_and(|data| meta_info.check_piece(piece_id, &data)))
+    }
+}
+
+/// Constructs the storage for a torrent once its meta info is known
+pub type StorageFactory =
+    Arc<dyn Fn(&MetaInfo) -> Result<Arc<dyn Storage>, BitTorrentError> + Send + Sync>;
+
+/// Choice of storage backend for a download
+#[derive(Clone)]
+pub enum StorageBackend {
+    /// keep small torrents in memory, and larger ones in per-piece files in the temp path
+    Auto,
+    Memory,
+    /// one file per piece in the given directory
+    PieceFiles(PathBuf),
+    /// write pieces straight into the torrent's files under the given directory
+    InPlace(PathBuf),
+    /// like `InPlace`, but through memory mapped files
+    Mmap(PathBuf),
+    /// serve the torrent's existing files under the given directory, never creating, extending
+    /// or writing to them
+    ReadOnly(PathBuf),
+    /// storage supplied by embedding code
+    #[allow(unused)]
+    Custom(StorageFactory),
+}
+
+impl StorageBackend {
+    /// Open the storage for the torrent described by `meta_info`. Files skipped in
+    /// `file_priorities` are not created by backends that write the torrent's files.
+    pub fn open(
+        &self,
+        meta_info: &MetaInfo,
+        temp_path: &Path,
+        file_priorities: &[FilePriority],
+    ) -> Result<Arc<dyn Storage>, BitTorrentError> {
+        let geometry = Geometry::new(meta_info);
+        Ok(match self {
+            StorageBackend::Auto if meta_info.length() > MAX_MEMORY_SIZE => {
+                Arc::new(PieceFileStorage::new(geometry, temp_path.to_path_buf()))
+            }
+            StorageBackend::Auto | StorageBackend::Memory => Arc::new(MemoryStorage::new(geometry)),
+            StorageBackend::PieceFiles(path) => {
+                Arc::new(PieceFileStorage::new(geometry, path.clone()))
+            }
+            StorageBackend::InPlace(path) => {
+                let layout = FileLayout::new(meta_info, path, file_priorities);
+                layout.allocate()?;
+                Arc::new(layout)
+            }
+            StorageBackend::Mmap(path) => Arc::new(MmapStorage::new(FileLayout::new(
+                meta_info,
+                path,
+                file_priorities,
+            ))?),
+            StorageBackend::ReadOnly(path) => {
+                let layout = FileLayout::new(meta_info, path, file_priorities);
+                layout.check()?;
+                Arc::new(ReadOnlyStorage { layout })
+            }
+            StorageBackend::Custom(factory) => factory(meta_info)?,
+        })
+    }
+}
+
+/// Piece sizes of a torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub piece_length: usize,
+    pub length: usize,
+}
+
+impl Geometry {
+    pub fn new(meta_info: &MetaInfo) -> Self {
+        Self {
+            piece_length: meta_info.info.piece_length,
+            length: meta_info.length(),
+        }
+    }
+
+    /// Byte offset and length of piece `piece_id` within the torrent.
+    pub fn piece_range(&self, piece_id: usize) -> (usize, usize) {
+        let offset = piece_id * self.piece_length;
+        (
+            offset,
+            self.length.saturating_sub(offset).min(self.piece_length),
+        )
+    }
+
+    /// Byte offset within the torrent of the block of `length` bytes at `begin` of piece
+    /// `piece_id`, checking that it lies within the piece.
+    pub fn block_offset(
+        &self,
+        piece_id: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<usize, BitTorrentError> {
+        let (offset, piece_size) = self.piece_range(piece_id);
+        match begin.checked_add(length) {
+            Some(end) if end <= piece_size => Ok(offset + begin),
+            _ => Err(bterror!("Block {begin}+{length} out of range")),
+        }
+    }
+}
+
+/// Slice out a block of a piece's data, checking that it lies within bounds.
+fn slice_block(data: &[u8], begin: usize, length: usize) -> Result<Vec<u8>, BitTorrentError> {
+    data.get(begin..begin + length)
+        .map(<[u8]>::to_vec)
+        .ok_or(bterror!("Block {begin}+{length} out of range"))
+}
+
+/// Pieces held in memory
+pub struct MemoryStorage {
+    geometry: Geometry,
+    pieces: RwLock<HashMap<usize, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(geometry: Geometry) -> Self {
+        Self {
+            geometry,
+            pieces: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn piece_size(&self, piece_id: usize) -> usize {
+        self.geometry.piece_range(piece_id).1
+    }
+
+    fn read_block(
+        &self,
+        piece_id: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, BitTorrentError> {
+        let pieces = self.pieces.read()?;
+        let data = pieces
+            .get(&piece_id)
+            .ok_or(bterror!("Piece {piece_id} is not stored"))?;
+        slice_block(data, begin, length)
+    }
+
+    fn write_piece(&self, piece_id: usize, data: Vec<u8>) -> Result<(), BitTorrentError> {
+        self.pieces.write()?.insert(piece_id, data);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BitTorrentError> {
+        Ok(())
+    }
+}
+
+/// Each piece saved to its own file in a directory
+pub struct PieceFileStorage {
+    geometry: Geometry,
+    directory: PathBuf,
+}
+
+impl PieceFileStorage {
+    pub fn new(geometry: Geometry, directory: PathBuf) -> Self {
+        Self {
+            geometry,
+            directory,
+        }
+    }
+
+    /// Location of the file that piece `piece_id` is saved to.
+    pub fn piece_path(&self, piece_id: usize) -> PathBuf {
+        self.directory.join(format!("piece-{piece_id}.dat"))
+    }
+}
+
+impl Storage for PieceFileStorage {
+    fn piece_size(&self, piece_id: usize) -> usize {
+        self.geometry.piece_range(piece_id).1
+    }
+
+    fn read_block(
+        &self,
+        piece_id: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, BitTorrentError> {
+        self.geometry.block_offset(piece_id, begin, length)?;
+        let mut file = fs::File::open(self.piece_path(piece_id))?;
+        file.seek(SeekFrom::Start(begin as u64))?;
+        let mut data = vec![0u8; length];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn write_piece(&self, piece_id: usize, data: Vec<u8>) -> Result<(), BitTorrentError> {
+        create_dir_all(&self.directory)?;
+        fs::write(self.piece_path(piece_id), data)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BitTorrentError> {
+        Ok(())
+    }
+}
+
+/// A single file of the torrent, and where its data sits in the torrent's byte stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutFile {
+    pub path: PathBuf,
+    pub offset: usize,
+    pub length: usize,
+    /// the file is not wanted, and any of its data shared with wanted pieces is kept in the
+    /// layout's parts file instead
+    pub skip: bool,
+    /// the file only aligns the next file to a piece boundary, so its data is all zeros and
+    /// never stored
+    pub padding: bool,
+}
+
+/// Mapping of the torrent's contiguous byte stream onto the files it is saved as,
+/// allowing pieces to be written to and read from their final location on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileLayout {
+    pub files: Vec<LayoutFile>,
+    pub geometry: Geometry,
+    /// sparse file holding the data of skipped files at its offset in the torrent, for pieces
+    /// that straddle a skipped and a wanted file
+    pub parts_path: PathBuf,
+}
+
+impl FileLayout {
+    /// Lay out the files of the torrent described by `meta_info` under the directory `path`,
+    /// skipping files with a priority of `FilePriority::Skip` in `file_priorities`.
+    pub fn new(meta_info: &MetaInfo, path: &Path, file_priorities: &[FilePriority]) -> Self {
+        let paths = match &meta_info.info.file_info {
+            FileInfo::Length(_) => vec![path.join(&meta_info.info.name)],
+            FileInfo::Files(files) => {
+                let base_path = path.join(&meta_info.info.name);
+                files
+                    .iter()
+                    .map(|file| {
+                        file.path
+                            .iter()
+                            .fold(base_path.clone(), |p, part| p.join(part))
+                    })
+                    .collect()
+            }
+        };
+        let padding = match &meta_info.info.file_info {
+            FileInfo::Length(_) => vec![false],
+            FileInfo::Files(files) => files.iter().map(|file| file.is_padding()).collect(),
+        };
+        let files = paths
+            .into_iter()
+            .zip(padding)
+            .zip(meta_info.file_ranges())
+            .enumerate()
+            .map(|(file_id, ((path, padding), range))| LayoutFile {
+                path,
+                offset: range.start,
+                length: range.len(),
+                skip: FilePriority::of(file_priorities, file_id) == FilePriority::Skip,
+                padding,
+            })
+            .collect();
+        Self {
+            files,
+            geometry: Geometry::new(meta_info),
+            parts_path: path.join(format!(".{}.parts", meta_info.info.name)),
+        }
+    }
+
+    /// Create every wanted file at its full length, leaving the contents sparse where the
+    /// filesystem supports it. Existing data is left untouched.
+    pub fn allocate(&self) -> Result<(), BitTorrentError> {
+        for file in self.files.iter().filter(|file| !file.skip && !file.padding) {
+            if let Some(parent) = file.path.parent() {
+                create_dir_all(parent)?;
+            }
+            let handle = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)?;
+            if handle.metadata()?.len() < file.length as u64 {
+                handle.set_len(file.length as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every wanted file exists and is at least as long as the torrent says,
+    /// without touching any of them.
+    pub fn check(&self) -> Result<(), BitTorrentError> {
+        for file in self.files.iter().filter(|file| !file.skip && !file.padding) {
+            let length = fs::metadata(&file.path)
+                .map_err(|err| bterror!("Missing file {}: {err}", file.path.display()))?
+                .len();
+            if length < file.length as u64 {
+                return Err(bterror!(
+                    "File {} is {length} bytes, expected {}",
+                    file.path.display(),
+                    file.length
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Split the byte range `offset..offset + length` of the torrent into the file segments
+    /// it covers, as `(file index, offset within file, offset within range, segment length)`.
+    /// Padding files are left out, so they read as zeros and writes to them are dropped.
+    fn segments(
+        &self,
+        offset: usize,
+        length: usize,
+    ) -> impl Iterator<Item = (usize, usize, usize, usize)> + '_ {
+        let end = offset + length;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(move |(_, file)| {
+                file.length > 0
+                    && !file.padding
+                    && file.offset < end
+                    && offset < file.offset + file.length
+            })
+            .map(move |(index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                (index, start - file.offset, start - offset, stop - start)
+            })
+    }
+
+    /// Location of byte `file_offset` of file `index` on disk.
+    fn locate(&self, index: usize, file_offset: usize) -> (&Path, u64) {
+        let file = &self.files[index];
+        if file.skip {
+            (&self.parts_path, (file.offset + file_offset) as u64)
+        } else {
+            (&file.path, file_offset as u64)
+        }
+    }
+
+    /// Write `data` into file `index`, starting at byte `file_offset` of the file.
+    fn write_segment(
+        &self,
+        index: usize,
+        file_offset: usize,
+        data: &[u8],
+    ) -> Result<(), BitTorrentError> {
+        let (path, position) = self.locate(index, file_offset);
+        let mut handle = OpenOptions::new()
+            .write(true)
+            .create(self.files[index].skip)
+            .truncate(false)
+            .open(path)?;
+        handle.seek(SeekFrom::Start(position))?;
+        handle.write_all(data)?;
+        Ok(())
+    }
+
+    /// Fill `buf` from file `index`, starting at byte `file_offset` of the file.
+    fn read_segment(
+        &self,
+        index: usize,
+        file_offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), BitTorrentError> {
+        let (path, position) = self.locate(index, file_offset);
+        let mut handle = fs::File::open(path)?;
+        handle.seek(SeekFrom::Start(position))?;
+        handle.read_exact(buf)?;
+        Ok(())
+    }
+
+    /// Write `data` into the files, starting at byte `offset` of the torrent.
+    pub fn write_at(&self, offset: usize, data: &[u8]) -> Result<(), BitTorrentError> {
+        for (index, file_offset, data_offset, length) in self.segments(offset, data.len()) {
+            self.write_segment(index, file_offset, &data[data_offset..data_offset + length])?;
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes from the files, starting at byte `offset` of the torrent.
+    pub fn read_at(&self, offset: usize, length: usize) -> Result<Vec<u8>, BitTorrentError> {
+        let mut data = vec![0u8; length];
+        for (index, file_offset, data_offset, length) in self.segments(offset, length) {
+            self.read_segment(
+                index,
+                file_offset,
+                &mut data[data_offset..data_offset + length],
+            )?;
+        }
+        Ok(data)
+    }
+}
+
+impl Storage for FileLayout {
+    fn piece_size(&self, piece_id: usize) -> usize {
+        self.geometry.piece_range(piece_id).1
+    }
+
+    fn read_block(
+        &self,
+        piece_id: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, BitTorrentError> {
+        self.read_at(self.geometry.block_offset(piece_id, begin, length)?, length)
+    }
+
+    fn write_piece(&self, piece_id: usize, data: Vec<u8>) -> Result<(), BitTorrentError> {
+        self.write_at(self.geometry.piece_range(piece_id).0, &data)
+    }
+
+    fn flush(&self) -> Result<(), BitTorrentError> {
+        Ok(())
+    }
+}
+
+/// The torrent's existing files, served without ever being written to
+pub struct ReadOnlyStorage {
+    layout: FileLayout,
+}
+
+impl Storage for ReadOnlyStorage {
+    fn piece_size(&self, piece_id: usize) -> usize {
+        self.layout.piece_size(piece_id)
+    }
+
+    fn read_block(
+        &self,
+        piece_id: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, BitTorrentError> {
+        self.layout.read_block(piece_id, begin, length)
+    }
+
+    fn write_piece(&self, piece_id: usize, _data: Vec<u8>) -> Result<(), BitTorrentError> {
+        Err(bterror!(
+            "Can't write piece {piece_id} to read-only storage"
+        ))
+    }
+
+    fn flush(&self) -> Result<(), BitTorrentError> {
+        Ok(())
+    }
+}
+
+/// Pieces written in place through memory mapped files
+pub struct MmapStorage {
+    layout: FileLayout,
+    maps: Vec<Option<Mutex<MmapMut>>>,
+}
+
+impl MmapStorage {
+    pub fn new(layout: FileLayout) -> Result<Self, BitTorrentError> {
+        layout.allocate()?;
+        let maps = layout
+            .files
+            .iter()
+            .map(|file| {
+                // empty files cannot be mapped, and skipped and padding files are not created
+                if file.length == 0 || file.skip || file.padding {
+                    return Ok(None);
+                }
+                let handle = OpenOptions::new().read(true).write(true).open(&file.path)?;
+                // safety: the files are owned by this download for as long as the map lives
+                let map = unsafe { MmapMut::map_mut(&handle)? };
+                Ok(Some(Mutex::new(map)))
+            })
+            .collect::<Result<_, BitTorrentError>>()?;
+        Ok(Self { layout, maps })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn piece_size(&self, piece_id: usize) -> usize {
+        self.layout.geometry.piece_range(piece_id).1
+    }
+
+    fn read_block(
+        &self,
+        piece_id: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, BitTorrentError> {
+        let offset = self.layout.geometry.block_offset(piece_id, begin, length)?;
+        let mut data = vec![0u8; length];
+        for (index, file_offset, data_offset, length) in self.layout.segments(offset, length) {
+            let buf = &mut data[data_offset..data_offset + length];
+            match &self.maps[index] {
+                Some(map) => buf.copy_from_slice(&map.lock()?[file_offset..file_offset + length]),
+                None => self.layout.read_segment(index, file_offset, buf)?,
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_piece(&self, piece_id: usize, data: Vec<u8>) -> Result<(), BitTorrentError> {
+        let offset = self.layout.geometry.piece_range(piece_id).0;
+        for (index, file_offset, data_offset, length) in self.layout.segments(offset, data.len()) {
+            let segment = &data[data_offset..data_offset + length];
+            match &self.maps[index] {
+                Some(map) => {
+                    map.lock()?[file_offset..file_offset + length].copy_from_slice(segment)
+                }
+                None => self.layout.write_segment(index, file_offset, segment)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BitTorrentError> {
+        for map in self.maps.iter().flatten() {
+            map.lock()?.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        bencode::{BencodedValue, Number},
+        bytes::Bytes,
+        dict,
+    };
+
+    /// Meta info of a torrent named "t" of the files `files`, given by name and length, with
+    /// pieces of 5 bytes.
+    fn meta_info(files: &[(&str, usize)]) -> MetaInfo {
+        let length: usize = files.iter().map(|(_, length)| length).sum();
+        let files = files
+            .iter()
+            .map(|(name, length)| {
+                dict! {
+                    b"length" => *length as Number,
+                    b"path" => vec![Bytes::from(name.to_string())],
+                }
+            })
+            .collect::<Vec<_>>();
+        let info = dict! {
+            b"files" => files,
+            b"name" => Bytes::from("t".to_string()),
+            b"piece length" => 5 as Number,
+            b"pieces" => Bytes(vec![0; 20 * length.div_ceil(5)]),
+        };
+        Result::from(dict! { b"info" => info }).unwrap()
+    }
+
+    /// Write the pieces of a 12 byte torrent counting up from 0 to `storage`, and check they
+    /// read back, and that blocks reaching past their piece are refused.
+    fn check_round_trip(storage: &dyn Storage) {
+        let data = (0..12).collect::<Vec<u8>>();
+        for (piece_id, piece) in data.chunks(5).enumerate() {
+            storage.write_piece(piece_id, piece.to_vec()).unwrap();
+        }
+        assert_eq!(storage.piece_size(2), 2);
+        for (piece_id, piece) in data.chunks(5).enumerate() {
+            assert_eq!(storage.read_piece(piece_id).unwrap(), piece);
+        }
+        assert_eq!(storage.read_block(1, 1, 3).unwrap(), vec![6, 7, 8]);
+        assert!(storage.read_block(1, 3, 3).is_err());
+        assert!(storage.read_block(2, 0, 3).is_err());
+        assert!(storage.read_block(3, 0, 1).is_err());
+    }
+
+    #[test]
+    fn memory_storage_round_trips() {
+        let geometry = Geometry {
+            piece_length: 5,
+            length: 12,
+        };
+        let storage = MemoryStorage::new(geometry);
+        assert!(storage.read_piece(0).is_err());
+        check_round_trip(&storage);
+    }
+
+    #[test]
+    fn piece_file_storage_round_trips() {
+        let dir = tempdir().unwrap();
+        let meta_info = meta_info(&[("a", 12)]);
+        let storage = StorageBackend::PieceFiles(dir.path().to_path_buf())
+            .open(&meta_info, dir.path(), &[])
+            .unwrap();
+        assert!(storage.read_piece(0).is_err());
+        check_round_trip(storage.as_ref());
+        assert!(dir.path().join("piece-2.dat").exists());
+    }
+
+    #[test]
+    fn in_place_storage_writes_across_file_boundaries() {
+        let dir = tempdir().unwrap();
+        let meta_info = meta_info(&[("a", 7), ("b", 5)]);
+        let storage = StorageBackend::InPlace(dir.path().to_path_buf())
+            .open(&meta_info, dir.path(), &[])
+            .unwrap();
+        // the files are allocated up front
+        assert_eq!(fs::read(dir.path().join("t/b")).unwrap(), vec![0; 5]);
+
+        // piece 1 holds the end of a and the start of b
+        check_round_trip(storage.as_ref());
+        assert_eq!(
+            fs::read(dir.path().join("t/a")).unwrap(),
+            (0..7).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            fs::read(dir.path().join("t/b")).unwrap(),
+            (7..12).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn mmap_storage_writes_across_file_boundaries() {
+        let dir = tempdir().unwrap();
+        let meta_info = meta_info(&[("a", 7), ("b", 5)]);
+        let storage = StorageBackend::Mmap(dir.path().to_path_buf())
+            .open(&meta_info, dir.path(), &[])
+            .unwrap();
+        check_round_trip(storage.as_ref());
+        storage.flush().unwrap();
+        assert_eq!(
+            fs::read(dir.path().join("t/a")).unwrap(),
+            (0..7).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            fs::read(dir.path().join("t/b")).unwrap(),
+            (7..12).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn skipped_files_are_kept_out_of_the_torrent_directory() {
+        let dir = tempdir().unwrap();
+        let meta_info = meta_info(&[("a", 7), ("b", 5)]);
+        let storage = StorageBackend::InPlace(dir.path().to_path_buf())
+            .open(&meta_info, dir.path(), &[FilePriority::Skip])
+            .unwrap();
+
+        // the part of piece 1 in the skipped file goes to the parts file
+        storage.write_piece(1, (5..10).collect()).unwrap();
+        storage.write_piece(2, (10..12).collect()).unwrap();
+        assert_eq!(storage.read_piece(1).unwrap(), (5..10).collect::<Vec<_>>());
+        assert!(!dir.path().join("t/a").exists());
+        assert!(dir.path().join(".t.parts").exists());
+        assert_eq!(
+            fs::read(dir.path().join("t/b")).unwrap(),
+            (7..12).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn padding_files_are_never_created() {
+        let dir = tempdir().unwrap();
+        let padding = dict! {
+            b"attr" => Bytes::from("p".to_string()),
+            b"length" => 3 as Number,
+            b"path" => vec![Bytes::from(".pad".to_string()), Bytes::from("3".to_string())],
+        };
+        let files = vec![
+            dict! { b"length" => 2 as Number, b"path" => vec![Bytes::from("a".to_string())] },
+            padding,
+            dict! { b"length" => 7 as Number, b"path" => vec![Bytes::from("b".to_string())] },
+        ];
+        let info = dict! {
+            b"files" => files,
+            b"name" => Bytes::from("t".to_string()),
+            b"piece length" => 5 as Number,
+            b"pieces" => Bytes(vec![0; 60]),
+        };
+        let meta_info = Result::from(dict! { b"info" => info }).unwrap();
+        let storage = StorageBackend::InPlace(dir.path().to_path_buf())
+            .open(&meta_info, dir.path(), &[])
+            .unwrap();
+
+        // the padding reads as zeros whatever is written to it
+        storage.write_piece(0, vec![1; 5]).unwrap();
+        assert_eq!(storage.read_piece(0).unwrap(), vec![1, 1, 0, 0, 0]);
+        assert!(!dir.path().join("t/.pad").exists());
+        assert_eq!(fs::read(dir.path().join("t/a")).unwrap(), vec![1, 1]);
+    }
+
+    #[test]
+    fn read_only_storage_needs_every_file_in_full() {
+        let dir = tempdir().unwrap();
+        let meta_info = meta_info(&[("a", 7), ("b", 5)]);
+        let open =
+            || StorageBackend::ReadOnly(dir.path().to_path_buf()).open(&meta_info, dir.path(), &[]);
+
+        // missing and short files are refused rather than created or extended
+        assert!(open().is_err());
+        assert!(!dir.path().join("t").exists());
+        fs::create_dir(dir.path().join("t")).unwrap();
+        fs::write(dir.path().join("t/a"), (0..7).collect::<Vec<u8>>()).unwrap();
+        fs::write(dir.path().join("t/b"), [7, 8]).unwrap();
+        assert!(open().is_err());
+        assert_eq!(fs::read(dir.path().join("t/b")).unwrap(), vec![7, 8]);
+
+        fs::write(dir.path().join("t/b"), (7..12).collect::<Vec<u8>>()).unwrap();
+        let storage = open().unwrap();
+        assert_eq!(storage.read_piece(1).unwrap(), (5..10).collect::<Vec<_>>());
+        assert!(storage.write_piece(1, vec![0; 5]).is_err());
+        assert_eq!(storage.read_piece(1).unwrap(), (5..10).collect::<Vec<_>>());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{error::BitTorrentError, info::MetaInfo, magnet::Magnet, util::bytes_to_hex};
+use crate::{bterror, error::BitTorrentError, info::MetaInfo, magnet::Magnet, util::bytes_to_hex};
 
 #[derive(Debug, Clone)]
 pub enum TorrentSource {
@@ -14,17 +14,30 @@ impl TorrentSource {
         }
     }
 
+    /// Info hash used in handshakes: the v1 hash, or the truncated v2 hash of v2-only torrents.
     pub fn hash(&self) -> Result<[u8; 20], BitTorrentError> {
+        self.swarm_hashes()?
+            .first()
+            .copied()
+            .ok_or(bterror!("Torrent has no info hash"))
+    }
+
+    /// Info hashes of every swarm the torrent is shared in, two for hybrid torrents.
+    pub fn swarm_hashes(&self) -> Result<Vec<[u8; 20]>, BitTorrentError> {
         match self {
-            TorrentSource::File(meta_info) => meta_info.info_hash(),
-            TorrentSource::Magnet(magnet) => Ok(magnet.xt),
+            TorrentSource::File(meta_info) => meta_info.swarm_hashes(),
+            TorrentSource::Magnet(magnet) => Ok(magnet.swarm_hashes()),
         }
     }
 
-    pub fn trackers(&self) -> Vec<String> {
+    /// Tiers of trackers to announce to, in order. Every tracker of a magnet link is a tier of
+    /// its own.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
         match self {
-            TorrentSource::File(meta_info) => meta_info.announce_list.clone(),
-            TorrentSource::Magnet(magnet) => magnet.tr.clone(),
+            TorrentSource::File(meta_info) => meta_info.tiers(),
+            TorrentSource::Magnet(magnet) => {
+                magnet.tr.iter().map(|url| vec![url.clone()]).collect()
+            }
         }
     }
 
@@ -34,7 +47,8 @@ impl TorrentSource {
             TorrentSource::Magnet(magnet) => magnet
                 .dn
                 .clone()
-                .unwrap_or_else(|| bytes_to_hex(&magnet.xt)),
+                .or_else(|| magnet.swarm_hashes().first().map(|hash| bytes_to_hex(hash)))
+                .unwrap_or_default(),
         }
     }
 }
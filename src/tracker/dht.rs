@@ -9,6 +9,7 @@ use std::{
     option,
     str::FromStr,
     sync::{
+        atomic::{AtomicBool, Ordering},
         Arc, Mutex, RwLock,
     },
     thread::Scope,
@@ -18,10 +19,13 @@ use std::{
 use hex::encode;
 use lazy_static::lazy_static;
 use regex::Match;
-use crossbeam::channel::{unbounded, Receiver};
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError};
 
 use crate::{
-    bencode::{BencodedValue, Number},
+    bencode::{
+        borrowed::{BencodedRef, DecodeOptions},
+        BencodedValue, Number,
+    },
     bterror, bytes,
     bytes::{Bytes, PullBytes},
     dict,
@@ -119,6 +123,10 @@ pub struct Dht {
     socket: UdpSocket,
     verbose: bool,
     peer_id: Bytes,
+    /// port to announce to the nodes holding peers for the torrent, if we serve it
+    pub announce_port: Option<u16>,
+    /// stops the workers started by `initialize`
+    pub killswitch: Arc<AtomicBool>,
 }
 
 impl Dht {
@@ -136,6 +144,8 @@ impl Dht {
             socket: UdpSocket::bind("0.0.0.0:0").unwrap(),
             verbose,
             peer_id,
+            announce_port: None,
+            killswitch: Arc::new(AtomicBool::new(false)),
         };
         dht.socket.set_read_timeout(Some(DHT_QUERY_TIMEOUT));
         dht
@@ -160,8 +170,10 @@ impl Dht {
 
         // recieve message
         let response_bytes = read_datagram(socket)?;
-        let response =
-            <Result<KrpcMessage, _>>::from(BencodedValue::ingest(&mut &response_bytes[..])?)?;
+        let response = <Result<KrpcMessage, _>>::from(BencodedRef::decode_with(
+            &mut &response_bytes[..],
+            DecodeOptions::UNTRUSTED,
+        )?)?;
         if response.transaction_id != transaction_id {
             Err(bterror!(
                 "Response transaction id does not match: recieved {}, expected {}",
@@ -194,43 +206,80 @@ impl Dht {
         }
 
         let seen_nodes = Arc::new(RwLock::new(HashSet::new()));
+        let announced_nodes = Arc::new(Mutex::new(HashSet::new()));
 
         for i in 0..workers {
             let node_send = node_send.clone();
             let node_recv = node_recv.clone();
             let addr_send = addr_send.clone();
             let peer_id = self.peer_id.clone();
-            let info_hash = self.torrent_source.hash().unwrap().clone();
+            let info_hashes = self.torrent_source.swarm_hashes().unwrap();
             let marked_nodes = seen_nodes.clone();
+            let announced_nodes = announced_nodes.clone();
+            let announce_port = self.announce_port;
+            let killswitch = self.killswitch.clone();
 
             scope.spawn(move || {
                 let mut socket = UdpSocket::bind("0.0.0.0:0").unwrap();
                 socket.set_read_timeout(Some(DHT_QUERY_TIMEOUT)).unwrap();
                 socket.set_write_timeout(Some(DHT_QUERY_TIMEOUT)).unwrap();
 
-                'outer: for node in node_recv {
-                    let (peers, nodes, keep) = match Dht::exchange_message(
-                        &mut socket,
-                        &node,
-                        DhtMessage::Query(Query::GetPeers {
-                            id: peer_id.clone().into(),
-                            info_hash,
-                        }),
-                    ) {
-                        Ok(DhtMessage::Response { nodes, peers, .. }) => {
-                            if peers.as_ref().map_or(true, |peers| peers.is_empty()) {
-                                (peers, nodes, false)
-                            } else {
-                                (peers, nodes, true)
+                'outer: loop {
+                    if killswitch.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let node = match node_recv.recv_timeout(DHT_QUERY_TIMEOUT) {
+                        Ok(node) => node,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    // hybrid torrents are looked up in both their v1 and v2 swarms
+                    let (mut peers, mut nodes, mut keep) = (Vec::new(), Vec::new(), false);
+                    for info_hash in info_hashes.iter().copied() {
+                        let Ok(DhtMessage::Response {
+                            nodes: found_nodes,
+                            peers: found_peers,
+                            token,
+                            ..
+                        }) = Dht::exchange_message(
+                            &mut socket,
+                            &node,
+                            DhtMessage::Query(Query::GetPeers {
+                                id: peer_id.clone(),
+                                info_hash,
+                            }),
+                        )
+                        else {
+                            continue;
+                        };
+                        // announce ourselves to nodes that handed out a token, once each
+                        if let (Some(port), Some(token)) = (announce_port, token) {
+                            if announced_nodes
+                                .lock()
+                                .unwrap()
+                                .insert((node.clone(), info_hash))
+                            {
+                                let _ = Dht::exchange_message(
+                                    &mut socket,
+                                    &node,
+                                    DhtMessage::Query(Query::AnnouncePeer {
+                                        id: peer_id.clone(),
+                                        port: Some(port),
+                                        info_hash,
+                                        token,
+                                    }),
+                                );
                             }
                         }
-                        _ => (None, None, false),
-                    };
+                        let found_peers = found_peers.unwrap_or_default();
+                        keep |= !found_peers.is_empty();
+                        peers.extend(found_peers);
+                        nodes.extend(found_nodes.unwrap_or_default());
+                    }
 
                     {
                         let marked_nodes = marked_nodes.read().unwrap();
                         for node in nodes
-                            .unwrap_or_default()
                             .into_iter()
                             .filter(|node| !marked_nodes.contains(node))
                         {
@@ -244,7 +293,7 @@ impl Dht {
                         marked_nodes.insert(node);
                     }
 
-                    for addr in peers.unwrap_or_default() {
+                    for addr in peers {
                         if addr_send.send(addr).is_err() {
                             break 'outer;
                         }
@@ -326,53 +375,55 @@ impl From<KrpcMessage> for BencodedValue {
     }
 }
 
-impl From<BencodedValue> for Result<KrpcMessage, BitTorrentError> {
-    fn from(value: BencodedValue) -> Self {
-        if let BencodedValue::Dict(mut message) = value {
+impl From<BencodedRef<'_>> for Result<KrpcMessage, BitTorrentError> {
+    fn from(value: BencodedRef<'_>) -> Self {
+        if let BencodedRef::Dict(message) = value {
             Ok(KrpcMessage {
                 transaction_id: message
-                    .pull(b"t")
-                    .and_then(BencodedValue::into_bytes)
+                    .get(b"t")
+                    .and_then(BencodedRef::as_bytes)
+                    .map(Bytes::from)
                     .ok_or(bterror!("Invalid KRPC message: missing transaction id"))?,
-                dht_message: match &message
-                    .pull(b"y")
-                    .and_then(BencodedValue::into_bytes)
-                    .ok_or(bterror!("Invalid KRPC message: missing message type"))?[..]
+                dht_message: match message
+                    .get(b"y")
+                    .and_then(BencodedRef::as_bytes)
+                    .ok_or(bterror!("Invalid KRPC message: missing message type"))?
                 {
                     b"q" => DhtMessage::Query({
-                        if let Some(mut arguments) =
-                            message.pull(b"a").and_then(BencodedValue::into_dict)
-                        {
+                        if let Some(arguments) = message.get(b"a").and_then(BencodedRef::as_dict) {
                             let id = arguments
-                                .pull(b"id")
-                                .and_then(BencodedValue::into_bytes)
+                                .get(b"id")
+                                .and_then(BencodedRef::as_bytes)
+                                .map(Bytes::from)
                                 .ok_or(bterror!("Invalid KRPC message: missing id"));
                             let target = arguments
-                                .pull(b"target")
-                                .and_then(BencodedValue::into_bytes)
+                                .get(b"target")
+                                .and_then(BencodedRef::as_bytes)
+                                .map(Bytes::from)
                                 .ok_or(bterror!("Invalid KRPC message: missing target"));
                             let info_hash = arguments
-                                .pull(b"info_hash")
-                                .and_then(BencodedValue::into_bytes)
-                                .and_then(|x| x[..].try_into().ok())
+                                .get(b"info_hash")
+                                .and_then(BencodedRef::as_bytes)
+                                .and_then(|x| x.try_into().ok())
                                 .ok_or(bterror!("Invalid KRPC message: missing info_hash"));
                             let implied_port = arguments
-                                .pull(b"implied_port")
-                                .and_then(BencodedValue::into_int)
+                                .get(b"implied_port")
+                                .and_then(BencodedRef::as_int)
                                 .map_or(false, |x| x == 1);
                             let port = arguments
-                                .pull(b"port")
-                                .and_then(BencodedValue::into_int)
+                                .get(b"port")
+                                .and_then(BencodedRef::as_int)
                                 .map(|x| x as u16)
                                 .ok_or(bterror!("Invalid KRPC message: missing port"));
                             let token = arguments
-                                .pull(b"token")
-                                .and_then(BencodedValue::into_bytes)
+                                .get(b"token")
+                                .and_then(BencodedRef::as_bytes)
+                                .map(Bytes::from)
                                 .ok_or(bterror!("Invalid KRPC message: missing token"));
-                            match &message
-                                .pull(b"q")
-                                .and_then(BencodedValue::into_bytes)
-                                .ok_or(bterror!("Invalid KRPC message: missing query type"))?[..]
+                            match message
+                                .get(b"q")
+                                .and_then(BencodedRef::as_bytes)
+                                .ok_or(bterror!("Invalid KRPC message: missing query type"))?
                             {
                                 b"ping" => Query::Ping { id: id? },
                                 b"find_node" => Query::FindNode {
@@ -400,25 +451,28 @@ impl From<BencodedValue> for Result<KrpcMessage, BitTorrentError> {
                         }
                     }),
                     b"r" => {
-                        if let Some(mut response) =
-                            message.pull(b"r").and_then(BencodedValue::into_dict)
-                        {
+                        if let Some(response) = message.get(b"r").and_then(BencodedRef::as_dict) {
                             DhtMessage::Response {
                                 id: response
-                                    .pull(b"id")
-                                    .and_then(BencodedValue::into_bytes)
+                                    .get(b"id")
+                                    .and_then(BencodedRef::as_bytes)
+                                    .map(Bytes::from)
                                     .ok_or(bterror!("Missing id"))?,
-                                token: response.pull(b"token").and_then(BencodedValue::into_bytes),
+                                token: response
+                                    .get(b"token")
+                                    .and_then(BencodedRef::as_bytes)
+                                    .map(Bytes::from),
                                 nodes: response
-                                    .pull(b"nodes")
-                                    .and_then(BencodedValue::into_bytes)
-                                    .map(<Vec<Node>>::from),
+                                    .get(b"nodes")
+                                    .and_then(BencodedRef::as_bytes)
+                                    .map(|nodes| <Vec<Node>>::from(Bytes::from(nodes))),
                                 peers: response
-                                    .pull(b"values")
-                                    .and_then(BencodedValue::into_list)
+                                    .get(b"values")
+                                    .and_then(BencodedRef::as_list)
                                     .map(|list| {
-                                        list.into_iter()
-                                            .filter_map(BencodedValue::into_bytes)
+                                        list.iter()
+                                            .filter_map(BencodedRef::as_bytes)
+                                            .map(Bytes::from)
                                             .map(<Result<SocketAddr, _>>::from)
                                             .collect::<Result<Vec<_>, _>>()
                                     })
@@ -429,19 +483,18 @@ impl From<BencodedValue> for Result<KrpcMessage, BitTorrentError> {
                         }
                     }
                     b"e" => {
-                        if let Some(mut error) =
-                            message.pull(b"e").and_then(BencodedValue::into_list)
-                        {
-                            let error_message = error
-                                .pop()
-                                .and_then(BencodedValue::into_bytes)
-                                .map(Bytes::into_string)
-                                .ok_or(bterror!("Invalid KRPC message: missing error message"))?;
+                        if let Some(error) = message.get(b"e").and_then(BencodedRef::as_list) {
+                            let mut error = error.iter();
                             let error_code = error
-                                .pop()
-                                .and_then(BencodedValue::into_int)
+                                .next()
+                                .and_then(BencodedRef::as_int)
                                 .ok_or(bterror!("Invalid KRPC message: missing error code"))?
                                 as usize;
+                            let error_message = error
+                                .next()
+                                .and_then(BencodedRef::as_bytes)
+                                .map(|message| Bytes::from(message).into_string())
+                                .ok_or(bterror!("Invalid KRPC message: missing error message"))?;
                             DhtMessage::Error(error_code, error_message)
                         } else {
                             return Err(bterror!("Invalid KRPC message: missing error data"));
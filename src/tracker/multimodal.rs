@@ -3,16 +3,25 @@ use std::{
     iter::empty,
     net::{SocketAddr, UdpSocket},
     ops::ControlFlow,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 
 use anyhow::Context;
 
+use clap::ValueEnum;
 use lazy_static::lazy_static;
+use rand::seq::SliceRandom;
 use regex::Regex;
 
 use crate::{
-    bencode::BencodedValue,
+    bencode::{
+        borrowed::{BencodedRef, DecodeOptions},
+        BencodedValue,
+    },
     bterror,
     error::BitTorrentError,
     peer::message::Codec,
@@ -22,18 +31,80 @@ use crate::{
 };
 
 const TRACKER_QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+/// limits for decoding tracker responses, which are not always canonically encoded
+const RESPONSE_DECODE_OPTIONS: DecodeOptions = DecodeOptions {
+    strict: false,
+    ..DecodeOptions::UNTRUSTED
+};
 
 lazy_static! {
     static ref UDP_TRACKER_RE: Regex = Regex::new(r"udp://([^:]+:\d+)(/announce)?").unwrap();
 }
 
+/// Which of a torrent's trackers are announced to at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum AnnounceMode {
+    /// The first tracker that answers, trying the tiers in order
+    #[default]
+    Sequential,
+    /// The first tracker that answers in each tier, all tiers at once
+    Tier,
+    /// Every tracker at once
+    All,
+}
+
+/// Event reported with an announce, numbered as in UDP announces (BEP 15)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnnounceEvent {
+    /// one of the announces made at the tracker's interval
+    #[default]
+    None = 0,
+    /// the download has just completed
+    Completed = 1,
+    /// first announce of the download
+    Started = 2,
+    /// the download is being shut down
+    Stopped = 3,
+}
+
+impl AnnounceEvent {
+    /// Value of the `event` parameter of HTTP announces, which is left out for regular ones.
+    fn name(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+}
+
+/// Transfer statistics and event reported with an announce
+#[derive(Debug, Clone, Copy)]
+struct AnnounceStats {
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    event: AnnounceEvent,
+}
+
 pub struct Tracker {
     pub torrent_source: TorrentSource,
     active_connection: TrackerConnection,
+    /// tier and position within it of the tracker `active_connection` is to
+    active_tracker: Option<(usize, usize)>,
     peers: VecDeque<SocketAddr>,
-    pub trackers: VecDeque<String>,
+    /// tiers of tracker urls (BEP 12), shuffled within each tier, with the last tracker that
+    /// answered moved to the front of its tier
+    pub tiers: Vec<Vec<String>>,
     pub peer_id: String,
     pub port: u16,
+    /// bytes of piece data uploaded to other peers, reported with each announce
+    pub uploaded: Arc<AtomicUsize>,
+    /// bytes of piece data downloaded from other peers, reported with each announce
+    pub downloaded: Arc<AtomicUsize>,
+    /// bytes of wanted data left to download, reported with each announce
+    pub left: Arc<AtomicUsize>,
 }
 
 impl Tracker {
@@ -42,36 +113,99 @@ impl Tracker {
         peer_id: String,
         port: u16,
     ) -> Result<Tracker, BitTorrentError> {
-        let mut trackers: VecDeque<String> = torrent_source.trackers().into();
+        let left = match &torrent_source {
+            TorrentSource::File(meta_info) => meta_info.length(),
+            _ => 0,
+        };
+        let mut tiers = torrent_source.trackers();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rand::thread_rng());
+        }
         Ok(Tracker {
-            active_connection: Tracker::_next_tracker(&mut trackers),
+            active_connection: TrackerConnection::None,
+            active_tracker: None,
             peers: VecDeque::new(),
-            trackers,
+            tiers,
             torrent_source,
             peer_id,
             port,
+            uploaded: Arc::new(AtomicUsize::new(0)),
+            downloaded: Arc::new(AtomicUsize::new(0)),
+            left: Arc::new(AtomicUsize::new(left)),
         })
     }
 
-    fn _next_tracker(
-        trackers: &mut VecDeque<String>,
-    ) -> TrackerConnection {
-        loop {
-            match trackers.pop_front() {
-                Some(url) => match TrackerConnection::new(url.clone()) {
-                    Ok(tracker_connection) => return tracker_connection,
-                    Err(err) => println!("Error connecting to tracker at {}: {}", url, err),
-                },
-                None => return TrackerConnection::None,
-            }
+    /// Split into trackers that announce independently of each other, one per tier or one per
+    /// tracker as `mode` asks for. They all report the same upload statistics.
+    pub fn split(self, mode: AnnounceMode) -> Vec<Tracker> {
+        let groups: Vec<Vec<Vec<String>>> = match mode {
+            AnnounceMode::Sequential => return vec![self],
+            AnnounceMode::Tier => self.tiers.iter().map(|tier| vec![tier.clone()]).collect(),
+            AnnounceMode::All => self
+                .tiers
+                .iter()
+                .flatten()
+                .map(|url| vec![vec![url.clone()]])
+                .collect(),
+        };
+        groups
+            .into_iter()
+            .map(|tiers| Tracker {
+                torrent_source: self.torrent_source.clone(),
+                active_connection: TrackerConnection::None,
+                active_tracker: None,
+                peers: VecDeque::new(),
+                tiers,
+                peer_id: self.peer_id.clone(),
+                port: self.port,
+                uploaded: self.uploaded.clone(),
+                downloaded: self.downloaded.clone(),
+                left: self.left.clone(),
+            })
+            .collect()
+    }
+
+    /// Current transfer statistics, to report along with `event`.
+    fn stats(&self, event: AnnounceEvent) -> AnnounceStats {
+        AnnounceStats {
+            uploaded: self.uploaded.load(Ordering::Relaxed) as u64,
+            downloaded: self.downloaded.load(Ordering::Relaxed) as u64,
+            left: self.left.load(Ordering::Relaxed) as u64,
+            event,
         }
     }
 
-    fn cycle_trackers(&mut self) {
-        self.active_connection = Tracker::_next_tracker(&mut self.trackers);
+    fn _query(
+        &mut self,
+        event: AnnounceEvent,
+    ) -> Result<Option<(Vec<SocketAddr>, Duration)>, BitTorrentError> {
+        // hybrid torrents are announced to both their v1 and v2 swarms
+        let mut result: Option<(Vec<SocketAddr>, Duration)> = None;
+        for info_hash in self.torrent_source.swarm_hashes()? {
+            let Some((peers, interval)) = self._query_swarm(&info_hash, event)? else {
+                return Ok(None);
+            };
+            match &mut result {
+                Some((all_peers, min_interval)) => {
+                    for peer in peers {
+                        if !all_peers.contains(&peer) {
+                            all_peers.push(peer);
+                        }
+                    }
+                    *min_interval = interval.min(*min_interval);
+                }
+                None => result = Some((peers, interval)),
+            }
+        }
+        Ok(result)
     }
 
-    fn _query(&mut self) -> Result<Option<(Vec<SocketAddr>, Duration)>, BitTorrentError> {
+    fn _query_swarm(
+        &mut self,
+        info_hash: &[u8; 20],
+        event: AnnounceEvent,
+    ) -> Result<Option<(Vec<SocketAddr>, Duration)>, BitTorrentError> {
+        let stats = self.stats(event);
         match &mut self.active_connection {
             TrackerConnection::Http(announce) => {
                 let client = reqwest::blocking::Client::new();
@@ -81,27 +215,16 @@ impl Tracker {
                         "{}?{}",
                         announce,
                         [
-                            (
-                                "info_hash",
-                                querystring_encode(&self.torrent_source.hash()?)
-                            ),
+                            ("info_hash", querystring_encode(info_hash)),
                             ("peer_id", self.peer_id.to_string()),
                             ("port", format!("{}", self.port)),
-                            ("uploaded", "0".to_string()),
-                            ("downloaded", "0".to_string()),
-                            (
-                                "left",
-                                format!(
-                                    "{}",
-                                    match &self.torrent_source {
-                                        TorrentSource::File(meta_info) => meta_info.length(),
-                                        _ => 0,
-                                    }
-                                )
-                            ),
+                            ("uploaded", stats.uploaded.to_string()),
+                            ("downloaded", stats.downloaded.to_string()),
+                            ("left", stats.left.to_string()),
                             ("compact", "1".to_string()),
                         ]
                         .into_iter()
+                        .chain(event.name().map(|name| ("event", name.to_string())))
                         .map(|(key, value)| format!("{}={}", key, value))
                         .collect::<Vec<_>>()
                         .join("&")
@@ -113,7 +236,9 @@ impl Tracker {
                     .with_context(|| "Error decoding request response")?
                     .to_vec();
 
-                match <Result<_, _>>::from(BencodedValue::ingest(&mut &raw_body[..])?)? {
+                let response =
+                    BencodedRef::decode_with(&mut &raw_body[..], RESPONSE_DECODE_OPTIONS)?;
+                match <Result<_, _>>::from(BencodedValue::from(response))? {
                     TrackerResponse::Success { interval, peers } => {
                         Ok(Some((peers, Duration::from_secs(interval as u64))))
                     }
@@ -129,26 +254,66 @@ impl Tracker {
                     udp_connection.connect()?;
                 }
                 Ok(Some(udp_connection.annouce(
-                    &self.torrent_source,
+                    info_hash,
                     &self.peer_id,
                     self.port,
+                    stats,
                 )?))
             }
             _ => Ok(None),
         }
     }
 
-    pub fn query(&mut self) -> Option<(Vec<SocketAddr>, Duration)> {
-        loop {
-            match self._query() {
-                Ok(tracker_info) => return tracker_info,
+    /// Announce `event` to the first tracker that answers, trying the trackers of each tier in order
+    /// before moving on to the next tier. The tracker that answers is moved to the front of its
+    /// tier, so it is tried first next time.
+    pub fn query(&mut self, event: AnnounceEvent) -> Option<(Vec<SocketAddr>, Duration)> {
+        let mut tiers = std::mem::take(&mut self.tiers);
+        let answer = announce_in_tiers(&mut tiers, |tier, position, url| {
+            if self.active_tracker != Some((tier, position)) {
+                match TrackerConnection::new(url.to_string()) {
+                    Ok(tracker_connection) => {
+                        self.active_connection = tracker_connection;
+                        self.active_tracker = Some((tier, position));
+                    }
+                    Err(err) => {
+                        println!("Error connecting to tracker at {}: {}", url, err);
+                        return None;
+                    }
+                }
+            }
+            match self._query(event) {
+                Ok(tracker_info) => Some(tracker_info),
                 Err(err) => {
                     println!("Error querying tracker: {}", err);
-                    self.cycle_trackers();
+                    None
                 }
             }
+        });
+        self.tiers = tiers;
+        let (tier, tracker_info) = answer?;
+        self.active_tracker = Some((tier, 0));
+        tracker_info
+    }
+}
+
+/// Call `announce` with the tier, position and url of each tracker in `tiers` in turn, trying
+/// every tracker of a tier before moving on to the next one, until one answers. The tracker
+/// that answers is moved to the front of its tier (BEP 12). Returns its tier and answer.
+fn announce_in_tiers<T>(
+    tiers: &mut [Vec<String>],
+    mut announce: impl FnMut(usize, usize, &str) -> Option<T>,
+) -> Option<(usize, T)> {
+    for (tier_id, tier) in tiers.iter_mut().enumerate() {
+        for position in 0..tier.len() {
+            if let Some(answer) = announce(tier_id, position, &tier[position]) {
+                let url = tier.remove(position);
+                tier.insert(0, url);
+                return Some((tier_id, answer));
+            }
         }
     }
+    None
 }
 
 impl Iterator for Tracker {
@@ -159,9 +324,7 @@ impl Iterator for Tracker {
             if let Some(peer) = self.peers.pop_front() {
                 return Some((peer, ControlFlow::Continue(())));
             } else {
-                let Some((peers, interval)) = self.query() else {
-                    return None;
-                };
+                let (peers, interval) = self.query(AnnounceEvent::None)?;
                 if let Some((yield_peer, rest)) = peers.split_first() {
                     self.peers.extend(rest);
                     return Some((*yield_peer, ControlFlow::Break(interval)));
@@ -254,9 +417,10 @@ impl UdpTrackerConnection {
 
     fn annouce(
         &mut self,
-        torrent_source: &TorrentSource,
+        info_hash: &[u8; 20],
         peer_id: &str,
         port: u16,
+        stats: AnnounceStats,
     ) -> Result<(Vec<SocketAddr>, Duration), BitTorrentError> {
         let transaction_id: u32 = rand::random();
         let key: u32 = rand::random();
@@ -270,12 +434,12 @@ impl UdpTrackerConnection {
             .chain(connection_id.to_be_bytes())
             .chain(1_u32.to_be_bytes()) // action (1: announce)
             .chain(transaction_id.to_be_bytes())
-            .chain(torrent_source.hash()?)
+            .chain(*info_hash)
             .chain(peer_id.bytes())
-            .chain(0_u64.to_be_bytes()) // downloaded
-            .chain(0_u64.to_be_bytes()) // left
-            .chain(0_u64.to_be_bytes()) // uploaded
-            .chain(0_u32.to_be_bytes()) // event (0: none)
+            .chain(stats.downloaded.to_be_bytes()) // downloaded
+            .chain(stats.left.to_be_bytes()) // left
+            .chain(stats.uploaded.to_be_bytes()) // uploaded
+            .chain((stats.event as u32).to_be_bytes()) // event
             .chain(0_u32.to_be_bytes()) // ip address (0: all)
             .chain(key.to_be_bytes()) // key
             .chain((-1_i32).to_be_bytes()) // num_want (-1: all)
@@ -303,3 +467,60 @@ impl UdpTrackerConnection {
         Ok((peers, Duration::from_secs(interval)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers(tiers: &[&[&str]]) -> Vec<Vec<String>> {
+        tiers
+            .iter()
+            .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+            .collect()
+    }
+
+    /// Announce to `tiers`, with only the trackers in `answering` answering, returning the
+    /// answering tracker's tier and url, and the trackers tried in order.
+    fn announce(
+        tiers: &mut [Vec<String>],
+        answering: &[&str],
+    ) -> (Option<(usize, String)>, Vec<String>) {
+        let mut tried = Vec::new();
+        let answer = announce_in_tiers(tiers, |_, _, url| {
+            tried.push(url.to_string());
+            answering.contains(&url).then(|| url.to_string())
+        });
+        (answer, tried)
+    }
+
+    #[test]
+    fn moves_the_answering_tracker_to_the_front_of_its_tier() {
+        let mut trackers = tiers(&[&["a", "b", "c"], &["d"]]);
+        let (answer, tried) = announce(&mut trackers, &["b"]);
+        assert_eq!(answer, Some((0, "b".to_string())));
+        assert_eq!(tried, vec!["a", "b"]);
+        assert_eq!(trackers, tiers(&[&["b", "a", "c"], &["d"]]));
+
+        // and tries it first next time
+        let (_, tried) = announce(&mut trackers, &["b"]);
+        assert_eq!(tried, vec!["b"]);
+    }
+
+    #[test]
+    fn moves_on_to_the_next_tier_once_every_tracker_of_a_tier_fails() {
+        let mut trackers = tiers(&[&["a", "b"], &["c", "d"]]);
+        let (answer, tried) = announce(&mut trackers, &["d"]);
+        assert_eq!(answer, Some((1, "d".to_string())));
+        assert_eq!(tried, vec!["a", "b", "c", "d"]);
+        assert_eq!(trackers, tiers(&[&["a", "b"], &["d", "c"]]));
+    }
+
+    #[test]
+    fn gives_up_once_every_tier_fails() {
+        let mut trackers = tiers(&[&["a"], &["b"]]);
+        let (answer, tried) = announce(&mut trackers, &[]);
+        assert_eq!(answer, None);
+        assert_eq!(tried, vec!["a", "b"]);
+        assert_eq!(trackers, tiers(&[&["a"], &["b"]]));
+    }
+}
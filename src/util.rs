@@ -5,6 +5,7 @@ use chrono::Utc;
 use lazy_static::lazy_static;
 use regex::Regex;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use std::{
     collections::HashSet,
     hash::Hash,
@@ -184,6 +185,13 @@ pub fn sha1_hash(bytes: &[u8]) -> [u8; 20] {
     hasher.finalize().into()
 }
 
+/// Calculate the SHA256 hash of a byte slice.
+pub fn sha256_hash(bytes: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    hasher.finalize().into()
+}
+
 pub fn sleep(millis: u64) {
     std::thread::sleep(std::time::Duration::from_millis(millis))
 }